@@ -0,0 +1,181 @@
+//! Envelope encryption of the files at rest. A directory holding a `.data_key` has its files
+//! encrypted with AES-256-GCM under that data key, which is itself stored wrapped by the master
+//! key of the `[storage]` config. Without a master key nothing is encrypted. The name of a file is
+//! authenticated with its content, so an encrypted file can't be passed off as another one.
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+
+pub const DATA_KEY_FILE: &str = ".data_key";
+
+const KEY_LEN: usize = 32;
+
+pub type Key = [u8; KEY_LEN];
+
+static MASTER_KEY: OnceLock<Key> = OnceLock::new();
+
+// The unwrapped data keys, by the path of their key file.
+static DATA_KEYS: OnceLock<Mutex<HashMap<String, Key>>> = OnceLock::new();
+
+// The key files found missing. A directory gets its data key when it is created, before any of
+// its files, so a directory without one stays unencrypted. Cleared once full.
+static NO_DATA_KEYS: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+const MAX_NO_DATA_KEYS: usize = 4096;
+
+/// Load the master key, 32 raw bytes or 64 hex characters.
+pub fn init_master_key(path: &str) -> anyhow::Result<()> {
+    let content = std::fs::read(path)?;
+    let key = match std::str::from_utf8(&content).map(str::trim) {
+        Ok(hex_key) if hex_key.len() == KEY_LEN * 2 => hex::decode(hex_key)?,
+        _ => content,
+    };
+    let key: Key = key
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("master key {} must be {} bytes", path, KEY_LEN))?;
+    let _ = MASTER_KEY.set(key);
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    MASTER_KEY.get().is_some()
+}
+
+/// Path of the data key which covers `path`, if encryption is enabled.
+pub(crate) fn data_key_path(path: &str) -> Option<String> {
+    if !is_enabled() {
+        return None;
+    }
+    let (dir, name) = path.rsplit_once('/')?;
+    if name.starts_with('.') {
+        return None;
+    }
+    Some(format!("{}/{}", dir, DATA_KEY_FILE))
+}
+
+pub(crate) fn cached_data_key(key_path: &str) -> Option<Key> {
+    DATA_KEYS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .get(key_path)
+        .copied()
+}
+
+pub(crate) fn is_unencrypted(key_path: &str) -> bool {
+    NO_DATA_KEYS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .contains(key_path)
+}
+
+pub(crate) fn set_unencrypted(key_path: &str, unencrypted: bool) {
+    let mut no_data_keys = NO_DATA_KEYS.get_or_init(Default::default).lock().unwrap();
+    if !unencrypted {
+        no_data_keys.remove(key_path);
+        return;
+    }
+    if no_data_keys.len() >= MAX_NO_DATA_KEYS {
+        no_data_keys.clear();
+    }
+    no_data_keys.insert(key_path.to_string());
+}
+
+pub(crate) fn unwrap_data_key(key_path: &str, wrapped: &[u8]) -> anyhow::Result<Key> {
+    let master_key = MASTER_KEY
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("no master key to unwrap {}", key_path))?;
+    let key: Key = open(master_key, wrapped, DATA_KEY_FILE)
+        .map_err(|e| anyhow::anyhow!("unwrap {} failed: {}", key_path, e))?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid data key {}", key_path))?;
+    DATA_KEYS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(key_path.to_string(), key);
+    Ok(key)
+}
+
+/// A fresh data key, wrapped by the master key.
+pub(crate) fn new_wrapped_data_key() -> anyhow::Result<Vec<u8>> {
+    let master_key = MASTER_KEY
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("no master key configured"))?;
+    let mut key = [0u8; KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| anyhow::anyhow!("generate data key failed"))?;
+    seal(master_key, &key, DATA_KEY_FILE)
+}
+
+/// `nonce || ciphertext || tag`, with `name` as the additional authenticated data.
+pub(crate) fn seal(key: &Key, plaintext: &[u8], name: &str) -> anyhow::Result<Vec<u8>> {
+    let key = LessSafeKey::new(
+        UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow::anyhow!("invalid key"))?,
+    );
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("generate nonce failed"))?;
+    let mut sealed = Vec::with_capacity(NONCE_LEN + plaintext.len() + AES_256_GCM.tag_len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(plaintext);
+    let mut in_out = sealed.split_off(NONCE_LEN);
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(name.as_bytes()),
+        &mut in_out,
+    )
+    .map_err(|_| anyhow::anyhow!("encrypt failed"))?;
+    sealed.extend_from_slice(&in_out);
+    Ok(sealed)
+}
+
+pub(crate) fn open(key: &Key, sealed: &[u8], name: &str) -> anyhow::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+        anyhow::bail!("encrypted file too short");
+    }
+    let key = LessSafeKey::new(
+        UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow::anyhow!("invalid key"))?,
+    );
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce =
+        Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow::anyhow!("invalid nonce"))?;
+    let mut in_out = ciphertext.to_vec();
+    let plaintext_len = key
+        .open_in_place(nonce, Aad::from(name.as_bytes()), &mut in_out)
+        .map_err(|_| anyhow::anyhow!("decrypt failed, wrong key or corrupted file"))?
+        .len();
+    in_out.truncate(plaintext_len);
+    Ok(in_out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = [1u8; KEY_LEN];
+        let sealed = seal(&key, b"private input", "private_input").unwrap();
+        assert_ne!(&sealed[NONCE_LEN..NONCE_LEN + 13], b"private input");
+        assert_eq!(
+            open(&key, &sealed, "private_input").unwrap(),
+            b"private input"
+        );
+        // a fresh nonce per file
+        assert_ne!(
+            seal(&key, b"private input", "private_input").unwrap(),
+            sealed
+        );
+
+        assert!(open(&[2u8; KEY_LEN], &sealed, "private_input").is_err());
+        assert!(open(&key, &sealed, "public_input").is_err());
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(open(&key, &tampered, "private_input").is_err());
+        assert!(open(&key, &sealed[..NONCE_LEN], "private_input").is_err());
+    }
+}
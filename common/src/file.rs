@@ -1,28 +1,585 @@
+use crate::envelope;
+use crate::mem::MemBackend;
 use anyhow::Ok;
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::config::Credentials;
 use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier};
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs;
-use std::io::Read;
+use std::future::Future;
 use std::io::Write;
-use std::thread;
-use tokio::io::AsyncReadExt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
 use tokio::runtime::Runtime;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
 
-pub fn new(path: &str) -> Box<dyn File> {
-    if is_s3_path(path) {
-        return Box::new(S3File::new(path));
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// Objects are uploaded in parts of this size, S3 requires at least 5 MiB but for the last part.
+const PART_SIZE: usize = 8 << 20;
+
+// Files are written to a temporary file next to them first, and renamed once complete.
+const TMP_SUFFIX: &str = ".tmp";
+
+// The SHA-256 digest of a file is written next to it, and checked when the file is read back.
+const DIGEST_SUFFIX: &str = ".sha256";
+
+/// Prefix of the [`IntegrityError`] message, to recognize it once it is turned into a string.
+pub const INTEGRITY_ERROR: &str = "integrity check failed";
+
+/// A file which does not match its recorded digest, e.g. truncated by a crashed writer. It is
+/// returned through `anyhow` before any decoding, so the callers can tell it apart and retry.
+#[derive(Debug)]
+pub struct IntegrityError {
+    pub path: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} for {}: expected sha256 {}, got {}",
+            INTEGRITY_ERROR, self.path, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+pub fn digest(buf: &[u8]) -> String {
+    hex::encode(Sha256::digest(buf))
+}
+
+/// Path of the digest recorded for `path`.
+pub fn digest_path(path: &str) -> String {
+    format!("{path}{DIGEST_SUFFIX}")
+}
+
+fn is_digest_path(path: &str) -> bool {
+    path.ends_with(DIGEST_SUFFIX)
+}
+
+/// The file of which `path` is the digest, if it is one.
+pub fn digested_path(path: &str) -> Option<&str> {
+    path.strip_suffix(DIGEST_SUFFIX)
+}
+
+/// The digests, the temporary files and the data keys, which `read_dir` does not list.
+pub fn is_hidden(file_name: &str) -> bool {
+    is_digest_path(file_name)
+        || file_name.ends_with(TMP_SUFFIX)
+        || file_name.ends_with(envelope::DATA_KEY_FILE)
+}
+
+// An empty digest is still being written.
+fn recorded(buf: Option<Vec<u8>>) -> Option<String> {
+    buf.map(|buf| String::from_utf8_lossy(&buf).trim().to_string())
+        .filter(|digest| !digest.is_empty())
+}
+
+/// The digest recorded for `path`, of its stored content. None if it has none, i.e. it was written
+/// before the digests were recorded, or it is still being written.
+pub async fn recorded_digest(path: &str) -> anyhow::Result<Option<String>> {
+    Ok(recorded(
+        raw_async(&digest_path(path)).read_optional().await?,
+    ))
+}
+
+// Files without a recorded digest are not checked.
+fn check_digest(path: &str, buf: &[u8], recorded_digest: Option<Vec<u8>>) -> anyhow::Result<()> {
+    let Some(expected) = recorded(recorded_digest) else {
+        return Ok(());
+    };
+    let actual = digest(buf);
+    if expected != actual {
+        return Err(IntegrityError {
+            path: path.to_string(),
+            expected,
+            actual,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Store `buf` as `{dir}/{sha256}` and return its path. Identical contents are stored once, an
+/// existing copy is reused if it passes the integrity check.
+pub async fn put_content_addressed(dir: &str, buf: &[u8]) -> anyhow::Result<String> {
+    let path = format!("{}/{}", dir, digest(buf));
+    let file = new_async(&path);
+    new_async(dir).create_dir_all().await?;
+    if !file.commit(buf).await? && file.read().await.is_err() {
+        // replace the corrupted copy
+        file.write(buf).await?;
+    }
+    Ok(path)
+}
+
+/// Encrypt the files written to `dirs` from now on, under a new data key shared by them. A no-op
+/// without a master key, and a directory which already has a data key keeps it.
+pub async fn enable_encryption(dirs: &[&str]) -> anyhow::Result<()> {
+    if !envelope::is_enabled() {
+        return Ok(());
+    }
+    let wrapped = envelope::new_wrapped_data_key()?;
+    for dir in dirs {
+        let key_path = format!("{}/{}", dir, envelope::DATA_KEY_FILE);
+        new_async(&key_path).commit(&wrapped).await?;
+        envelope::set_unencrypted(&key_path, false);
+    }
+    Ok(())
+}
+
+/// Whether `path` is encrypted at rest, its content differs from what `read` returns.
+pub async fn is_encrypted(path: &str) -> anyhow::Result<bool> {
+    Ok(data_key_async(path).await?.is_some())
+}
+
+// The data key of the directory of `path`, if it is encrypted.
+fn data_key(path: &str) -> anyhow::Result<Option<envelope::Key>> {
+    let key_path = match envelope::data_key_path(path) {
+        Some(key_path) if !is_hidden(path) => key_path,
+        _ => return Ok(None),
+    };
+    if let Some(key) = envelope::cached_data_key(&key_path) {
+        return Ok(Some(key));
+    }
+    if envelope::is_unencrypted(&key_path) {
+        return Ok(None);
+    }
+    unwrap_data_key(&key_path, new(&key_path).read_optional()?)
+}
+
+fn unwrap_data_key(
+    key_path: &str,
+    wrapped: Option<Vec<u8>>,
+) -> anyhow::Result<Option<envelope::Key>> {
+    let Some(wrapped) = wrapped else {
+        envelope::set_unencrypted(key_path, true);
+        return Ok(None);
+    };
+    Ok(Some(envelope::unwrap_data_key(key_path, &wrapped)?))
+}
+
+async fn data_key_async(path: &str) -> anyhow::Result<Option<envelope::Key>> {
+    let key_path = match envelope::data_key_path(path) {
+        Some(key_path) if !is_hidden(path) => key_path,
+        _ => return Ok(None),
+    };
+    if let Some(key) = envelope::cached_data_key(&key_path) {
+        return Ok(Some(key));
+    }
+    if envelope::is_unencrypted(&key_path) {
+        return Ok(None);
+    }
+    unwrap_data_key(&key_path, new_async(&key_path).read_optional().await?)
+}
+
+// The name of the file is authenticated with its content.
+fn file_name(path: &str) -> &str {
+    path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+// The digests are of the stored, encrypted content.
+fn seal<'a>(path: &str, buf: &'a [u8]) -> anyhow::Result<Cow<'a, [u8]>> {
+    Ok(match data_key(path)? {
+        Some(key) => Cow::Owned(envelope::seal(&key, buf, file_name(path))?),
+        None => Cow::Borrowed(buf),
+    })
+}
+
+async fn seal_async<'a>(path: &str, buf: &'a [u8]) -> anyhow::Result<Cow<'a, [u8]>> {
+    Ok(match data_key_async(path).await? {
+        Some(key) => Cow::Owned(envelope::seal(&key, buf, file_name(path))?),
+        None => Cow::Borrowed(buf),
+    })
+}
+
+fn open(path: &str, buf: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match data_key(path)? {
+        Some(key) => envelope::open(&key, &buf, file_name(path)),
+        None => Ok(buf),
+    }
+}
+
+async fn open_async(path: &str, buf: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match data_key_async(path).await? {
+        Some(key) => envelope::open(&key, &buf, file_name(path)),
+        None => Ok(buf),
+    }
+}
+
+/// The `[storage]` section of the config, for S3 compatible object stores. Unset fields fall back
+/// to the AWS environment.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StorageConfig {
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    // used by the `s3:///key` paths, which omit the bucket
+    pub bucket: Option<String>,
+    // prepended to every object key
+    pub prefix: Option<String>,
+    // required by MinIO and most on-prem stores
+    pub force_path_style: Option<bool>,
+    // 32 bytes, raw or hex, which wrap the data keys of the encrypted directories
+    pub master_key_path: Option<String>,
+}
+
+static STORAGE: std::sync::OnceLock<StorageConfig> = std::sync::OnceLock::new();
+
+/// Configure the S3 backend and the encryption, must be called before the first file access.
+/// Later calls are ignored.
+pub fn init_storage(config: StorageConfig) -> anyhow::Result<()> {
+    if let Some(master_key_path) = &config.master_key_path {
+        envelope::init_master_key(master_key_path)?;
+    }
+    let _ = STORAGE.set(config);
+    Ok(())
+}
+
+fn storage() -> &'static StorageConfig {
+    STORAGE.get_or_init(StorageConfig::default)
+}
+
+/// A storage backend, selected by the scheme of the paths: `s3://` and `mem://` are built in,
+/// the paths without a scheme are local. A backend stores the bytes as it is given them, the
+/// encryption and the digests are applied on top of every backend by [`SealedFile`].
+pub trait Backend: Send + Sync {
+    fn open(&self, path: &str) -> Box<dyn File>;
+    fn open_async(&self, path: &str) -> Box<dyn AsyncFile>;
+}
+
+struct S3Backend;
+
+impl Backend for S3Backend {
+    fn open(&self, path: &str) -> Box<dyn File> {
+        Box::new(S3File::new(path))
     }
-    Box::new(LocalFile::new(path))
+
+    fn open_async(&self, path: &str) -> Box<dyn AsyncFile> {
+        Box::new(S3File::new(path))
+    }
+}
+
+type Backends = HashMap<String, Arc<dyn Backend>>;
+
+static BACKENDS: std::sync::OnceLock<RwLock<Backends>> = std::sync::OnceLock::new();
+
+fn backends() -> &'static RwLock<Backends> {
+    BACKENDS.get_or_init(|| {
+        let mut backends: Backends = HashMap::new();
+        backends.insert("s3".to_string(), Arc::new(S3Backend));
+        backends.insert("mem".to_string(), Arc::new(MemBackend::default()));
+        RwLock::new(backends)
+    })
+}
+
+/// Serve the `{scheme}://` paths by `backend`, replacing the backend registered for it.
+pub fn register_backend(scheme: &str, backend: impl Backend + 'static) {
+    backends()
+        .write()
+        .unwrap()
+        .insert(scheme.to_string(), Arc::new(backend));
+}
+
+fn backend(path: &str) -> Option<Arc<dyn Backend>> {
+    let (scheme, _) = path.split_once("://")?;
+    backends().read().unwrap().get(scheme).cloned()
+}
+
+// The file as its backend stores it, neither decrypted nor checked.
+fn raw(path: &str) -> Box<dyn File> {
+    match backend(path) {
+        Some(backend) => backend.open(path),
+        None => Box::new(LocalFile::new(path)),
+    }
+}
+
+fn raw_async(path: &str) -> Box<dyn AsyncFile> {
+    match backend(path) {
+        Some(backend) => backend.open_async(path),
+        None => Box::new(LocalFile::new(path)),
+    }
+}
+
+pub fn new(path: &str) -> Box<dyn File> {
+    Box::new(SealedFile::new(path))
+}
+
+pub fn new_async(path: &str) -> Box<dyn AsyncFile> {
+    Box::new(SealedFile::new(path))
 }
 
 pub fn is_s3_path(path: &str) -> bool {
     path.starts_with("s3://")
 }
 
+/// The writes are atomic: a file which `exists` is complete, readers never see a partial file.
 pub trait File: std::io::Write {
     fn read(&self) -> anyhow::Result<Vec<u8>>;
     fn read_to_string(&self) -> anyhow::Result<String>;
     fn read_dir(&self) -> anyhow::Result<Vec<String>>;
     fn create_dir_all(&self) -> anyhow::Result<()>;
+    fn exists(&self) -> anyhow::Result<bool>;
+    /// Publish `buf` unless the file already exists, and return whether it was written. Of
+    /// concurrent commits to the same path exactly one wins, the others leave it untouched.
+    fn commit(&self, buf: &[u8]) -> anyhow::Result<bool>;
+
+    /// The content of the file, None if it does not exist.
+    fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.exists()? {
+            return Ok(None);
+        }
+        self.read().map(Some)
+    }
+}
+
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Async counterpart of [`File`], for the callers running on a tokio runtime. The streams are
+/// not buffered in memory, and a writer must be shut down to complete the write.
+#[async_trait]
+pub trait AsyncFile: Send + Sync {
+    async fn read(&self) -> anyhow::Result<Vec<u8>>;
+    async fn reader(&self) -> anyhow::Result<BoxedReader>;
+    async fn write(&self, buf: &[u8]) -> anyhow::Result<()>;
+    async fn writer(&self) -> anyhow::Result<BoxedWriter>;
+    async fn read_dir(&self) -> anyhow::Result<Vec<String>>;
+    async fn create_dir_all(&self) -> anyhow::Result<()>;
+    async fn exists(&self) -> anyhow::Result<bool>;
+    async fn commit(&self, buf: &[u8]) -> anyhow::Result<bool>;
+    /// Remove the file, or the directory and everything under it, and return the number of bytes
+    /// freed. A missing path is not an error.
+    async fn remove_all(&self) -> anyhow::Result<u64>;
+
+    async fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        if !self.exists().await? {
+            return Ok(None);
+        }
+        self.read().await.map(Some)
+    }
+}
+
+/// A file of any backend, encrypted under the data key of its directory and checked against its
+/// recorded digest on read. The digests are of the stored, encrypted content.
+pub struct SealedFile {
+    pub path: String,
+}
+
+impl SealedFile {
+    pub fn new(path: &str) -> Self {
+        SealedFile {
+            path: path.to_string(),
+        }
+    }
+
+    // The digests have no digest themselves.
+    fn digest_path(&self) -> Option<String> {
+        (!is_digest_path(&self.path)).then(|| digest_path(&self.path))
+    }
+}
+
+fn visible(files: Vec<String>) -> Vec<String> {
+    files.into_iter().filter(|name| !is_hidden(name)).collect()
+}
+
+// A reader in between sees the new content with an empty digest, which is not checked.
+// `write_all` skips an empty buffer, so the digest is cleared by `write`.
+fn clear_digest(path: &str) -> std::io::Result<()> {
+    raw(path).write(&[]).map(drop)
+}
+
+impl Write for SealedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = buf.len();
+        let buf = seal(&self.path, buf).map_err(std::io::Error::other)?;
+        let digest_path = self.digest_path();
+        if let Some(digest_path) = &digest_path {
+            clear_digest(digest_path)?;
+        }
+        raw(&self.path).write_all(&buf)?;
+        if let Some(digest_path) = &digest_path {
+            raw(digest_path).write_all(digest(&buf).as_bytes())?;
+        }
+        std::result::Result::Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::result::Result::Ok(())
+    }
+}
+
+impl SealedFile {
+    fn checked(&self, buf: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if let Some(digest_path) = self.digest_path() {
+            check_digest(&self.path, &buf, raw(&digest_path).read_optional()?)?;
+        }
+        open(&self.path, buf)
+    }
+
+    async fn checked_async(&self, buf: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        if let Some(digest_path) = self.digest_path() {
+            let recorded = raw_async(&digest_path).read_optional().await?;
+            check_digest(&self.path, &buf, recorded)?;
+        }
+        open_async(&self.path, buf).await
+    }
+}
+
+impl File for SealedFile {
+    fn read(&self) -> anyhow::Result<Vec<u8>> {
+        self.checked(raw(&self.path).read()?)
+    }
+
+    fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        raw(&self.path)
+            .read_optional()?
+            .map(|buf| self.checked(buf))
+            .transpose()
+    }
+
+    fn read_to_string(&self) -> anyhow::Result<String> {
+        let data = File::read(self)?;
+        Ok(String::from_utf8(data)?)
+    }
+
+    fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        Ok(visible(raw(&self.path).read_dir()?))
+    }
+
+    fn create_dir_all(&self) -> anyhow::Result<()> {
+        raw(&self.path).create_dir_all()
+    }
+
+    fn exists(&self) -> anyhow::Result<bool> {
+        raw(&self.path).exists()
+    }
+
+    fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        let buf = seal(&self.path, buf)?;
+        if !raw(&self.path).commit(&buf)? {
+            return Ok(false);
+        }
+        if let Some(digest_path) = self.digest_path() {
+            raw(&digest_path).write_all(digest(&buf).as_bytes())?;
+        }
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl AsyncFile for SealedFile {
+    async fn read(&self) -> anyhow::Result<Vec<u8>> {
+        self.checked_async(raw_async(&self.path).read().await?)
+            .await
+    }
+
+    async fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        match raw_async(&self.path).read_optional().await? {
+            Some(buf) => Ok(Some(self.checked_async(buf).await?)),
+            None => Ok(None),
+        }
+    }
+
+    // The stored content, checked once it is read to the end.
+    async fn reader(&self) -> anyhow::Result<BoxedReader> {
+        let expected = match self.digest_path() {
+            Some(_) => recorded_digest(&self.path).await?,
+            None => None,
+        };
+        let inner = raw_async(&self.path).reader().await?;
+        let Some(expected) = expected else {
+            return Ok(inner);
+        };
+        Ok(Box::new(DigestReader {
+            inner,
+            hasher: Sha256::new(),
+            path: self.path.clone(),
+            expected,
+            checked: false,
+        }))
+    }
+
+    async fn write(&self, buf: &[u8]) -> anyhow::Result<()> {
+        let buf = seal_async(&self.path, buf).await?;
+        let digest_path = self.digest_path();
+        if let Some(digest_path) = &digest_path {
+            raw_async(digest_path).write(&[]).await?;
+        }
+        raw_async(&self.path).write(&buf).await?;
+        if let Some(digest_path) = &digest_path {
+            raw_async(digest_path)
+                .write(digest(&buf).as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    // The streamed files are not encrypted, a file of an encrypted directory is written whole.
+    // Their digest is recorded once the writer is shut down, a crash in between leaves it empty
+    // and the file unchecked.
+    async fn writer(&self) -> anyhow::Result<BoxedWriter> {
+        if data_key_async(&self.path).await?.is_some() {
+            anyhow::bail!("{} is encrypted, it can't be streamed", self.path);
+        }
+        let Some(digest_path) = self.digest_path() else {
+            return raw_async(&self.path).writer().await;
+        };
+        raw_async(&digest_path).write(&[]).await?;
+        Ok(Box::new(DigestWriter {
+            inner: raw_async(&self.path).writer().await?,
+            hasher: Sha256::new(),
+            digest_path,
+            record: None,
+            done: false,
+        }))
+    }
+
+    async fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        Ok(visible(raw_async(&self.path).read_dir().await?))
+    }
+
+    async fn create_dir_all(&self) -> anyhow::Result<()> {
+        raw_async(&self.path).create_dir_all().await
+    }
+
+    async fn exists(&self) -> anyhow::Result<bool> {
+        raw_async(&self.path).exists().await
+    }
+
+    async fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        let buf = seal_async(&self.path, buf).await?;
+        if !raw_async(&self.path).commit(&buf).await? {
+            return Ok(false);
+        }
+        if let Some(digest_path) = self.digest_path() {
+            raw_async(&digest_path)
+                .write(digest(&buf).as_bytes())
+                .await?;
+        }
+        Ok(true)
+    }
+
+    async fn remove_all(&self) -> anyhow::Result<u64> {
+        let mut bytes = raw_async(&self.path).remove_all().await?;
+        if let Some(digest_path) = self.digest_path() {
+            bytes += raw_async(&digest_path).remove_all().await?;
+        }
+        Ok(bytes)
+    }
 }
 
 pub struct LocalFile {
@@ -37,12 +594,65 @@ impl LocalFile {
     }
 }
 
-impl Write for LocalFile {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let mut file = std::fs::File::create(&self.path)?;
+fn not_found_as_none<T>(result: std::io::Result<T>) -> anyhow::Result<Option<T>> {
+    match result {
+        std::result::Result::Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Unique among the writers of the same path, and on the same file system so it can be renamed.
+fn tmp_path(path: &str) -> String {
+    format!(
+        "{}.{}.{}{}",
+        path,
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TMP_SUFFIX
+    )
+}
+
+// Write `buf` to a synced temporary file, which the caller publishes.
+fn local_write_tmp(path: &str, buf: &[u8]) -> std::io::Result<String> {
+    let tmp = tmp_path(path);
+    let result = fs::File::create(&tmp).and_then(|mut file| {
         file.write_all(buf)?;
-        file.flush()?;
+        file.sync_all()
+    });
+    match result {
+        std::result::Result::Ok(()) => std::result::Result::Ok(tmp),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn local_publish(path: &str, buf: &[u8]) -> std::io::Result<()> {
+    let tmp = local_write_tmp(path, buf)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+// A hard link, unlike a rename, fails if the path exists.
+fn local_publish_new(path: &str, buf: &[u8]) -> std::io::Result<bool> {
+    let tmp = local_write_tmp(path, buf)?;
+    let result = fs::hard_link(&tmp, path);
+    let _ = fs::remove_file(&tmp);
+    match result {
+        std::result::Result::Ok(()) => std::result::Result::Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => std::result::Result::Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
+impl Write for LocalFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        local_publish(&self.path, buf)?;
         std::result::Result::Ok(buf.len())
     }
 
@@ -56,11 +666,13 @@ impl File for LocalFile {
         Ok(std::fs::read(&self.path)?)
     }
 
+    fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        not_found_as_none(std::fs::read(&self.path))
+    }
+
     fn read_to_string(&self) -> anyhow::Result<String> {
-        let mut file_root = std::fs::File::open(&self.path)?;
-        let mut content = String::new();
-        file_root.read_to_string(&mut content)?;
-        Ok(content)
+        let data = File::read(self)?;
+        Ok(String::from_utf8(data)?)
     }
 
     fn read_dir(&self) -> anyhow::Result<Vec<String>> {
@@ -82,6 +694,114 @@ impl File for LocalFile {
         fs::create_dir_all(&self.path)?;
         Ok(())
     }
+
+    fn exists(&self) -> anyhow::Result<bool> {
+        Ok(std::path::Path::new(&self.path).exists())
+    }
+
+    fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        Ok(local_publish_new(&self.path, buf)?)
+    }
+}
+
+#[async_trait]
+impl AsyncFile for LocalFile {
+    async fn read(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(tokio::fs::read(&self.path).await?)
+    }
+
+    async fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        not_found_as_none(tokio::fs::read(&self.path).await)
+    }
+
+    async fn reader(&self) -> anyhow::Result<BoxedReader> {
+        Ok(Box::new(tokio::fs::File::open(&self.path).await?))
+    }
+
+    async fn write(&self, buf: &[u8]) -> anyhow::Result<()> {
+        let mut file = LocalFile::new(&self.path);
+        let buf = buf.to_vec();
+        tokio::task::spawn_blocking(move || Write::write_all(&mut file, &buf)).await??;
+        Ok(())
+    }
+
+    async fn writer(&self) -> anyhow::Result<BoxedWriter> {
+        let (writer, reader) = tokio::io::duplex(PART_SIZE);
+        let path = self.path.clone();
+        let upload = tokio::spawn(async move { local_upload(&path, reader).await });
+        Ok(Box::new(UploadWriter {
+            inner: writer,
+            upload: Some(upload),
+        }))
+    }
+
+    async fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        let mut files = vec![];
+        let mut dir_entries = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            if let Some(file_name) = entry.file_name().to_str() {
+                files.push(file_name.to_string());
+            }
+        }
+        Ok(files)
+    }
+
+    async fn create_dir_all(&self) -> anyhow::Result<()> {
+        Ok(tokio::fs::create_dir_all(&self.path).await?)
+    }
+
+    async fn exists(&self) -> anyhow::Result<bool> {
+        Ok(tokio::fs::try_exists(&self.path).await?)
+    }
+
+    async fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        let file = LocalFile::new(&self.path);
+        let buf = buf.to_vec();
+        tokio::task::spawn_blocking(move || File::commit(&file, &buf)).await?
+    }
+
+    async fn remove_all(&self) -> anyhow::Result<u64> {
+        let path = self.path.clone();
+        let bytes =
+            tokio::task::spawn_blocking(move || local_remove_all(std::path::Path::new(&path)))
+                .await??;
+        Ok(bytes)
+    }
+}
+
+fn local_remove_all(path: &std::path::Path) -> std::io::Result<u64> {
+    let metadata = match fs::symlink_metadata(path) {
+        std::result::Result::Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return std::result::Result::Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        fs::remove_file(path)?;
+        return std::result::Result::Ok(metadata.len());
+    }
+    let mut bytes = 0;
+    for entry in fs::read_dir(path)? {
+        bytes += local_remove_all(&entry?.path())?;
+    }
+    fs::remove_dir(path)?;
+    std::result::Result::Ok(bytes)
+}
+
+// The file is renamed into place once the stream is complete.
+async fn local_upload(path: &str, mut reader: DuplexStream) -> anyhow::Result<()> {
+    let tmp = tmp_path(path);
+    let result = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result
 }
 
 pub struct S3File {
@@ -97,20 +817,12 @@ impl S3File {
 }
 
 impl Write for S3File {
-    #[allow(clippy::io_other_error)]
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let path = self.path.clone();
         let length = buf.len();
         let buf = buf.to_vec();
-        let handle = thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async { s3_write_file(&path, &buf).await })
-        });
-
-        handle
-            .join()
-            .unwrap()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))?;
+        block_on(async move { s3_write_file(&path, &buf).await })
+            .map_err(|e| std::io::Error::other(format!("{}", e)))?;
         std::result::Result::Ok(length)
     }
 
@@ -122,43 +834,233 @@ impl Write for S3File {
 impl File for S3File {
     fn read(&self) -> anyhow::Result<Vec<u8>> {
         let path = self.path.clone();
-        let handle = thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async { s3_read(&path).await })
-        });
+        block_on(async move { s3_read(&path).await })
+    }
 
-        handle.join().unwrap()
+    fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = self.path.clone();
+        block_on(async move { s3_read_optional(&path).await })
     }
 
     fn read_to_string(&self) -> anyhow::Result<String> {
-        let data = self.read()?;
+        let data = File::read(self)?;
         Ok(String::from_utf8(data)?)
     }
 
     fn read_dir(&self) -> anyhow::Result<Vec<String>> {
         let path = self.path.clone();
-        let handle = thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async { list_files_in_s3(&path).await })
-        });
-
-        handle.join().unwrap()
+        block_on(async move { list_files_in_s3(&path).await })
     }
 
     fn create_dir_all(&self) -> anyhow::Result<()> {
         let path = self.path.clone();
-        let handle = thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async { s3_create_dir_all(&path).await })
-        });
+        block_on(async move { s3_create_dir_all(&path).await })
+    }
+
+    fn exists(&self) -> anyhow::Result<bool> {
+        let path = self.path.clone();
+        block_on(async move { s3_exist(&path).await })
+    }
+
+    fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        let path = self.path.clone();
+        let buf = buf.to_vec();
+        block_on(async move { s3_commit(&path, &buf).await })
+    }
+}
+
+#[async_trait]
+impl AsyncFile for S3File {
+    async fn read(&self) -> anyhow::Result<Vec<u8>> {
+        s3_read(&self.path).await
+    }
+
+    async fn read_optional(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        s3_read_optional(&self.path).await
+    }
+
+    async fn reader(&self) -> anyhow::Result<BoxedReader> {
+        let (bucket, key) = parse_s3_path(&self.path);
+        let response = s3_client()
+            .await
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await?;
+        Ok(Box::new(response.body.into_async_read()))
+    }
+
+    async fn write(&self, buf: &[u8]) -> anyhow::Result<()> {
+        if buf.len() < PART_SIZE {
+            return s3_write_file(&self.path, buf).await;
+        }
+        let mut writer = self.writer().await?;
+        writer.write_all(buf).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    async fn writer(&self) -> anyhow::Result<BoxedWriter> {
+        let (writer, reader) = tokio::io::duplex(PART_SIZE);
+        let path = self.path.clone();
+        let upload = tokio::spawn(async move { s3_upload(&path, reader).await });
+        Ok(Box::new(UploadWriter {
+            inner: writer,
+            upload: Some(upload),
+        }))
+    }
+
+    async fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        list_files_in_s3(&self.path).await
+    }
+
+    async fn create_dir_all(&self) -> anyhow::Result<()> {
+        s3_create_dir_all(&self.path).await
+    }
+
+    async fn exists(&self) -> anyhow::Result<bool> {
+        s3_exist(&self.path).await
+    }
+
+    async fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        s3_commit(&self.path, buf).await
+    }
+
+    async fn remove_all(&self) -> anyhow::Result<u64> {
+        s3_remove_all(&self.path).await
+    }
+}
+
+/// Streams the written bytes to a background upload, a multipart upload on S3 or a temporary
+/// file locally, which is completed on shutdown. Dropping the writer before shutdown aborts it.
+struct UploadWriter {
+    inner: DuplexStream,
+    upload: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl AsyncWrite for UploadWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        let Some(upload) = self.upload.as_mut() else {
+            return Poll::Ready(std::result::Result::Ok(()));
+        };
+        let result = ready!(Pin::new(upload).poll(cx));
+        self.upload = None;
+        Poll::Ready(match result {
+            std::result::Result::Ok(result) => result.map_err(std::io::Error::other),
+            Err(e) => Err(std::io::Error::other(e)),
+        })
+    }
+}
+
+impl Drop for UploadWriter {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            upload.abort();
+        }
+    }
+}
+
+type Recording = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Records the digest of the written bytes once the inner writer is shut down.
+struct DigestWriter {
+    inner: BoxedWriter,
+    hasher: Sha256,
+    digest_path: String,
+    record: Option<Recording>,
+    done: bool,
+}
+
+impl AsyncWrite for DigestWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.hasher.update(&buf[..written]);
+        Poll::Ready(std::result::Result::Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.done {
+            return Poll::Ready(std::result::Result::Ok(()));
+        }
+        if self.record.is_none() {
+            ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+            let digest = hex::encode(std::mem::take(&mut self.hasher).finalize());
+            let digest_path = self.digest_path.clone();
+            self.record = Some(Box::pin(async move {
+                raw_async(&digest_path).write(digest.as_bytes()).await
+            }));
+        }
+        let result = ready!(self.record.as_mut().unwrap().as_mut().poll(cx));
+        self.record = None;
+        self.done = true;
+        Poll::Ready(result.map_err(std::io::Error::other))
+    }
+}
+
+/// Checks the read bytes against the recorded digest at the end of the stream, the last read
+/// fails with an [`IntegrityError`] on a mismatch.
+struct DigestReader {
+    inner: BoxedReader,
+    hasher: Sha256,
+    path: String,
+    expected: String,
+    checked: bool,
+}
 
-        handle.join().unwrap()
+impl AsyncRead for DigestReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[filled..];
+        if !read.is_empty() {
+            self.hasher.update(read);
+            return Poll::Ready(std::result::Result::Ok(()));
+        }
+        if buf.remaining() == 0 || self.checked {
+            return Poll::Ready(std::result::Result::Ok(()));
+        }
+        self.checked = true;
+        let actual = hex::encode(std::mem::take(&mut self.hasher).finalize());
+        if actual != self.expected {
+            return Poll::Ready(Err(std::io::Error::other(IntegrityError {
+                path: self.path.clone(),
+                expected: self.expected.clone(),
+                actual,
+            })));
+        }
+        Poll::Ready(std::result::Result::Ok(()))
     }
 }
 
 async fn s3_read(path: &str) -> anyhow::Result<Vec<u8>> {
     let (bucket, key) = parse_s3_path(path);
-    let client = get_s3_client().await;
+    let client = s3_client().await;
 
     let response = client.get_object().bucket(bucket).key(key).send().await?;
 
@@ -172,6 +1074,86 @@ async fn s3_read(path: &str) -> anyhow::Result<Vec<u8>> {
     Ok(vec_bytes)
 }
 
+// Returns None if the object does not exist.
+async fn s3_read_optional(path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let (bucket, key) = parse_s3_path(path);
+    let client = s3_client().await;
+    let response = match client.get_object().bucket(bucket).key(key).send().await {
+        std::result::Result::Ok(response) => response,
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut vec_bytes = Vec::new();
+    response
+        .body
+        .into_async_read()
+        .read_to_end(&mut vec_bytes)
+        .await?;
+    Ok(Some(vec_bytes))
+}
+
+// A conditional put, which S3 rejects with 412 if the object exists.
+async fn s3_commit(path: &str, buf: &[u8]) -> anyhow::Result<bool> {
+    let (bucket, key) = parse_s3_path(path);
+    let result = s3_client()
+        .await
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .if_none_match("*")
+        .body(ByteStream::from(buf.to_vec()))
+        .send()
+        .await;
+    match result {
+        std::result::Result::Ok(_) => Ok(true),
+        Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 412) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The object and the objects under it as a directory.
+async fn s3_remove_all(path: &str) -> anyhow::Result<u64> {
+    let (bucket, key) = parse_s3_path(path);
+    let key = key.trim_end_matches('/').to_string();
+    let dir = format!("{}/", key);
+    let client = s3_client().await;
+    let mut bytes = 0;
+    let mut continuation_token = None;
+    loop {
+        let response = client
+            .list_objects_v2()
+            .bucket(&bucket)
+            .prefix(&key)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+        let mut objects = vec![];
+        for object in response.contents() {
+            let Some(object_key) = object.key() else {
+                continue;
+            };
+            if object_key == key || object_key.starts_with(&dir) {
+                bytes += object.size().unwrap_or_default() as u64;
+                objects.push(ObjectIdentifier::builder().key(object_key).build()?);
+            }
+        }
+        // a listed page holds at most 1000 keys, the limit of a delete
+        if !objects.is_empty() {
+            client
+                .delete_objects()
+                .bucket(&bucket)
+                .delete(Delete::builder().set_objects(Some(objects)).build()?)
+                .send()
+                .await?;
+        }
+        continuation_token = response.next_continuation_token().map(String::from);
+        if continuation_token.is_none() {
+            break;
+        }
+    }
+    Ok(bytes)
+}
+
 async fn s3_create_dir_all(path: &str) -> anyhow::Result<()> {
     let (bucket, key) = parse_s3_path(path);
     let parts: Vec<&str> = key.split('/').collect();
@@ -193,7 +1175,7 @@ async fn s3_create_dir_all(path: &str) -> anyhow::Result<()> {
 async fn s3_write_file(path: &str, buf: &[u8]) -> anyhow::Result<()> {
     let (bucket, key) = parse_s3_path(path);
 
-    let client = get_s3_client().await;
+    let client = s3_client().await;
     client
         .put_object()
         .bucket(bucket)
@@ -205,9 +1187,85 @@ async fn s3_write_file(path: &str, buf: &[u8]) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn s3_upload(path: &str, mut reader: DuplexStream) -> anyhow::Result<()> {
+    let first_part = read_part(&mut reader).await?;
+    if first_part.len() < PART_SIZE {
+        return s3_write_file(path, &first_part).await;
+    }
+
+    let (bucket, key) = parse_s3_path(path);
+    let client = s3_client().await;
+    let upload = client
+        .create_multipart_upload()
+        .bucket(&bucket)
+        .key(&key)
+        .send()
+        .await?;
+    let upload_id = upload
+        .upload_id()
+        .ok_or_else(|| anyhow::anyhow!("no upload id for {}", path))?;
+    let result = async {
+        let mut parts = vec![];
+        let mut part = first_part;
+        while !part.is_empty() {
+            let part_number = parts.len() as i32 + 1;
+            let is_last = part.len() < PART_SIZE;
+            let response = client
+                .upload_part()
+                .bucket(&bucket)
+                .key(&key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(part))
+                .send()
+                .await?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(response.e_tag().map(String::from))
+                    .part_number(part_number)
+                    .build(),
+            );
+            if is_last {
+                break;
+            }
+            part = read_part(&mut reader).await?;
+        }
+        client
+            .complete_multipart_upload()
+            .bucket(&bucket)
+            .key(&key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = client
+            .abort_multipart_upload()
+            .bucket(&bucket)
+            .key(&key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+    }
+    result
+}
+
+async fn read_part(reader: &mut DuplexStream) -> std::io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    reader.take(PART_SIZE as u64).read_to_end(&mut part).await?;
+    std::result::Result::Ok(part)
+}
+
 async fn list_files_in_s3(path: &str) -> anyhow::Result<Vec<String>> {
     let (bucket, key) = parse_s3_path(path);
-    let client = get_s3_client().await;
+    let client = s3_client().await;
     let prefix = if key.ends_with('/') {
         key
     } else {
@@ -237,7 +1295,7 @@ async fn list_files_in_s3(path: &str) -> anyhow::Result<Vec<String>> {
 
 async fn s3_exist(path: &str) -> anyhow::Result<bool> {
     let (bucket, key) = parse_s3_path(path);
-    let client = get_s3_client().await;
+    let client = s3_client().await;
 
     let response = client.head_object().bucket(bucket).key(key).send().await;
 
@@ -248,10 +1306,167 @@ async fn s3_exist(path: &str) -> anyhow::Result<bool> {
 fn parse_s3_path(path: &str) -> (String, String) {
     let path_without_prefix = path.strip_prefix("s3://").unwrap();
     let (bucket, key) = path_without_prefix.split_once('/').unwrap();
-    (bucket.to_string(), key.to_string())
+    let storage = storage();
+    let bucket = match (bucket, &storage.bucket) {
+        ("", Some(default_bucket)) => default_bucket.as_str(),
+        _ => bucket,
+    };
+    let key = match &storage.prefix {
+        Some(prefix) if !prefix.is_empty() => format!("{}/{}", prefix.trim_end_matches('/'), key),
+        _ => key.to_string(),
+    };
+    (bucket.to_string(), key)
 }
 
-async fn get_s3_client() -> aws_sdk_s3::Client {
-    let config = aws_config::load_from_env().await;
-    aws_sdk_s3::Client::new(&config)
+static S3_CLIENT: OnceCell<aws_sdk_s3::Client> = OnceCell::const_new();
+
+// The client is created once, and shared by all the files.
+async fn s3_client() -> &'static aws_sdk_s3::Client {
+    S3_CLIENT
+        .get_or_init(|| async {
+            let storage = storage();
+            let mut loader = aws_config::defaults(BehaviorVersion::latest());
+            if let Some(region) = &storage.region {
+                loader = loader.region(Region::new(region.clone()));
+            }
+            if let Some(endpoint) = &storage.endpoint {
+                loader = loader.endpoint_url(endpoint);
+            }
+            if let (Some(access_key_id), Some(secret_access_key)) =
+                (&storage.access_key_id, &storage.secret_access_key)
+            {
+                loader = loader.credentials_provider(Credentials::new(
+                    access_key_id,
+                    secret_access_key,
+                    None,
+                    None,
+                    "storage-config",
+                ));
+            }
+            let config = loader.load().await;
+            let s3_config = aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(storage.force_path_style.unwrap_or(false))
+                .build();
+            aws_sdk_s3::Client::from_conf(s3_config)
+        })
+        .await
+}
+
+static RUNTIME: std::sync::OnceLock<Runtime> = std::sync::OnceLock::new();
+
+// Run the S3 calls of the sync `File` on a shared runtime. The caller may be on a runtime itself,
+// so it waits on a channel rather than entering the runtime.
+fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let runtime = RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .thread_name("common-file")
+            .enable_all()
+            .build()
+            .expect("failed to build the file runtime")
+    });
+    let (tx, rx) = std::sync::mpsc::channel();
+    runtime.spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.recv().expect("file runtime task panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("common-{}-{}", name, std::process::id()));
+        let dir = dir.to_str().unwrap().to_string();
+        new(&dir).create_dir_all().unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_digest_mismatch() {
+        let dir = test_dir("digest");
+        let path = format!("{}/segment", dir);
+        new_async(&path).write(b"segment").await.unwrap();
+        assert_eq!(new(&path).read().unwrap(), b"segment");
+        assert_eq!(new(&dir).read_dir().unwrap(), vec!["segment"]);
+
+        // truncated by a crashed writer
+        fs::write(&path, b"seg").unwrap();
+        let e = new_async(&path).read().await.unwrap_err();
+        let e = e.downcast_ref::<IntegrityError>().unwrap();
+        assert_eq!(e.expected, digest(b"segment"));
+        assert_eq!(e.actual, digest(b"seg"));
+        assert!(e.to_string().starts_with(INTEGRITY_ERROR));
+        assert!(new(&path).read().is_err());
+
+        new_async(&dir).remove_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_streamed_digest() {
+        let dir = test_dir("stream");
+        let path = format!("{}/segment", dir);
+        let mut writer = new_async(&path).writer().await.unwrap();
+        writer.write_all(b"streamed segment").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(
+            recorded_digest(&path).await.unwrap(),
+            Some(digest(b"streamed segment"))
+        );
+
+        let mut buf = vec![];
+        let reader = new_async(&path).reader().await.unwrap();
+        reader.take(8).read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"streamed");
+        buf.clear();
+        let mut reader = new_async(&path).reader().await.unwrap();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"streamed segment");
+
+        fs::write(&path, b"streamed").unwrap();
+        let mut reader = new_async(&path).reader().await.unwrap();
+        let e = reader.read_to_end(&mut vec![]).await.unwrap_err();
+        assert!(e.to_string().starts_with(INTEGRITY_ERROR));
+
+        new_async(&dir).remove_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_commit_once() {
+        let dir = test_dir("commit");
+        let path = format!("{}/receipt", dir);
+        assert!(new_async(&path).commit(b"first").await.unwrap());
+        assert!(!new_async(&path).commit(b"second").await.unwrap());
+        assert!(!new(&path).commit(b"third").unwrap());
+        assert_eq!(new_async(&path).read().await.unwrap(), b"first");
+        assert_eq!(
+            fs::read(digest_path(&path)).unwrap(),
+            digest(b"first").as_bytes()
+        );
+
+        new_async(&dir).remove_all().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_writer() {
+        let dir = test_dir("encrypted");
+        new_async(&dir).create_dir_all().await.unwrap();
+        let key_path = format!("{}-master.key", dir);
+        fs::write(&key_path, hex::encode([7u8; 32])).unwrap();
+        envelope::init_master_key(&key_path).unwrap();
+        enable_encryption(&[&dir]).await.unwrap();
+
+        let path = format!("{}/private_input", dir);
+        assert!(new_async(&path).writer().await.is_err());
+        new_async(&path).write(b"secret").await.unwrap();
+        assert_ne!(fs::read(&path).unwrap(), b"secret");
+        assert_eq!(new_async(&path).read().await.unwrap(), b"secret");
+
+        new_async(&dir).remove_all().await.unwrap();
+        let _ = fs::remove_file(key_path);
+    }
 }
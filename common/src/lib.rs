@@ -1,2 +1,4 @@
+pub mod envelope;
 pub mod file;
+pub mod mem;
 pub mod tls;
@@ -0,0 +1,281 @@
+//! The `mem://` backend, which keeps the files in the memory of the process, for the tests. Like
+//! the other backends, its files are digested and encrypted by `file::SealedFile`.
+use crate::file::{AsyncFile, Backend, BoxedReader, BoxedWriter, File};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::AsyncWrite;
+
+#[derive(Default)]
+struct Store {
+    files: BTreeMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+}
+
+/// A store shared by the files it opens. The directories are created by `create_dir_all` or
+/// implicitly by writing a file under them.
+#[derive(Clone, Default)]
+pub struct MemBackend {
+    store: Arc<Mutex<Store>>,
+}
+
+impl Backend for MemBackend {
+    fn open(&self, path: &str) -> Box<dyn File> {
+        Box::new(self.file(path))
+    }
+
+    fn open_async(&self, path: &str) -> Box<dyn AsyncFile> {
+        Box::new(self.file(path))
+    }
+}
+
+impl MemBackend {
+    fn file(&self, path: &str) -> MemFile {
+        MemFile {
+            path: path.trim_end_matches('/').to_string(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+pub struct MemFile {
+    path: String,
+    store: Arc<Mutex<Store>>,
+}
+
+impl MemFile {
+    fn read(&self) -> anyhow::Result<Vec<u8>> {
+        self.store
+            .lock()
+            .unwrap()
+            .files
+            .get(&self.path)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("{} not found", self.path))
+    }
+
+    fn write(&self, buf: &[u8]) {
+        insert(&self.store, &self.path, buf.to_vec());
+    }
+
+    fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        let store = self.store.lock().unwrap();
+        let prefix = format!("{}/", self.path);
+        let children: BTreeSet<String> = store
+            .files
+            .keys()
+            .chain(store.dirs.iter())
+            .filter_map(|path| path.strip_prefix(&prefix))
+            .map(|name| name.split('/').next().unwrap_or(name).to_string())
+            .collect();
+        if children.is_empty() && !store.dirs.contains(&self.path) {
+            anyhow::bail!("{} not found", self.path);
+        }
+        Ok(children.into_iter().collect())
+    }
+
+    fn create_dir_all(&self) {
+        self.store.lock().unwrap().dirs.insert(self.path.clone());
+    }
+
+    fn exists(&self) -> bool {
+        let store = self.store.lock().unwrap();
+        store.files.contains_key(&self.path) || store.dirs.contains(&self.path)
+    }
+
+    fn commit(&self, buf: &[u8]) -> bool {
+        if self.store.lock().unwrap().files.contains_key(&self.path) {
+            return false;
+        }
+        insert(&self.store, &self.path, buf.to_vec());
+        true
+    }
+
+    fn remove_all(&self) -> u64 {
+        let mut store = self.store.lock().unwrap();
+        let prefix = format!("{}/", self.path);
+        let under = |path: &String| *path == self.path || path.starts_with(&prefix);
+        let mut bytes = 0;
+        store.files.retain(|path, buf| {
+            if under(path) {
+                bytes += buf.len() as u64;
+            }
+            !under(path)
+        });
+        store.dirs.retain(|path| !under(path));
+        bytes
+    }
+}
+
+fn insert(store: &Mutex<Store>, path: &str, buf: Vec<u8>) {
+    let mut store = store.lock().unwrap();
+    if let Some((dir, _)) = path.rsplit_once('/') {
+        store.dirs.insert(dir.to_string());
+    }
+    store.files.insert(path.to_string(), buf);
+}
+
+impl std::io::Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        MemFile::write(self, buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl File for MemFile {
+    fn read(&self) -> anyhow::Result<Vec<u8>> {
+        MemFile::read(self)
+    }
+
+    fn read_to_string(&self) -> anyhow::Result<String> {
+        Ok(String::from_utf8(MemFile::read(self)?)?)
+    }
+
+    fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        MemFile::read_dir(self)
+    }
+
+    fn create_dir_all(&self) -> anyhow::Result<()> {
+        MemFile::create_dir_all(self);
+        Ok(())
+    }
+
+    fn exists(&self) -> anyhow::Result<bool> {
+        Ok(MemFile::exists(self))
+    }
+
+    fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        Ok(MemFile::commit(self, buf))
+    }
+}
+
+#[async_trait]
+impl AsyncFile for MemFile {
+    async fn read(&self) -> anyhow::Result<Vec<u8>> {
+        MemFile::read(self)
+    }
+
+    async fn reader(&self) -> anyhow::Result<BoxedReader> {
+        Ok(Box::new(std::io::Cursor::new(MemFile::read(self)?)))
+    }
+
+    async fn write(&self, buf: &[u8]) -> anyhow::Result<()> {
+        MemFile::write(self, buf);
+        Ok(())
+    }
+
+    async fn writer(&self) -> anyhow::Result<BoxedWriter> {
+        Ok(Box::new(MemWriter {
+            path: self.path.clone(),
+            store: self.store.clone(),
+            buf: Vec::new(),
+        }))
+    }
+
+    async fn read_dir(&self) -> anyhow::Result<Vec<String>> {
+        MemFile::read_dir(self)
+    }
+
+    async fn create_dir_all(&self) -> anyhow::Result<()> {
+        MemFile::create_dir_all(self);
+        Ok(())
+    }
+
+    async fn exists(&self) -> anyhow::Result<bool> {
+        Ok(MemFile::exists(self))
+    }
+
+    async fn commit(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        Ok(MemFile::commit(self, buf))
+    }
+
+    async fn remove_all(&self) -> anyhow::Result<u64> {
+        Ok(MemFile::remove_all(self))
+    }
+}
+
+// Published on shutdown, like the other writers.
+struct MemWriter {
+    path: String,
+    store: Arc<Mutex<Store>>,
+    buf: Vec<u8>,
+}
+
+impl AsyncWrite for MemWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let buf = std::mem::take(&mut self.buf);
+        insert(&self.store, &self.path, buf);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file;
+    use std::io::Write;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_mem_file() {
+        let dir = "mem://test_mem_file";
+        file::new_async(&format!("{}/empty", dir))
+            .create_dir_all()
+            .await
+            .unwrap();
+        file::new_async(&format!("{}/a/b", dir))
+            .write(b"b")
+            .await
+            .unwrap();
+        assert!(file::new(&format!("{}/c", dir)).commit(b"c").unwrap());
+        assert!(!file::new(&format!("{}/c", dir)).commit(b"x").unwrap());
+        assert_eq!(file::new(&format!("{}/c", dir)).read().unwrap(), b"c");
+        assert_eq!(
+            file::new(&file::digest_path(&format!("{}/c", dir)))
+                .read()
+                .unwrap(),
+            file::digest(b"c").as_bytes()
+        );
+
+        let mut writer = file::new_async(&format!("{}/d", dir))
+            .writer()
+            .await
+            .unwrap();
+        writer.write_all(b"dd").await.unwrap();
+        assert!(!file::new(&format!("{}/d", dir)).exists().unwrap());
+        writer.shutdown().await.unwrap();
+
+        assert_eq!(
+            file::new(dir).read_dir().unwrap(),
+            vec!["a", "c", "d", "empty"]
+        );
+        file::new(&file::digest_path(&format!("{}/a/b", dir)))
+            .write_all(file::digest(b"x").as_bytes())
+            .unwrap();
+        let e = file::new(&format!("{}/a/b", dir)).read().unwrap_err();
+        assert!(e.downcast_ref::<file::IntegrityError>().is_some());
+
+        // the contents and the digests of b, c and d
+        assert_eq!(file::new_async(dir).remove_all().await.unwrap(), 4 + 3 * 64);
+        assert!(!file::new(dir).exists().unwrap());
+        assert!(file::new(&format!("{}/c", dir)).read().is_err());
+    }
+}
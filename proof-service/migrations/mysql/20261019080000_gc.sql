@@ -0,0 +1,13 @@
+-- Add migration script here
+ALTER TABLE stage_task ADD COLUMN `gc_level` int not null default 0 AFTER `step`;
+
+CREATE TABLE IF NOT EXISTS gc_log
+(
+    id                  bigint        primary key auto_increment,
+    proof_id            varchar(255)  not null,
+    path                varchar(1024) not null,
+    bytes               bigint        not null default 0,
+    created_at          timestamp     not null default now()
+);
+
+CREATE INDEX index_gc_log_proof_id ON gc_log (proof_id);
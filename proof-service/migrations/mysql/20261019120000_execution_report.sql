@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS execution_report
+(
+    proof_id            varchar(255) primary key,
+    total_cycles        bigint       not null default 0,
+    report              mediumtext   not null,
+    created_at          timestamp    not null default now()
+);
@@ -0,0 +1,48 @@
+-- The schema of migrations/mysql up to 20261019080000_gc.sql
+CREATE TABLE IF NOT EXISTS stage_task
+(
+    id                  varchar(255) primary key,
+    address             varchar(64)  ,
+    status              int          not null default 0,
+    step                int          not null default 0,
+    gc_level            int          not null default 0,
+    context             text         ,
+    result              text         ,
+    check_at            bigint       not null default 0,
+    created_at          timestamptz  not null default now(),
+    updated_at          timestamptz  not null default now()
+);
+
+CREATE TABLE IF NOT EXISTS prove_task
+(
+    id                  varchar(255) not null primary key,
+    proof_id            varchar(255) not null default '',
+    itype               int          not null default 0,
+    status              int          not null default 0,
+    time_cost           bigint       not null default 0,
+    node_info           varchar(255) not null default '',
+    content             text         ,
+    check_at            bigint       not null default 0,
+    created_at          timestamptz  not null default now(),
+    updated_at          timestamptz  not null default now()
+);
+
+CREATE INDEX IF NOT EXISTS index_proof_id ON prove_task (proof_id);
+
+CREATE TABLE IF NOT EXISTS "user"
+(
+    address             varchar(64)  primary key,
+    created_at          timestamptz  not null default now(),
+    updated_at          timestamptz  not null default now()
+);
+
+CREATE TABLE IF NOT EXISTS gc_log
+(
+    id                  bigserial     primary key,
+    proof_id            varchar(255)  not null,
+    path                varchar(1024) not null,
+    bytes               bigint        not null default 0,
+    created_at          timestamptz   not null default now()
+);
+
+CREATE INDEX IF NOT EXISTS index_gc_log_proof_id ON gc_log (proof_id);
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS lease
+(
+    name                varchar(255) primary key,
+    owner               varchar(64)  not null,
+    expires_at          bigint       not null default 0
+);
@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS stage_event
+(
+    id                  bigserial    primary key,
+    proof_id            varchar(255) not null,
+    step                int          not null default 0,
+    task_id             varchar(255) not null default '',
+    node                varchar(255) not null default '',
+    state               int          not null default 0,
+    duration            bigint       not null default 0,
+    message             text         not null,
+    ts                  bigint       not null default 0
+);
+
+CREATE INDEX IF NOT EXISTS index_stage_event_proof_id ON stage_event (proof_id);
@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS notification_delivery
+(
+    id                  bigserial     primary key,
+    proof_id            varchar(255)  not null,
+    url                 varchar(1024) not null,
+    attempt             int           not null default 0,
+    delivered           boolean       not null default false,
+    message             text          not null,
+    ts                  bigint        not null default 0
+);
+
+CREATE INDEX IF NOT EXISTS index_notification_delivery_proof_id ON notification_delivery (proof_id);
@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS attestation
+(
+    proof_id            varchar(255) primary key,
+    attestation         text         not null,
+    created_at          timestamptz  not null default now()
+);
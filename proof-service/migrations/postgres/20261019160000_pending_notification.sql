@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS pending_notification
+(
+    id                  varchar(64)   primary key,
+    proof_id            varchar(255)  not null,
+    url                 varchar(1024) not null,
+    body                text          not null,
+    signature           varchar(128)  not null,
+    attempt             int           not null default 0,
+    created_at          bigint        not null default 0
+);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE stage_event ADD COLUMN retries int NOT NULL DEFAULT 0;
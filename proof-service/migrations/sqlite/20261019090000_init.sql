@@ -0,0 +1,48 @@
+-- The schema of migrations/mysql up to 20261019080000_gc.sql
+CREATE TABLE IF NOT EXISTS stage_task
+(
+    id                  text         primary key,
+    address             text         ,
+    status              integer      not null default 0,
+    step                integer      not null default 0,
+    gc_level            integer      not null default 0,
+    context             text         ,
+    result              text         ,
+    check_at            integer      not null default 0,
+    created_at          timestamp    not null default current_timestamp,
+    updated_at          timestamp    not null default current_timestamp
+);
+
+CREATE TABLE IF NOT EXISTS prove_task
+(
+    id                  text         not null primary key,
+    proof_id            text         not null default '',
+    itype               integer      not null default 0,
+    status              integer      not null default 0,
+    time_cost           integer      not null default 0,
+    node_info           text         not null default '',
+    content             text         ,
+    check_at            integer      not null default 0,
+    created_at          timestamp    not null default current_timestamp,
+    updated_at          timestamp    not null default current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS index_proof_id ON prove_task (proof_id);
+
+CREATE TABLE IF NOT EXISTS user
+(
+    address             text         primary key,
+    created_at          timestamp    not null default current_timestamp,
+    updated_at          timestamp    not null default current_timestamp
+);
+
+CREATE TABLE IF NOT EXISTS gc_log
+(
+    id                  integer      primary key autoincrement,
+    proof_id            text         not null,
+    path                text         not null,
+    bytes               integer      not null default 0,
+    created_at          timestamp    not null default current_timestamp
+);
+
+CREATE INDEX IF NOT EXISTS index_gc_log_proof_id ON gc_log (proof_id);
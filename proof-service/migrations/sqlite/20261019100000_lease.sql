@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS lease
+(
+    name                text         primary key,
+    owner               text         not null,
+    expires_at          integer      not null default 0
+);
@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS stage_event
+(
+    id                  integer      primary key autoincrement,
+    proof_id            text         not null,
+    step                integer      not null default 0,
+    task_id             text         not null default '',
+    node                text         not null default '',
+    state               integer      not null default 0,
+    duration            integer      not null default 0,
+    message             text         not null,
+    ts                  integer      not null default 0
+);
+
+CREATE INDEX IF NOT EXISTS index_stage_event_proof_id ON stage_event (proof_id);
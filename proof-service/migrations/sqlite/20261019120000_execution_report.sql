@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS execution_report
+(
+    proof_id            text         primary key,
+    total_cycles        integer      not null default 0,
+    report              text         not null,
+    created_at          timestamp    not null default current_timestamp
+);
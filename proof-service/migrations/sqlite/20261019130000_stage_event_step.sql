@@ -0,0 +1,2 @@
+-- Add migration script here
+CREATE INDEX index_stage_event_step ON stage_event (step, state);
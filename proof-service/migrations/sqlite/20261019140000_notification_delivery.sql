@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS notification_delivery
+(
+    id                  integer      primary key autoincrement,
+    proof_id            text         not null,
+    url                 text         not null,
+    attempt             integer      not null default 0,
+    delivered           boolean      not null default false,
+    message             text         not null,
+    ts                  integer      not null default 0
+);
+
+CREATE INDEX IF NOT EXISTS index_notification_delivery_proof_id ON notification_delivery (proof_id);
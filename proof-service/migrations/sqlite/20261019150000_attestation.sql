@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS attestation
+(
+    proof_id            text         primary key,
+    attestation         text         not null,
+    created_at          timestamp    not null default current_timestamp
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS pending_notification
+(
+    id                  text         primary key,
+    proof_id            text         not null,
+    url                 text         not null,
+    body                text         not null,
+    signature           text         not null,
+    attempt             integer      not null default 0,
+    created_at          integer      not null default 0
+);
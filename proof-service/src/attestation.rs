@@ -0,0 +1,113 @@
+//! Attestations of the results of the proofs. The stage signs a canonical summary of a successful
+//! proof with its key, so that the data returned by `GetStatus` can be trusted without verifying
+//! the proof itself.
+use crate::proto::stage_service::v1::Attestation;
+use common::file;
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::Signature;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+static WALLET: OnceLock<LocalWallet> = OnceLock::new();
+
+/// Sign the attestations with the hex secp256k1 private key in `key_path`.
+pub fn init(key_path: &str) -> anyhow::Result<()> {
+    let key = file::new(key_path).read_to_string()?;
+    let wallet = LocalWallet::from_str(key.trim())?;
+    tracing::info!("[attestation] signer {:?}", wallet.address());
+    let _ = WALLET.set(wallet);
+    Ok(())
+}
+
+/// The message signed for `attestation`.
+pub fn summary(attestation: &Attestation) -> String {
+    format!(
+        "zkm-attestation-v1&{}&{}&{}&{}&{}",
+        attestation.proof_id,
+        attestation.program_id,
+        attestation.public_values_hash,
+        attestation.proof_hash,
+        attestation.finished_at
+    )
+}
+
+fn hash(buf: &[u8]) -> String {
+    if buf.is_empty() {
+        String::new()
+    } else {
+        file::digest(buf)
+    }
+}
+
+async fn sign(wallet: &LocalWallet, mut attestation: Attestation) -> anyhow::Result<Attestation> {
+    let signature = wallet.sign_message(summary(&attestation)).await?;
+    attestation.signer = format!("{:?}", wallet.address());
+    attestation.signature = signature.to_string();
+    Ok(attestation)
+}
+
+/// Attest the result of a proof, None if the stage has no key.
+pub async fn attest(
+    proof_id: &str,
+    program_id: &str,
+    public_values: &[u8],
+    proof: &[u8],
+    finished_at: u64,
+) -> anyhow::Result<Option<Attestation>> {
+    let Some(wallet) = WALLET.get() else {
+        return Ok(None);
+    };
+    let attestation = Attestation {
+        proof_id: proof_id.to_string(),
+        program_id: program_id.to_string(),
+        public_values_hash: hash(public_values),
+        proof_hash: hash(proof),
+        finished_at,
+        ..Default::default()
+    };
+    Ok(Some(sign(wallet, attestation).await?))
+}
+
+/// Whether `attestation` is signed by its signer.
+pub fn verify(attestation: &Attestation) -> anyhow::Result<bool> {
+    let signature = Signature::from_str(&attestation.signature)?;
+    let recovered = signature.recover(summary(attestation))?;
+    Ok(format!("{:?}", recovered) == attestation.signer.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sign_and_verify() {
+        let wallet = LocalWallet::from_str(
+            "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318",
+        )
+        .unwrap();
+        let attestation = Attestation {
+            proof_id: "p1".to_string(),
+            program_id: "ab".to_string(),
+            public_values_hash: hash(b"public values"),
+            proof_hash: hash(b""),
+            finished_at: 1700000000,
+            ..Default::default()
+        };
+        assert_eq!(
+            summary(&attestation),
+            format!(
+                "zkm-attestation-v1&p1&ab&{}&&1700000000",
+                attestation.public_values_hash
+            )
+        );
+        let mut attestation = sign(&wallet, attestation).await.unwrap();
+        assert_eq!(
+            attestation.signer,
+            "0x2c7536e3605d9c16a7a3d7b1898e529396a65c23"
+        );
+        assert!(verify(&attestation).unwrap());
+
+        attestation.finished_at += 1;
+        assert!(!verify(&attestation).unwrap());
+    }
+}
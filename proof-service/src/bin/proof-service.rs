@@ -8,15 +8,20 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
 use prometheus::{Encoder, TextEncoder};
 
+use common::file;
 use proof_service::{
-    config, metrics,
+    config, local, metrics,
     proto::{
         prover_service::v1::prover_service_server::ProverServiceServer,
-        stage_service::v1::stage_service_server::StageServiceServer,
+        stage_service::v1::{
+            stage_service_server::StageServiceServer, GenerateProofRequest, Status,
+        },
     },
     prover_node::{self, ProverNode},
     prover_service::ProverServiceSVC,
+    shutdown,
     stage::stage_service::StageServiceSVC,
+    telemetry,
 };
 
 #[derive(Parser, Debug)]
@@ -26,6 +31,22 @@ struct Args {
     config: String,
     #[arg(short = 's', long = "stage", default_value_t = false)]
     stage: bool,
+    /// Prove a single ELF in-process, without MySQL or a separate prover
+    #[arg(long = "local", default_value_t = false)]
+    local: bool,
+    #[arg(long = "elf")]
+    elf: Option<String>,
+    #[arg(long = "public-input")]
+    public_input: Option<String>,
+    #[arg(long = "private-input")]
+    private_input: Option<String>,
+    #[arg(long = "proof-id")]
+    proof_id: Option<String>,
+    #[arg(long = "seg-size", default_value_t = 131072)]
+    seg_size: u32,
+    /// 1: Split, 3: Agg, 5: Snark
+    #[arg(long = "target-step", default_value_t = 5)]
+    target_step: i32,
 }
 
 #[tokio::main]
@@ -33,6 +54,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     config::setup_logger();
     let args = Args::parse();
     let runtime_config = config::RuntimeConfig::from_toml(&args.config).expect("Config is missing");
+    file::init_storage(runtime_config.storage.clone().unwrap_or_default())?;
+    tokio::spawn(async {
+        shutdown::wait_for_signal().await;
+        tracing::info!("Received shutdown signal, draining");
+        shutdown::drain();
+        shutdown::wait_for_signal().await;
+        tracing::warn!("Received second shutdown signal, exiting immediately");
+        std::process::exit(1);
+    });
+    if args.local {
+        let result = run_local(args, runtime_config).await;
+        telemetry::shutdown();
+        return result;
+    }
+
     let addr = runtime_config.addr.as_str().parse()?;
     let nodes_lock = crate::prover_node::instance();
     {
@@ -65,7 +101,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let stage = StageServiceSVC::new(runtime_config.clone()).await?;
         server
             .add_service(StageServiceServer::new(stage))
-            .serve(addr)
+            .serve_with_shutdown(addr, shutdown::drained())
     } else {
         #[cfg(all(feature = "prover", feature = "gpu"))]
         {
@@ -73,27 +109,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             plonky2::init_globalmem(134217728);
             prover::init_stark_op_stream_simple();
         }
-        let prover = ProverServiceSVC::new(runtime_config.clone());
+        let prover = ProverServiceSVC::new(runtime_config.clone())?;
         server
             .add_service(ProverServiceServer::new(prover))
-            .serve(addr)
+            .serve_with_shutdown(addr, shutdown::drained())
     };
 
     let metrics_addr = runtime_config.metrics_addr.as_str().parse()?;
-    let make_svc = make_service_fn(move |_| {
-        let registry = metrics::REGISTRY_INSTANCE.clone();
-        async move {
-            Ok::<_, hyper::Error>(service_fn(move |_: Request<Body>| {
-                let registry = registry.clone();
-                async move {
-                    let encoder = TextEncoder::new();
-                    let metric_families = registry.gather();
-                    let mut buffer = Vec::new();
-                    encoder.encode(&metric_families, &mut buffer).unwrap();
-                    Ok::<_, hyper::Error>(Response::new(Body::from(buffer)))
-                }
-            }))
-        }
+    let make_svc = make_service_fn(move |_| async move {
+        Ok::<_, hyper::Error>(service_fn(move |_: Request<Body>| async move {
+            let encoder = TextEncoder::new();
+            let metric_families = metrics::gather();
+            let mut buffer = Vec::new();
+            encoder.encode(&metric_families, &mut buffer).unwrap();
+            Ok::<_, hyper::Error>(Response::new(Body::from(buffer)))
+        }))
     });
     metrics::init_registry();
     let metrics_server = hyper::Server::bind(&metrics_addr).serve(make_svc);
@@ -124,10 +154,52 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !args.stage {
         plonky2::destroy_ctx();
     }
+    telemetry::shutdown();
 
     Ok(())
 }
 
+async fn run_local(
+    args: Args,
+    runtime_config: config::RuntimeConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let read = |path: &Option<String>| match path {
+        Some(path) => file::new(path).read(),
+        None => Ok(vec![]),
+    };
+    let request = GenerateProofRequest {
+        proof_id: args
+            .proof_id
+            .clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        elf_data: read(&args.elf)?,
+        seg_size: args.seg_size,
+        public_input_stream: read(&args.public_input)?,
+        private_input_stream: read(&args.private_input)?,
+        target_step: Some(args.target_step),
+        ..Default::default()
+    };
+    if request.elf_data.is_empty() {
+        return Err("--elf is required in local mode".into());
+    }
+    tracing::info!(
+        "Starting local proof {} under {}",
+        request.proof_id,
+        runtime_config.base_dir
+    );
+    let task = local::run(runtime_config, request).await?;
+    tracing::info!(
+        "[local] {} status: {} step: {}",
+        task.id,
+        task.status,
+        task.step
+    );
+    if task.status != Status::Success as i32 {
+        return Err(format!("proof {} failed with status {}", task.id, task.status).into());
+    }
+    Ok(())
+}
+
 pub async fn start_file_server(host: &str) -> Result<(), Box<dyn std::error::Error>> {
     let file_server = warp::fs::dir("public");
     warp::serve(file_server)
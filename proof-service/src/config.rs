@@ -1,4 +1,5 @@
 use crate::proto::includes::v1::ProverVersion;
+use crate::telemetry;
 use common::file;
 use serde_derive::Deserialize;
 use tracing_forest::ForestLayer;
@@ -6,6 +7,42 @@ use tracing_subscriber::{
     fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry,
 };
 
+/// The `[retention]` section of the stage config. The files are kept forever by default.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RetentionConfig {
+    // delete the segments once the aggregation succeeded, on the split node with segment_transfer
+    pub delete_segments: Option<bool>,
+    // delete everything but the final proof after this many days
+    pub intermediates_days: Option<u64>,
+    // delete the whole proof directory after this many days
+    pub proofs_days: Option<u64>,
+    // seconds between two collections
+    pub interval: Option<u64>,
+}
+
+/// The `[notify]` section of the stage config, see `notify`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct NotifyConfig {
+    // the HMAC-SHA256 key of the signature of the notifications, unsigned without it
+    pub secret: Option<String>,
+    // notified of the end of every proof, besides the callback url of the request
+    pub urls: Option<Vec<String>>,
+    // deliveries of a notification before giving up, 5 by default
+    pub attempts: Option<u32>,
+    // the hosts a callback url of a request may point to, `*.{domain}` for its subdomains
+    pub callback_hosts: Option<Vec<String>>,
+}
+
+/// The `[execute]` section of the stage config, the bounds of the programs run by `Execute` and
+/// `EstimateProof`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ExecuteConfig {
+    // cycles of a program before it is stopped, unbounded by default
+    pub max_cycles: Option<u64>,
+    // programs run at once, the others are refused, 2 by default
+    pub concurrency: Option<usize>,
+}
+
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct RuntimeConfig {
     pub addr: String,
@@ -18,10 +55,29 @@ pub struct RuntimeConfig {
     pub fileserver_url: Option<String>,
     // pub fileserver_addr: String,
     pub proving_key_paths: Vec<String>,
+    // persist the program proving keys, and the number of programs kept in memory
+    pub key_cache_dir: Option<String>,
+    pub key_cache_size: Option<usize>,
+    // set up the snark wrap keys when the prover starts instead of on the first snark task
+    pub snark_warm_up: Option<bool>,
 
     pub ca_cert_path: Option<String>,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
+
+    // S3 compatible object store of the `s3://` paths
+    pub storage: Option<file::StorageConfig>,
+    // the prover nodes do not share the base dir: the split inputs are sent inline and the
+    // segments are streamed from the split node to the prove nodes
+    pub segment_transfer: Option<bool>,
+    // garbage collection of the proof directories by the stage
+    pub retention: Option<RetentionConfig>,
+    // notifications of the end of the proofs by the stage
+    pub notify: Option<NotifyConfig>,
+    // hex secp256k1 private key signing the results of the proofs
+    pub attestation_key_path: Option<String>,
+    // programs run by the stage itself
+    pub execute: Option<ExecuteConfig>,
 }
 
 impl RuntimeConfig {
@@ -35,9 +91,18 @@ impl RuntimeConfig {
             fileserver_url: None,
             // fileserver_addr: "0.0.0.0:40000".to_string(),
             proving_key_paths: vec![],
+            key_cache_dir: None,
+            key_cache_size: None,
+            snark_warm_up: None,
             ca_cert_path: None,
             cert_path: None,
             key_path: None,
+            storage: None,
+            segment_transfer: None,
+            retention: None,
+            notify: None,
+            attestation_key_path: None,
+            execute: None,
         }
     }
 
@@ -57,7 +122,8 @@ impl RuntimeConfig {
 
 /// A simple logger.
 ///
-/// Set the `RUST_LOG` environment variable to be set to `info` or `debug`.
+/// Set the `RUST_LOG` environment variable to be set to `info` or `debug`. The spans are also
+/// exported to `OTEL_EXPORTER_OTLP_ENDPOINT` if it is set.
 pub fn setup_logger() {
     let env_filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new("off"))
@@ -76,6 +142,7 @@ pub fn setup_logger() {
             Registry::default()
                 .with(env_filter)
                 .with(ForestLayer::default())
+                .with(telemetry::otlp_layer())
                 .init();
         }
         "flat" => {
@@ -87,6 +154,7 @@ pub fn setup_logger() {
                 .with_env_filter(env_filter)
                 .with_span_events(FmtSpan::CLOSE)
                 .finish()
+                .with(telemetry::otlp_layer())
                 .init();
         }
         _ => {
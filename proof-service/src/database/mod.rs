@@ -0,0 +1,453 @@
+use async_trait::async_trait;
+use ethers::abi::ethereum_types;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+mod mysql;
+mod postgres;
+mod sqlite;
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct StageTask {
+    pub id: String,
+    pub status: i32,
+    pub context: Option<String>,
+    pub result: Option<String>,
+    pub check_at: i64,
+    pub step: i32,
+}
+
+#[warn(unused_macros)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct ProveTask {
+    pub id: String,
+    pub itype: i32,
+    pub proof_id: String,
+    pub status: i32,
+    pub time_cost: i64,
+    pub node_info: String,
+    pub content: Option<String>,
+    pub check_at: i64,
+}
+
+/// A step change of a proof, or a task result, see `GetProofTrace`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct StageEvent {
+    pub proof_id: String,
+    pub step: i32,
+    pub task_id: String,
+    pub node: String,
+    pub state: i32,
+    pub duration: i64,
+    pub message: String,
+    pub ts: i64,
+    // the times the task was rescheduled before it ended
+    pub retries: i32,
+}
+
+/// The duration (seconds) of a past task, with the total cycles of its proof if the execution
+/// report was recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct TaskDuration {
+    pub duration: i64,
+    pub total_cycles: Option<i64>,
+}
+
+/// An attempt to deliver the notification of the end of a proof to `url`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct NotificationDelivery {
+    pub proof_id: String,
+    pub url: String,
+    pub attempt: i32,
+    pub delivered: bool,
+    pub message: String,
+    pub ts: i64,
+}
+
+/// A notification to `url` which is not yet delivered nor given up, after `attempt` attempts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct PendingNotification {
+    pub id: String,
+    pub proof_id: String,
+    pub url: String,
+    pub body: String,
+    // empty if unsigned
+    pub signature: String,
+    pub attempt: i32,
+    pub created_at: i64,
+}
+
+#[warn(unused_macros)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, sqlx::FromRow)]
+pub struct User {
+    pub address: String,
+}
+
+/// The persistence of the stage and prove tasks, implemented for MySQL, Postgres and SQLite. Each
+/// has its own migrations under `migrations/{mysql,postgres,sqlite}`.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn migrate(&self) -> anyhow::Result<()>;
+
+    async fn get_incomplete_stage_tasks(
+        &self,
+        status: i32,
+        check_at: i64,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>>;
+
+    async fn get_stage_task(&self, proof_id: &str) -> anyhow::Result<StageTask>;
+
+    async fn insert_stage_task(
+        &self,
+        proof_id: &str,
+        address: &str,
+        status: i32,
+        context: &str,
+    ) -> anyhow::Result<bool>;
+
+    async fn update_stage_task(
+        &self,
+        proof_id: &str,
+        status: i32,
+        result: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Compare and set `check_at`, returns the number of rows updated.
+    async fn update_stage_task_check_at(
+        &self,
+        proof_id: &str,
+        old_check_at: u64,
+        check_at: u64,
+        step: i32,
+    ) -> anyhow::Result<u64>;
+
+    async fn insert_prove_task(&self, task: &ProveTask) -> anyhow::Result<bool>;
+
+    async fn get_prove_tasks(&self, proof_id: &str) -> anyhow::Result<Vec<ProveTask>>;
+
+    async fn get_prove_tasks_by_type(
+        &self,
+        proof_id: &str,
+        itype: i32,
+    ) -> anyhow::Result<Vec<ProveTask>>;
+
+    /// The proofs whose aggregation succeeded, with a gc level below `gc_level`.
+    async fn get_aggregated_stage_tasks(
+        &self,
+        gc_level: i32,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>>;
+
+    /// The proofs which are no longer computing, created before `created_before` (unix seconds),
+    /// with a gc level below `gc_level`.
+    async fn get_finished_stage_tasks(
+        &self,
+        gc_level: i32,
+        created_before: u64,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>>;
+
+    async fn update_stage_task_gc_level(
+        &self,
+        proof_id: &str,
+        gc_level: i32,
+    ) -> anyhow::Result<bool>;
+
+    /// Whether the context of a proof with a gc level below `gc_level` mentions `path`.
+    async fn is_referenced_by_stage_task(&self, path: &str, gc_level: i32) -> anyhow::Result<bool>;
+
+    async fn insert_gc_log(&self, proof_id: &str, path: &str, bytes: u64) -> anyhow::Result<bool>;
+
+    async fn insert_stage_event(&self, event: &StageEvent) -> anyhow::Result<bool>;
+
+    /// The events of the proof, oldest first.
+    async fn get_stage_events(&self, proof_id: &str) -> anyhow::Result<Vec<StageEvent>>;
+
+    /// The durations of the last `limit` tasks of `step` which ended in `state`, newest first.
+    async fn get_task_durations(
+        &self,
+        step: i32,
+        state: i32,
+        limit: i32,
+    ) -> anyhow::Result<Vec<TaskDuration>>;
+
+    /// Record the execution report (json) of the proof, replacing a previous one.
+    async fn save_execution_report(
+        &self,
+        proof_id: &str,
+        total_cycles: u64,
+        report: &str,
+    ) -> anyhow::Result<bool>;
+
+    async fn get_execution_report(&self, proof_id: &str) -> anyhow::Result<Option<String>>;
+
+    /// Record the attestation (json) of the proof, replacing a previous one.
+    async fn save_attestation(&self, proof_id: &str, attestation: &str) -> anyhow::Result<bool>;
+
+    async fn get_attestation(&self, proof_id: &str) -> anyhow::Result<Option<String>>;
+
+    async fn insert_notification_delivery(
+        &self,
+        delivery: &NotificationDelivery,
+    ) -> anyhow::Result<bool>;
+
+    /// The delivery attempts of the notifications of the proof, oldest first.
+    async fn get_notification_deliveries(
+        &self,
+        proof_id: &str,
+    ) -> anyhow::Result<Vec<NotificationDelivery>>;
+
+    async fn insert_pending_notification(
+        &self,
+        notification: &PendingNotification,
+    ) -> anyhow::Result<bool>;
+
+    /// The pending notifications, oldest first.
+    async fn get_pending_notifications(
+        &self,
+        limit: i32,
+    ) -> anyhow::Result<Vec<PendingNotification>>;
+
+    async fn update_pending_notification_attempt(
+        &self,
+        id: &str,
+        attempt: i32,
+    ) -> anyhow::Result<bool>;
+
+    async fn delete_pending_notification(&self, id: &str) -> anyhow::Result<bool>;
+
+    /// The users with this exact address.
+    async fn get_users(&self, address: &str) -> anyhow::Result<Vec<User>>;
+
+    /// Take the lease `name` until `expires_at` if it is free, expired at `now` or already held
+    /// by `owner` (unix seconds), and return whether `owner` holds it.
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        owner: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> anyhow::Result<bool>;
+
+    async fn release_lease(&self, name: &str, owner: &str) -> anyhow::Result<bool>;
+}
+
+/// The store selected by the scheme of the database url: `mysql://`, `postgres://` or `sqlite:`.
+#[derive(Clone)]
+pub struct Database {
+    store: Arc<dyn Store>,
+}
+
+impl std::ops::Deref for Database {
+    type Target = dyn Store;
+
+    fn deref(&self) -> &Self::Target {
+        self.store.as_ref()
+    }
+}
+
+impl Database {
+    pub fn new(database_url: &str) -> anyhow::Result<Self> {
+        let store: Arc<dyn Store> = match database_url.split_once(':').map(|(scheme, _)| scheme) {
+            Some("mysql") | Some("mariadb") => Arc::new(mysql::MySqlStore::new(database_url)?),
+            Some("postgres") | Some("postgresql") => {
+                Arc::new(postgres::PgStore::new(database_url)?)
+            }
+            Some("sqlite") => Arc::new(sqlite::SqliteStore::new(database_url)?),
+            _ => anyhow::bail!("unsupported database url {}", database_url),
+        };
+        Ok(Database { store })
+    }
+
+    #[allow(dead_code)]
+    pub async fn get_prove_task_infos<Info>(
+        &self,
+        proof_id: &str,
+        itype: i32,
+    ) -> anyhow::Result<Vec<Info>>
+    where
+        Info: serde::de::DeserializeOwned,
+    {
+        let rows = self.get_prove_tasks_by_type(proof_id, itype).await?;
+        let mut task_infos: Vec<Info> = vec![];
+        for row in rows {
+            let task_info = serde_json::from_str(row.content.as_ref().unwrap())
+                .map_err(|e| anyhow::anyhow!(e))?;
+            task_infos.push(task_info);
+        }
+        Ok(task_infos)
+    }
+
+    /// Query the whitelisting user
+    /// EIP55 support
+    #[allow(dead_code)]
+    pub async fn get_user(&self, address: &str) -> anyhow::Result<Vec<User>> {
+        let checksum_address =
+            ethers::utils::to_checksum(&address.parse::<ethereum_types::Address>()?, None);
+        // Determine whether “0x” is needed based on the specific `User` table in the database.
+        let checksum_address = checksum_address.trim_start_matches("0x");
+        tracing::debug!("searching address {}", checksum_address);
+        let rows = self.get_users(checksum_address).await?;
+        tracing::debug!("get_user: {:?}", rows);
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A migrated sqlite store in a file of its own, removed by the caller.
+    async fn migrated_db() -> (Database, PathBuf) {
+        let path = std::env::temp_dir().join(format!("stage-{}.db", uuid::Uuid::new_v4()));
+        let db = Database::new(&format!("sqlite://{}", path.display())).unwrap();
+        db.migrate().await.unwrap();
+        (db, path)
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_tasks() {
+        let (db, path) = migrated_db().await;
+        db.insert_stage_task("p1", "", 1, "{}").await.unwrap();
+        assert_eq!(
+            db.update_stage_task_check_at("p1", 0, 5, 2).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            db.update_stage_task_check_at("p1", 0, 6, 2).await.unwrap(),
+            0
+        );
+        let task = db.get_stage_task("p1").await.unwrap();
+        assert_eq!((task.check_at, task.step), (5, 2));
+        assert_eq!(
+            db.get_incomplete_stage_tasks(1, 10, 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let prove_task = ProveTask {
+            id: "t1".to_string(),
+            proof_id: "p1".to_string(),
+            itype: 2,
+            content: Some("1".to_string()),
+            ..Default::default()
+        };
+        db.insert_prove_task(&prove_task).await.unwrap();
+        let infos: Vec<i32> = db.get_prove_task_infos("p1", 2).await.unwrap();
+        assert_eq!(infos, vec![1]);
+
+        db.update_stage_task("p1", 3, "").await.unwrap();
+        assert_eq!(
+            db.get_finished_stage_tasks(1, u32::MAX as u64, 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(db
+            .get_finished_stage_tasks(1, 1, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_leases() {
+        let (db, path) = migrated_db().await;
+        assert!(db.acquire_lease("l", "a", 100, 160).await.unwrap());
+        assert!(db.acquire_lease("l", "a", 110, 170).await.unwrap());
+        assert!(!db.acquire_lease("l", "b", 120, 180).await.unwrap());
+        assert!(db.acquire_lease("l", "b", 171, 231).await.unwrap());
+        assert!(!db.release_lease("l", "a").await.unwrap());
+        assert!(db.release_lease("l", "b").await.unwrap());
+        assert!(db.acquire_lease("l", "a", 200, 260).await.unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_events() {
+        let (db, path) = migrated_db().await;
+        for (proof_id, duration) in [("p1", 4), ("p2", 6)] {
+            let event = StageEvent {
+                proof_id: proof_id.to_string(),
+                step: 1,
+                task_id: "t".to_string(),
+                state: 3,
+                duration,
+                retries: 2,
+                ..Default::default()
+            };
+            db.insert_stage_event(&event).await.unwrap();
+        }
+        let events = db.get_stage_events("p1").await.unwrap();
+        assert_eq!((events[0].duration, events[0].retries), (4, 2));
+
+        db.save_execution_report("p1", 2, "{}").await.unwrap();
+        let durations = db.get_task_durations(1, 3, 10).await.unwrap();
+        assert_eq!(
+            durations
+                .iter()
+                .map(|d| (d.duration, d.total_cycles))
+                .collect::<Vec<_>>(),
+            [(6, None), (4, Some(2))]
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_reports() {
+        let (db, path) = migrated_db().await;
+        assert_eq!(db.get_execution_report("p1").await.unwrap(), None);
+        db.save_execution_report("p1", 1, "{}").await.unwrap();
+        db.save_execution_report("p1", 2, "[]").await.unwrap();
+        assert_eq!(
+            db.get_execution_report("p1").await.unwrap().as_deref(),
+            Some("[]")
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_notifications() {
+        let (db, path) = migrated_db().await;
+        let pending = PendingNotification {
+            id: "n1".to_string(),
+            proof_id: "p1".to_string(),
+            url: "https://example.com".to_string(),
+            body: "{}".to_string(),
+            ..Default::default()
+        };
+        db.insert_pending_notification(&pending).await.unwrap();
+        db.update_pending_notification_attempt("n1", 2)
+            .await
+            .unwrap();
+        let pendings = db.get_pending_notifications(10).await.unwrap();
+        assert_eq!(
+            pendings
+                .iter()
+                .map(|p| (&*p.id, p.attempt))
+                .collect::<Vec<_>>(),
+            [("n1", 2)]
+        );
+        db.delete_pending_notification("n1").await.unwrap();
+        assert!(db.get_pending_notifications(10).await.unwrap().is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_attestations() {
+        let (db, path) = migrated_db().await;
+        assert_eq!(db.get_attestation("p1").await.unwrap(), None);
+        db.save_attestation("p1", "{}").await.unwrap();
+        db.save_attestation("p1", "[]").await.unwrap();
+        assert_eq!(
+            db.get_attestation("p1").await.unwrap().as_deref(),
+            Some("[]")
+        );
+        let _ = std::fs::remove_file(path);
+    }
+}
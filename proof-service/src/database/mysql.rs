@@ -0,0 +1,449 @@
+use super::{
+    NotificationDelivery, PendingNotification, ProveTask, StageEvent, StageTask, Store,
+    TaskDuration, User,
+};
+use crate::proto::includes::v1::Step;
+use crate::proto::stage_service::v1::Status;
+use async_trait::async_trait;
+
+pub struct MySqlStore {
+    db_pool: sqlx::mysql::MySqlPool,
+}
+
+impl MySqlStore {
+    pub fn new(database_url: &str) -> anyhow::Result<Self> {
+        let db_pool = sqlx::mysql::MySqlPool::connect_lazy(database_url)?;
+        Ok(MySqlStore { db_pool })
+    }
+}
+
+#[async_trait]
+impl Store for MySqlStore {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        sqlx::migrate!("./migrations/mysql")
+            .run(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_incomplete_stage_tasks(
+        &self,
+        status: i32,
+        check_at: i64,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>> {
+        let rows = sqlx::query_as!(
+            StageTask,
+            "SELECT id, status, context, result, check_at, step from stage_task where status = ? and check_at < ? limit ?",
+            status,
+            check_at,
+            limit,
+        )
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows)
+    }
+
+    async fn get_stage_task(&self, proof_id: &str) -> anyhow::Result<StageTask> {
+        let row = sqlx::query_as!(
+            StageTask,
+            "SELECT id, status, context, result, check_at, step from stage_task where id = ?",
+            proof_id,
+        )
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(row)
+    }
+
+    async fn insert_stage_task(
+        &self,
+        proof_id: &str,
+        address: &str,
+        status: i32,
+        context: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query!(
+            "INSERT INTO stage_task (id, address, status, context) values (?,?,?,?)",
+            proof_id,
+            address,
+            status,
+            context
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn update_stage_task(
+        &self,
+        proof_id: &str,
+        status: i32,
+        result: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query!(
+            "UPDATE stage_task set status = ?, result = ? where id = ?",
+            status,
+            result,
+            proof_id
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn update_stage_task_check_at(
+        &self,
+        proof_id: &str,
+        old_check_at: u64,
+        check_at: u64,
+        step: i32,
+    ) -> anyhow::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "UPDATE stage_task set check_at = ?, step = ? where id = ? and check_at = ?",
+            check_at,
+            step,
+            proof_id,
+            old_check_at
+        )
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    async fn insert_prove_task(&self, task: &ProveTask) -> anyhow::Result<bool> {
+        sqlx::query!(
+            "INSERT INTO prove_task (id, itype, proof_id, status, time_cost, node_info, content, check_at) values (?,?,?,?,?,?,?,?)",
+            task.id,
+            task.itype,
+            task.proof_id,
+            task.status,
+            task.time_cost,
+            task.node_info,
+            task.content,
+            task.check_at
+        )
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn get_prove_tasks(&self, proof_id: &str) -> anyhow::Result<Vec<ProveTask>> {
+        let rows = sqlx::query_as!(
+            ProveTask,
+            "SELECT id, itype, proof_id, status, time_cost, node_info, content, check_at from prove_task where proof_id = ?",
+            proof_id,
+        )
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows)
+    }
+
+    async fn get_prove_tasks_by_type(
+        &self,
+        proof_id: &str,
+        itype: i32,
+    ) -> anyhow::Result<Vec<ProveTask>> {
+        let rows = sqlx::query_as!(
+            ProveTask,
+            "SELECT id, itype, proof_id, status, time_cost, node_info, content, check_at from prove_task where proof_id = ? and itype = ?",
+            proof_id,
+            itype,
+        )
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows)
+    }
+
+    async fn get_aggregated_stage_tasks(
+        &self,
+        gc_level: i32,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>> {
+        let rows = sqlx::query_as::<_, StageTask>(
+            "SELECT id, status, context, result, check_at, step from stage_task where gc_level < ? and (status = ? or step = ?) limit ?",
+        )
+        .bind(gc_level)
+        .bind(i32::from(Status::Success))
+        .bind(i32::from(Step::Snark))
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_finished_stage_tasks(
+        &self,
+        gc_level: i32,
+        created_before: u64,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>> {
+        let rows = sqlx::query_as::<_, StageTask>(
+            "SELECT id, status, context, result, check_at, step from stage_task where gc_level < ? and status != ? and created_at < FROM_UNIXTIME(?) limit ?",
+        )
+        .bind(gc_level)
+        .bind(i32::from(Status::Computing))
+        .bind(created_before)
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn update_stage_task_gc_level(
+        &self,
+        proof_id: &str,
+        gc_level: i32,
+    ) -> anyhow::Result<bool> {
+        sqlx::query("UPDATE stage_task set gc_level = ? where id = ?")
+            .bind(gc_level)
+            .bind(proof_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn is_referenced_by_stage_task(&self, path: &str, gc_level: i32) -> anyhow::Result<bool> {
+        let row =
+            sqlx::query("SELECT id from stage_task where gc_level < ? and context like ? limit 1")
+                .bind(gc_level)
+                .bind(format!("%{}%", path))
+                .fetch_optional(&self.db_pool)
+                .await?;
+        Ok(row.is_some())
+    }
+
+    async fn insert_gc_log(&self, proof_id: &str, path: &str, bytes: u64) -> anyhow::Result<bool> {
+        sqlx::query("INSERT INTO gc_log (proof_id, path, bytes) values (?,?,?)")
+            .bind(proof_id)
+            .bind(path)
+            .bind(bytes)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn insert_stage_event(&self, event: &StageEvent) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO stage_event (proof_id, step, task_id, node, state, duration, message, ts, retries) values (?,?,?,?,?,?,?,?,?)",
+        )
+        .bind(&event.proof_id)
+        .bind(event.step)
+        .bind(&event.task_id)
+        .bind(&event.node)
+        .bind(event.state)
+        .bind(event.duration)
+        .bind(&event.message)
+        .bind(event.ts)
+        .bind(event.retries)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_stage_events(&self, proof_id: &str) -> anyhow::Result<Vec<StageEvent>> {
+        let rows = sqlx::query_as::<_, StageEvent>(
+            "SELECT proof_id, step, task_id, node, state, duration, message, ts, retries from stage_event where proof_id = ? order by id",
+        )
+        .bind(proof_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_task_durations(
+        &self,
+        step: i32,
+        state: i32,
+        limit: i32,
+    ) -> anyhow::Result<Vec<TaskDuration>> {
+        let rows = sqlx::query_as::<_, TaskDuration>(
+            "SELECT e.duration, r.total_cycles from stage_event e left join execution_report r on r.proof_id = e.proof_id where e.step = ? and e.state = ? and e.task_id != '' order by e.id desc limit ?",
+        )
+        .bind(step)
+        .bind(state)
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn save_execution_report(
+        &self,
+        proof_id: &str,
+        total_cycles: u64,
+        report: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query(
+            "REPLACE INTO execution_report (proof_id, total_cycles, report) values (?,?,?)",
+        )
+        .bind(proof_id)
+        .bind(total_cycles as i64)
+        .bind(report)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_execution_report(&self, proof_id: &str) -> anyhow::Result<Option<String>> {
+        let report = sqlx::query_scalar::<_, String>(
+            "SELECT report from execution_report where proof_id = ?",
+        )
+        .bind(proof_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(report)
+    }
+
+    async fn save_attestation(&self, proof_id: &str, attestation: &str) -> anyhow::Result<bool> {
+        sqlx::query("REPLACE INTO attestation (proof_id, attestation) values (?,?)")
+            .bind(proof_id)
+            .bind(attestation)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn get_attestation(&self, proof_id: &str) -> anyhow::Result<Option<String>> {
+        let attestation = sqlx::query_scalar::<_, String>(
+            "SELECT attestation from attestation where proof_id = ?",
+        )
+        .bind(proof_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(attestation)
+    }
+
+    async fn insert_notification_delivery(
+        &self,
+        delivery: &NotificationDelivery,
+    ) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO notification_delivery (proof_id, url, attempt, delivered, message, ts) values (?,?,?,?,?,?)",
+        )
+        .bind(&delivery.proof_id)
+        .bind(&delivery.url)
+        .bind(delivery.attempt)
+        .bind(delivery.delivered)
+        .bind(&delivery.message)
+        .bind(delivery.ts)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_notification_deliveries(
+        &self,
+        proof_id: &str,
+    ) -> anyhow::Result<Vec<NotificationDelivery>> {
+        let rows = sqlx::query_as::<_, NotificationDelivery>(
+            "SELECT proof_id, url, attempt, delivered, message, ts from notification_delivery where proof_id = ? order by id",
+        )
+        .bind(proof_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn insert_pending_notification(
+        &self,
+        notification: &PendingNotification,
+    ) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO pending_notification (id, proof_id, url, body, signature, attempt, created_at) values (?,?,?,?,?,?,?)",
+        )
+        .bind(&notification.id)
+        .bind(&notification.proof_id)
+        .bind(&notification.url)
+        .bind(&notification.body)
+        .bind(&notification.signature)
+        .bind(notification.attempt)
+        .bind(notification.created_at)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_pending_notifications(
+        &self,
+        limit: i32,
+    ) -> anyhow::Result<Vec<PendingNotification>> {
+        let rows = sqlx::query_as::<_, PendingNotification>(
+            "SELECT id, proof_id, url, body, signature, attempt, created_at from pending_notification order by created_at limit ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn update_pending_notification_attempt(
+        &self,
+        id: &str,
+        attempt: i32,
+    ) -> anyhow::Result<bool> {
+        sqlx::query("UPDATE pending_notification set attempt = ? where id = ?")
+            .bind(attempt)
+            .bind(id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn delete_pending_notification(&self, id: &str) -> anyhow::Result<bool> {
+        sqlx::query("DELETE from pending_notification where id = ?")
+            .bind(id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn get_users(&self, address: &str) -> anyhow::Result<Vec<User>> {
+        let rows = sqlx::query_as!(User, "SELECT address from user where address = ?", address)
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows)
+    }
+
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        owner: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> anyhow::Result<bool> {
+        let rows_affected = sqlx::query(
+            "UPDATE lease set owner = ?, expires_at = ? where name = ? and (owner = ? or expires_at < ?)",
+        )
+        .bind(owner)
+        .bind(expires_at)
+        .bind(name)
+        .bind(owner)
+        .bind(now)
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        if rows_affected == 1 {
+            return Ok(true);
+        }
+        let rows_affected =
+            sqlx::query("INSERT IGNORE INTO lease (name, owner, expires_at) values (?,?,?)")
+                .bind(name)
+                .bind(owner)
+                .bind(expires_at)
+                .execute(&self.db_pool)
+                .await?
+                .rows_affected();
+        Ok(rows_affected == 1)
+    }
+
+    async fn release_lease(&self, name: &str, owner: &str) -> anyhow::Result<bool> {
+        let rows_affected = sqlx::query("DELETE FROM lease where name = ? and owner = ?")
+            .bind(name)
+            .bind(owner)
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+        Ok(rows_affected == 1)
+    }
+}
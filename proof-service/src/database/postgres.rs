@@ -0,0 +1,447 @@
+use super::{
+    NotificationDelivery, PendingNotification, ProveTask, StageEvent, StageTask, Store,
+    TaskDuration, User,
+};
+use crate::proto::includes::v1::Step;
+use crate::proto::stage_service::v1::Status;
+use async_trait::async_trait;
+
+pub struct PgStore {
+    db_pool: sqlx::postgres::PgPool,
+}
+
+impl PgStore {
+    pub fn new(database_url: &str) -> anyhow::Result<Self> {
+        let db_pool = sqlx::postgres::PgPool::connect_lazy(database_url)?;
+        Ok(PgStore { db_pool })
+    }
+}
+
+#[async_trait]
+impl Store for PgStore {
+    async fn migrate(&self) -> anyhow::Result<()> {
+        sqlx::migrate!("./migrations/postgres")
+            .run(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_incomplete_stage_tasks(
+        &self,
+        status: i32,
+        check_at: i64,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>> {
+        let rows = sqlx::query_as::<_, StageTask>(
+            "SELECT id, status, context, result, check_at, step from stage_task where status = $1 and check_at < $2 limit $3",
+        )
+        .bind(status)
+        .bind(check_at)
+        .bind(i64::from(limit))
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_stage_task(&self, proof_id: &str) -> anyhow::Result<StageTask> {
+        let row = sqlx::query_as::<_, StageTask>(
+            "SELECT id, status, context, result, check_at, step from stage_task where id = $1",
+        )
+        .bind(proof_id)
+        .fetch_one(&self.db_pool)
+        .await?;
+        Ok(row)
+    }
+
+    async fn insert_stage_task(
+        &self,
+        proof_id: &str,
+        address: &str,
+        status: i32,
+        context: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query("INSERT INTO stage_task (id, address, status, context) values ($1,$2,$3,$4)")
+            .bind(proof_id)
+            .bind(address)
+            .bind(status)
+            .bind(context)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn update_stage_task(
+        &self,
+        proof_id: &str,
+        status: i32,
+        result: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query("UPDATE stage_task set status = $1, result = $2 where id = $3")
+            .bind(status)
+            .bind(result)
+            .bind(proof_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn update_stage_task_check_at(
+        &self,
+        proof_id: &str,
+        old_check_at: u64,
+        check_at: u64,
+        step: i32,
+    ) -> anyhow::Result<u64> {
+        let rows_affected = sqlx::query(
+            "UPDATE stage_task set check_at = $1, step = $2 where id = $3 and check_at = $4",
+        )
+        .bind(check_at as i64)
+        .bind(step)
+        .bind(proof_id)
+        .bind(old_check_at as i64)
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    async fn insert_prove_task(&self, task: &ProveTask) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO prove_task (id, itype, proof_id, status, time_cost, node_info, content, check_at) values ($1,$2,$3,$4,$5,$6,$7,$8)",
+        )
+        .bind(&task.id)
+        .bind(task.itype)
+        .bind(&task.proof_id)
+        .bind(task.status)
+        .bind(task.time_cost)
+        .bind(&task.node_info)
+        .bind(&task.content)
+        .bind(task.check_at)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_prove_tasks(&self, proof_id: &str) -> anyhow::Result<Vec<ProveTask>> {
+        let rows = sqlx::query_as::<_, ProveTask>(
+            "SELECT id, itype, proof_id, status, time_cost, node_info, content, check_at from prove_task where proof_id = $1",
+        )
+        .bind(proof_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_prove_tasks_by_type(
+        &self,
+        proof_id: &str,
+        itype: i32,
+    ) -> anyhow::Result<Vec<ProveTask>> {
+        let rows = sqlx::query_as::<_, ProveTask>(
+            "SELECT id, itype, proof_id, status, time_cost, node_info, content, check_at from prove_task where proof_id = $1 and itype = $2",
+        )
+        .bind(proof_id)
+        .bind(itype)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_aggregated_stage_tasks(
+        &self,
+        gc_level: i32,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>> {
+        let rows = sqlx::query_as::<_, StageTask>(
+            "SELECT id, status, context, result, check_at, step from stage_task where gc_level < $1 and (status = $2 or step = $3) limit $4",
+        )
+        .bind(gc_level)
+        .bind(i32::from(Status::Success))
+        .bind(i32::from(Step::Snark))
+        .bind(i64::from(limit))
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_finished_stage_tasks(
+        &self,
+        gc_level: i32,
+        created_before: u64,
+        limit: i32,
+    ) -> anyhow::Result<Vec<StageTask>> {
+        let rows = sqlx::query_as::<_, StageTask>(
+            "SELECT id, status, context, result, check_at, step from stage_task where gc_level < $1 and status != $2 and created_at < to_timestamp($3) limit $4",
+        )
+        .bind(gc_level)
+        .bind(i32::from(Status::Computing))
+        .bind(created_before as f64)
+        .bind(i64::from(limit))
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn update_stage_task_gc_level(
+        &self,
+        proof_id: &str,
+        gc_level: i32,
+    ) -> anyhow::Result<bool> {
+        sqlx::query("UPDATE stage_task set gc_level = $1 where id = $2")
+            .bind(gc_level)
+            .bind(proof_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn is_referenced_by_stage_task(&self, path: &str, gc_level: i32) -> anyhow::Result<bool> {
+        let row = sqlx::query(
+            "SELECT id from stage_task where gc_level < $1 and context like $2 limit 1",
+        )
+        .bind(gc_level)
+        .bind(format!("%{}%", path))
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    async fn insert_gc_log(&self, proof_id: &str, path: &str, bytes: u64) -> anyhow::Result<bool> {
+        sqlx::query("INSERT INTO gc_log (proof_id, path, bytes) values ($1,$2,$3)")
+            .bind(proof_id)
+            .bind(path)
+            .bind(bytes as i64)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn insert_stage_event(&self, event: &StageEvent) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO stage_event (proof_id, step, task_id, node, state, duration, message, ts, retries) values ($1,$2,$3,$4,$5,$6,$7,$8,$9)",
+        )
+        .bind(&event.proof_id)
+        .bind(event.step)
+        .bind(&event.task_id)
+        .bind(&event.node)
+        .bind(event.state)
+        .bind(event.duration)
+        .bind(&event.message)
+        .bind(event.ts)
+        .bind(event.retries)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_stage_events(&self, proof_id: &str) -> anyhow::Result<Vec<StageEvent>> {
+        let rows = sqlx::query_as::<_, StageEvent>(
+            "SELECT proof_id, step, task_id, node, state, duration, message, ts, retries from stage_event where proof_id = $1 order by id",
+        )
+        .bind(proof_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_task_durations(
+        &self,
+        step: i32,
+        state: i32,
+        limit: i32,
+    ) -> anyhow::Result<Vec<TaskDuration>> {
+        let rows = sqlx::query_as::<_, TaskDuration>(
+            "SELECT e.duration, r.total_cycles from stage_event e left join execution_report r on r.proof_id = e.proof_id where e.step = $1 and e.state = $2 and e.task_id != '' order by e.id desc limit $3",
+        )
+        .bind(step)
+        .bind(state)
+        .bind(i64::from(limit))
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn save_execution_report(
+        &self,
+        proof_id: &str,
+        total_cycles: u64,
+        report: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO execution_report (proof_id, total_cycles, report) values ($1,$2,$3) ON CONFLICT (proof_id) DO UPDATE SET total_cycles = EXCLUDED.total_cycles, report = EXCLUDED.report",
+        )
+        .bind(proof_id)
+        .bind(total_cycles as i64)
+        .bind(report)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_execution_report(&self, proof_id: &str) -> anyhow::Result<Option<String>> {
+        let report = sqlx::query_scalar::<_, String>(
+            "SELECT report from execution_report where proof_id = $1",
+        )
+        .bind(proof_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(report)
+    }
+
+    async fn save_attestation(&self, proof_id: &str, attestation: &str) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO attestation (proof_id, attestation) values ($1,$2) ON CONFLICT (proof_id) DO UPDATE SET attestation = EXCLUDED.attestation",
+        )
+        .bind(proof_id)
+        .bind(attestation)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_attestation(&self, proof_id: &str) -> anyhow::Result<Option<String>> {
+        let attestation = sqlx::query_scalar::<_, String>(
+            "SELECT attestation from attestation where proof_id = $1",
+        )
+        .bind(proof_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(attestation)
+    }
+
+    async fn insert_notification_delivery(
+        &self,
+        delivery: &NotificationDelivery,
+    ) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO notification_delivery (proof_id, url, attempt, delivered, message, ts) values ($1,$2,$3,$4,$5,$6)",
+        )
+        .bind(&delivery.proof_id)
+        .bind(&delivery.url)
+        .bind(delivery.attempt)
+        .bind(delivery.delivered)
+        .bind(&delivery.message)
+        .bind(delivery.ts)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_notification_deliveries(
+        &self,
+        proof_id: &str,
+    ) -> anyhow::Result<Vec<NotificationDelivery>> {
+        let rows = sqlx::query_as::<_, NotificationDelivery>(
+            "SELECT proof_id, url, attempt, delivered, message, ts from notification_delivery where proof_id = $1 order by id",
+        )
+        .bind(proof_id)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn insert_pending_notification(
+        &self,
+        notification: &PendingNotification,
+    ) -> anyhow::Result<bool> {
+        sqlx::query(
+            "INSERT INTO pending_notification (id, proof_id, url, body, signature, attempt, created_at) values ($1,$2,$3,$4,$5,$6,$7)",
+        )
+        .bind(&notification.id)
+        .bind(&notification.proof_id)
+        .bind(&notification.url)
+        .bind(&notification.body)
+        .bind(&notification.signature)
+        .bind(notification.attempt)
+        .bind(notification.created_at)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
+    async fn get_pending_notifications(
+        &self,
+        limit: i32,
+    ) -> anyhow::Result<Vec<PendingNotification>> {
+        let rows = sqlx::query_as::<_, PendingNotification>(
+            "SELECT id, proof_id, url, body, signature, attempt, created_at from pending_notification order by created_at limit $1",
+        )
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn update_pending_notification_attempt(
+        &self,
+        id: &str,
+        attempt: i32,
+    ) -> anyhow::Result<bool> {
+        sqlx::query("UPDATE pending_notification set attempt = $1 where id = $2")
+            .bind(attempt)
+            .bind(id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn delete_pending_notification(&self, id: &str) -> anyhow::Result<bool> {
+        sqlx::query("DELETE from pending_notification where id = $1")
+            .bind(id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(true)
+    }
+
+    async fn get_users(&self, address: &str) -> anyhow::Result<Vec<User>> {
+        // `user` is a reserved word
+        let rows = sqlx::query_as::<_, User>(r#"SELECT address from "user" where address = $1"#)
+            .bind(address)
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows)
+    }
+
+    async fn acquire_lease(
+        &self,
+        name: &str,
+        owner: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> anyhow::Result<bool> {
+        let rows_affected = sqlx::query(
+            "UPDATE lease set owner = $1, expires_at = $2 where name = $3 and (owner = $4 or expires_at < $5)",
+        )
+        .bind(owner)
+        .bind(expires_at as i64)
+        .bind(name)
+        .bind(owner)
+        .bind(now as i64)
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        if rows_affected == 1 {
+            return Ok(true);
+        }
+        let rows_affected = sqlx::query(
+            "INSERT INTO lease (name, owner, expires_at) values ($1,$2,$3) ON CONFLICT DO NOTHING",
+        )
+        .bind(name)
+        .bind(owner)
+        .bind(expires_at as i64)
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected == 1)
+    }
+
+    async fn release_lease(&self, name: &str, owner: &str) -> anyhow::Result<bool> {
+        let rows_affected = sqlx::query("DELETE FROM lease where name = $1 and owner = $2")
+            .bind(name)
+            .bind(owner)
+            .execute(&self.db_pool)
+            .await?
+            .rows_affected();
+        Ok(rows_affected == 1)
+    }
+}
@@ -0,0 +1,116 @@
+//! Leases in the `lease` table, so that several stage replicas share the stage tasks and the
+//! prover nodes. A lease is held by the owner id of the process until it expires, unless renewed.
+use crate::database::Database;
+use crate::stage::stage::get_timestamp;
+use std::sync::OnceLock;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// Seconds a lease is held without renewal.
+pub const LEASE_TTL: u64 = 60;
+const RENEW_INTERVAL: u64 = LEASE_TTL / 3;
+
+static DB: OnceLock<Database> = OnceLock::new();
+static OWNER: OnceLock<String> = OnceLock::new();
+
+/// Reserve the prover nodes through `db`. Without it the nodes are only reserved in the process.
+pub fn init(db: Database) {
+    let _ = DB.set(db);
+}
+
+/// The owner id of this process.
+pub fn owner() -> &'static str {
+    OWNER.get_or_init(|| uuid::Uuid::new_v4().to_string())
+}
+
+pub fn stage_task_lease(proof_id: &str) -> String {
+    format!("stage_task:{}", proof_id)
+}
+
+fn node_lease(addr: &str) -> String {
+    format!("node:{}", addr)
+}
+
+/// Take the lease, or renew it if this process already holds it.
+pub async fn acquire(db: &Database, name: &str) -> anyhow::Result<bool> {
+    let now = get_timestamp();
+    db.acquire_lease(name, owner(), now, now + LEASE_TTL).await
+}
+
+pub async fn release(db: &Database, name: &str) -> anyhow::Result<bool> {
+    db.release_lease(name, owner()).await
+}
+
+/// A prover node reserved for one task, renewed in the background and released on drop.
+pub struct NodeLease {
+    name: String,
+    renewer: Option<JoinHandle<()>>,
+    // set once another replica took the node
+    lost: watch::Receiver<bool>,
+}
+
+impl NodeLease {
+    /// Resolves once another replica took the node, the task on it is then handed back.
+    pub async fn lost(&mut self) {
+        if self.lost.wait_for(|lost| *lost).await.is_err() {
+            // not renewed, so never lost
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for NodeLease {
+    fn drop(&mut self) {
+        if let (Some(renewer), Some(db)) = (self.renewer.take(), DB.get()) {
+            renewer.abort();
+            let name = std::mem::take(&mut self.name);
+            tokio::spawn(async move {
+                if let Err(e) = release(db, &name).await {
+                    tracing::warn!("[lease] release {} failed: {:?}", name, e);
+                }
+            });
+        }
+    }
+}
+
+/// Reserve the node across the stage replicas, None if another replica holds it.
+pub async fn reserve_node(addr: &str) -> Option<NodeLease> {
+    let name = node_lease(addr);
+    let (lost_tx, lost) = watch::channel(false);
+    let Some(db) = DB.get() else {
+        return Some(NodeLease {
+            name,
+            renewer: None,
+            lost,
+        });
+    };
+    match acquire(db, &name).await {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => {
+            tracing::error!("[lease] reserve {} failed: {:?}", name, e);
+            return None;
+        }
+    }
+    let renewed = name.clone();
+    let renewer = tokio::spawn(async move {
+        loop {
+            time::sleep(time::Duration::from_secs(RENEW_INTERVAL)).await;
+            match acquire(db, &renewed).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    tracing::warn!("[lease] {} taken by another replica", renewed);
+                    let _ = lost_tx.send(true);
+                    break;
+                }
+                Err(e) => tracing::warn!("[lease] renew {} failed: {:?}", renewed, e),
+            }
+        }
+    });
+    Some(NodeLease {
+        name,
+        renewer: Some(renewer),
+        lost,
+    })
+}
@@ -1,10 +1,16 @@
+pub mod attestation;
 pub mod config;
 pub mod database;
+pub mod lease;
+pub mod local;
 pub mod metrics;
+pub mod notify;
 pub mod prover_client;
 pub mod prover_node;
 pub mod prover_service;
+pub mod shutdown;
 pub mod stage;
+pub mod telemetry;
 
 pub mod proto;
 
@@ -0,0 +1,211 @@
+//! Standalone mode: drive a single proof through the stage state machine against an in-process
+//! prover, without MySQL or gRPC. Task records are stored as json files next to the proof.
+use crate::database::{ProveTask, StageTask};
+use crate::proto::includes::v1::Step;
+use crate::proto::prover_service::v1::prover_service_server::ProverService;
+use crate::proto::stage_service::v1::{GenerateProofRequest, Status};
+use crate::prover_client::{
+    aggregate_request, prove_request, result_code_to_state, snark_request, split_request,
+};
+use crate::prover_service::ProverServiceSVC;
+use crate::stage::{
+    stage::Stage,
+    stage_service::prepare_generate_task,
+    tasks::{
+        save_task, Task, TASK_ITYPE_AGG, TASK_ITYPE_FINAL, TASK_ITYPE_PROVE, TASK_ITYPE_SPLIT,
+        TASK_STATE_FAILED, TASK_STATE_SUCCESS,
+    },
+};
+use crate::{config, shutdown};
+use common::file;
+use std::io::Write;
+use tonic::Request;
+
+/// File-backed replacement of the `stage_task` and `prove_task` tables.
+pub struct LocalTaskStore {
+    dir: String,
+}
+
+impl LocalTaskStore {
+    pub fn new(dir: &str) -> anyhow::Result<Self> {
+        file::new(&format!("{dir}/tasks")).create_dir_all()?;
+        Ok(Self { dir: dir.into() })
+    }
+
+    pub fn update_stage_task(&self, task: &StageTask) -> anyhow::Result<()> {
+        let path = format!("{}/stage_task.json", self.dir);
+        file::new(&path).write_all(&serde_json::to_vec_pretty(task)?)?;
+        Ok(())
+    }
+
+    pub async fn insert_prove_task(&self, task: &ProveTask) -> anyhow::Result<()> {
+        let path = format!("{}/tasks/{}.json", self.dir, task.id);
+        file::new(&path).write_all(&serde_json::to_vec_pretty(task)?)?;
+        Ok(())
+    }
+}
+
+async fn run_task(prover: &ProverServiceSVC, task: Task) -> Task {
+    match task {
+        Task::Split(mut task) => {
+            tracing::info!("[local] split {} start", task.task_id);
+            match prover.split_elf(Request::new(split_request(&task))).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    task.state = result_code(response.result.as_ref());
+                    task.total_steps = response.total_steps;
+                    task.total_segments = response.total_segments;
+                    task.execution_report = response.execution_report;
+                }
+                Err(e) => task.state = failed(&task.task_id, e),
+            }
+            Task::Split(task)
+        }
+        Task::Prove(mut task) => {
+            tracing::info!("[local] prove {}:{} start", task.task_id, task.file_no);
+            match prover.prove(Request::new(prove_request(&task))).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    task.state = result_code(response.result.as_ref());
+                    task.output = response.output_receipt;
+                }
+                Err(e) => task.state = failed(&task.task_id, e),
+            }
+            Task::Prove(task)
+        }
+        Task::Agg(mut task) => {
+            tracing::info!(
+                "[local] aggregate {}:{} start",
+                task.task_id,
+                task.agg_index
+            );
+            match prover
+                .aggregate(Request::new(aggregate_request(&task)))
+                .await
+            {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    task.state = result_code(response.result.as_ref());
+                    task.output = response.agg_receipt;
+                }
+                Err(e) => task.state = failed(&task.task_id, e),
+            }
+            Task::Agg(task)
+        }
+        Task::Snark(mut task) => {
+            tracing::info!("[local] snark {} start", task.task_id);
+            match prover.snark_proof(Request::new(snark_request(&task))).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    task.state = result_code(response.result.as_ref());
+                    task.output = response.snark_proof_with_public_inputs;
+                }
+                Err(e) => task.state = failed(&task.task_id, e),
+            }
+            Task::Snark(task)
+        }
+    }
+}
+
+fn result_code(result: Option<&crate::proto::prover_service::v1::Result>) -> u32 {
+    result
+        .map(|result| result_code_to_state(result.code))
+        .unwrap_or(TASK_STATE_FAILED)
+}
+
+fn failed(task_id: &str, e: tonic::Status) -> u32 {
+    tracing::error!("[local] task {} failed: {}", task_id, e);
+    TASK_STATE_FAILED
+}
+
+/// Prove `request` with an in-process prover, writing everything under
+/// `{base_dir}/proof/{proof_id}` as the stage service does. Tasks are run one at a time, since
+/// they share a single prover.
+pub async fn run(
+    config: config::RuntimeConfig,
+    request: GenerateProofRequest,
+) -> anyhow::Result<StageTask> {
+    let target_step = request
+        .target_step
+        .and_then(Step::from_i32)
+        .unwrap_or(Step::Snark);
+    let generate_task = prepare_generate_task(&config.base_dir, &request, target_step).await?;
+    let store = LocalTaskStore::new(&generate_task.base_dir)?;
+    let mut stage_task = StageTask {
+        id: request.proof_id.clone(),
+        status: Status::Computing.into(),
+        context: Some(serde_json::to_string(&generate_task)?),
+        ..Default::default()
+    };
+    store.update_stage_task(&stage_task)?;
+
+    let prover = ProverServiceSVC::new(config)?;
+    let mut stage = Stage::new(generate_task.clone());
+    stage.dispatch();
+    loop {
+        if shutdown::is_draining() {
+            anyhow::bail!("interrupted at {:?}", stage.step);
+        }
+        let task = match stage.step {
+            Step::Prove => stage
+                .get_split_task()
+                .map(Task::Split)
+                .or_else(|| stage.get_prove_task().map(Task::Prove))
+                .or_else(|| {
+                    if stage.is_tasks_gen_done {
+                        stage.get_agg_task().map(Task::Agg)
+                    } else {
+                        None
+                    }
+                }),
+            Step::Snark => stage.get_snark_task().map(Task::Snark),
+            _ => None,
+        };
+        match task {
+            Some(task) => match run_task(&prover, task).await {
+                Task::Split(mut data) => {
+                    stage.on_split_task(&mut data);
+                    save_task!(data, store, TASK_ITYPE_SPLIT, "local".to_string());
+                }
+                Task::Prove(mut data) => {
+                    stage.on_prove_task(&mut data);
+                    save_task!(data, store, TASK_ITYPE_PROVE, "local".to_string());
+                }
+                Task::Agg(mut data) => {
+                    stage.on_agg_task(&mut data);
+                    save_task!(data, store, TASK_ITYPE_AGG, "local".to_string());
+                }
+                Task::Snark(mut data) => {
+                    stage.on_snark_task(&mut data);
+                    save_task!(data, store, TASK_ITYPE_FINAL, "local".to_string());
+                }
+            },
+            None => tokio::time::sleep(tokio::time::Duration::from_millis(200)).await,
+        }
+        if stage.is_success() || stage.is_error() {
+            break;
+        }
+        stage.dispatch();
+        stage_task.step = stage.step.into();
+    }
+
+    if stage.is_error() {
+        stage_task.status = match stage.step {
+            Step::Split => Status::SplitError,
+            Step::Prove => Status::ProveError,
+            Step::Agg => Status::AggError,
+            Step::Snark => Status::SnarkError,
+            _ => Status::InternalError,
+        }
+        .into();
+    } else {
+        stage_task.status = Status::Success.into();
+        if generate_task.target_step == Step::Snark {
+            stage_task.result = Some(file::new(&generate_task.snark_path).read_to_string()?);
+        }
+        tracing::info!("[local] finished {:?} ", stage);
+    }
+    stage_task.step = stage.step.into();
+    store.update_stage_task(&stage_task)?;
+    Ok(stage_task)
+}
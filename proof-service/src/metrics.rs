@@ -1,5 +1,12 @@
+use crate::proto::includes::v1::Step;
+use crate::stage::tasks::{Trace, TASK_STATE_SUCCESS};
 use lazy_static::lazy_static;
-use prometheus::{CounterVec, Gauge, HistogramOpts, HistogramVec, Opts, Registry};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    exponential_buckets, CounterVec, Histogram, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry,
+};
+use std::collections::HashMap;
 use std::time::Instant;
 
 lazy_static! {
@@ -11,14 +18,148 @@ lazy_static! {
         &["method"]
     )
     .unwrap();
-    pub static ref SEGMENTS_GAUGE: Gauge =
-        Gauge::new("stage_segment_count", "Current number of segments").unwrap();
+    pub static ref TASK_DURATION_HISTOGRAM_VEC: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "stage_task_duration_seconds",
+            "Duration of the successful tasks"
+        )
+        .buckets(exponential_buckets(1.0, 2.0, 14).unwrap()),
+        &["task", "version"]
+    )
+    .unwrap();
+    pub static ref TASK_FAILURE_COUNTER_VEC: IntCounterVec = IntCounterVec::new(
+        Opts::new("stage_task_failures", "Failed task rpcs by error class"),
+        &["task", "class"]
+    )
+    .unwrap();
+    pub static ref PENDING_TASKS_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("stage_pending_tasks", "Tasks waiting for a prover node"),
+        &["task"]
+    )
+    .unwrap();
+    pub static ref NODE_STATUS_GAUGE_VEC: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "prover_node_status",
+            "1 if the prover node is in the status"
+        ),
+        &["node", "status"]
+    )
+    .unwrap();
+    pub static ref PROOF_SEGMENTS_HISTOGRAM: Histogram = Histogram::with_opts(
+        HistogramOpts::new("proof_segments", "Segments per proof")
+            .buckets(exponential_buckets(1.0, 2.0, 16).unwrap())
+    )
+    .unwrap();
+    pub static ref PROOF_CYCLES_HISTOGRAM: Histogram = Histogram::with_opts(
+        HistogramOpts::new("proof_cycles", "Cycles per proof")
+            .buckets(exponential_buckets(1e6, 4.0, 10).unwrap())
+    )
+    .unwrap();
+    pub static ref KEY_CACHE_COUNTER_VEC: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "key_cache_lookups",
+            "Proving key lookups served from memory (hit), from disk (load) or by a setup"
+        ),
+        &["result"]
+    )
+    .unwrap();
 }
 
 pub fn init_registry() {
     let _ = REGISTRY_INSTANCE.register(Box::new(REQ_COUNTER_VEC.clone()));
     let _ = REGISTRY_INSTANCE.register(Box::new(METHOD_HISTOGRAM_VEC.clone()));
-    let _ = REGISTRY_INSTANCE.register(Box::new(SEGMENTS_GAUGE.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TASK_DURATION_HISTOGRAM_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(TASK_FAILURE_COUNTER_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PENDING_TASKS_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(NODE_STATUS_GAUGE_VEC.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROOF_SEGMENTS_HISTOGRAM.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(PROOF_CYCLES_HISTOGRAM.clone()));
+    let _ = REGISTRY_INSTANCE.register(Box::new(KEY_CACHE_COUNTER_VEC.clone()));
+}
+
+/// Refresh the metrics sampled at scrape time, and gather the registry.
+pub fn gather() -> Vec<MetricFamily> {
+    {
+        let nodes = crate::prover_node::instance().lock().unwrap();
+        for node in nodes
+            .get_nodes()
+            .iter()
+            .chain(nodes.get_snark_nodes().iter())
+        {
+            let busy = *node.status.lock().unwrap() == crate::prover_node::NodeStatus::Busy;
+            NODE_STATUS_GAUGE_VEC
+                .with_label_values(&[&node.addr, "busy"])
+                .set(busy as i64);
+            NODE_STATUS_GAUGE_VEC
+                .with_label_values(&[&node.addr, "idle"])
+                .set(!busy as i64);
+        }
+    }
+    #[cfg(feature = "prover_v2")]
+    {
+        let stats = prover_v2::key_cache_stats();
+        for (result, total) in [
+            ("hit", stats.hits),
+            ("load", stats.loads),
+            ("setup", stats.setups),
+        ] {
+            let counter = KEY_CACHE_COUNTER_VEC.with_label_values(&[result]);
+            counter.inc_by(total.saturating_sub(counter.get()));
+        }
+    }
+    REGISTRY_INSTANCE.gather()
+}
+
+pub fn task_label(step: Step) -> &'static str {
+    match step {
+        Step::Split => "split",
+        Step::Prove => "prove",
+        Step::Agg => "agg",
+        Step::Snark => "snark",
+        _ => "other",
+    }
+}
+
+/// Observe the duration of a task result of a proof generated by the prover `version`.
+pub fn observe_task(step: Step, version: &str, trace: &Trace, state: u32) {
+    if state == TASK_STATE_SUCCESS && trace.finish_ts >= trace.start_ts {
+        TASK_DURATION_HISTOGRAM_VEC
+            .with_label_values(&[task_label(step), version])
+            .observe(trace.duration() as f64);
+    }
+}
+
+pub fn count_failure(step: Step, class: &str) {
+    TASK_FAILURE_COUNTER_VEC
+        .with_label_values(&[task_label(step), class])
+        .inc();
+}
+
+/// The tasks of a proof waiting for a prover node, summed over the running proofs into
+/// `stage_pending_tasks`. They are taken out of the sum when dropped.
+#[derive(Default)]
+pub struct PendingTasks {
+    counts: HashMap<&'static str, i64>,
+}
+
+impl PendingTasks {
+    pub fn set(&mut self, step: Step, count: usize) {
+        let task = task_label(step);
+        let last = self.counts.insert(task, count as i64).unwrap_or_default();
+        PENDING_TASKS_GAUGE_VEC
+            .with_label_values(&[task])
+            .add(count as i64 - last);
+    }
+}
+
+impl Drop for PendingTasks {
+    fn drop(&mut self) {
+        for (task, count) in self.counts.drain() {
+            PENDING_TASKS_GAUGE_VEC
+                .with_label_values(&[task])
+                .sub(count);
+        }
+    }
 }
 
 pub async fn record_metrics<F, Fut, T>(
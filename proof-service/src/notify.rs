@@ -0,0 +1,454 @@
+//! Notifications of the end of the proofs, sent by the stage once the `stage_task` row is updated.
+//! A notification is a json [`Notification`], signed by HMAC-SHA256 under the secret of the
+//! `[notify]` config, and delivered to a url by the sink of its scheme. Every delivery attempt is
+//! recorded in the `notification_delivery` table. A notification is kept in the
+//! `pending_notification` table until it is delivered or given up, and the deliveries interrupted
+//! by a crash are resumed by a stage replica, under the `notification:{id}` lease.
+use crate::config::NotifyConfig;
+use crate::database::{Database, NotificationDelivery, PendingNotification};
+use crate::lease;
+use crate::proto::stage_service::v1::Attestation;
+use crate::shutdown;
+use crate::stage::stage::get_timestamp;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// The http header of the hex signature of the body.
+pub const SIGNATURE_HEADER: &str = "X-Zkm-Signature";
+const DEFAULT_ATTEMPTS: u32 = 5;
+// seconds
+const MAX_BACKOFF: u64 = 60;
+const HTTP_TIMEOUT: u64 = 10;
+// seconds between two scans of the pending notifications
+const RESUME_INTERVAL: u64 = 60;
+const RESUME_BATCH: i32 = 100;
+
+static CONFIG: OnceLock<NotifyConfig> = OnceLock::new();
+
+/// Notify the proofs by `config`. Without it only the callback urls are notified, unsigned.
+pub fn init(config: NotifyConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static NotifyConfig {
+    CONFIG.get_or_init(NotifyConfig::default)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Notification {
+    pub proof_id: String,
+    // stage.v1.Status of the proof
+    pub status: i32,
+    // the step the proof ended at
+    pub step: i32,
+    pub error_message: String,
+    // unix seconds
+    pub finished_at: u64,
+    // see `attestation`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<Attestation>,
+}
+
+/// Delivers the notifications to the urls of a scheme. `http://` and `https://` are built in, as
+/// well as `file://{path}` appending them as lines to a local file and `unix://{path}` writing
+/// them to a unix socket, e.g. of a job system or of a local stand-in for the tests.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// `signature` is set if a secret is configured.
+    async fn deliver(&self, url: &str, body: &[u8], signature: Option<&str>) -> anyhow::Result<()>;
+}
+
+struct HttpSink {
+    client: reqwest::Client,
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+    async fn deliver(&self, url: &str, body: &[u8], signature: Option<&str>) -> anyhow::Result<()> {
+        let mut request = self
+            .client
+            .post(url)
+            .timeout(Duration::from_secs(HTTP_TIMEOUT))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let response = request.send().await?;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "http status {}",
+            response.status()
+        );
+        Ok(())
+    }
+}
+
+fn line(body: &[u8]) -> Vec<u8> {
+    [body, b"\n"].concat()
+}
+
+struct FileSink;
+
+#[async_trait]
+impl Sink for FileSink {
+    async fn deliver(&self, url: &str, body: &[u8], _: Option<&str>) -> anyhow::Result<()> {
+        let path = url.trim_start_matches("file://");
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(&line(body)).await?;
+        Ok(())
+    }
+}
+
+struct UnixSink;
+
+#[async_trait]
+impl Sink for UnixSink {
+    async fn deliver(&self, url: &str, body: &[u8], _: Option<&str>) -> anyhow::Result<()> {
+        let path = url.trim_start_matches("unix://");
+        let mut stream = tokio::net::UnixStream::connect(path).await?;
+        stream.write_all(&line(body)).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+type Sinks = HashMap<String, Arc<dyn Sink>>;
+
+static SINKS: OnceLock<RwLock<Sinks>> = OnceLock::new();
+
+fn sinks() -> &'static RwLock<Sinks> {
+    SINKS.get_or_init(|| {
+        // a redirect could lead a callback out of the allowed hosts
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("build the http client of the notifications");
+        let http: Arc<dyn Sink> = Arc::new(HttpSink { client });
+        let mut sinks: Sinks = HashMap::new();
+        sinks.insert("http".to_string(), http.clone());
+        sinks.insert("https".to_string(), http);
+        sinks.insert("file".to_string(), Arc::new(FileSink));
+        sinks.insert("unix".to_string(), Arc::new(UnixSink));
+        RwLock::new(sinks)
+    })
+}
+
+/// Deliver to the `{scheme}://` urls by `sink`, replacing the sink registered for it.
+pub fn register_sink(scheme: &str, sink: impl Sink + 'static) {
+    sinks()
+        .write()
+        .unwrap()
+        .insert(scheme.to_string(), Arc::new(sink));
+}
+
+fn sink(url: &str) -> Option<Arc<dyn Sink>> {
+    let (scheme, _) = url.split_once("://")?;
+    sinks().read().unwrap().get(scheme).cloned()
+}
+
+/// Whether a request may ask for a notification to `url`. Only the config sets the other
+/// schemes, so that a client can not make the stage write to its files. The host must be one of
+/// the `callback_hosts` of the config, without them any host but the loopback, private and
+/// link-local addresses. The names are not resolved, set `callback_hosts` to keep the callbacks
+/// out of the internal network.
+pub fn is_valid_callback(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    match (url.host_str(), &config().callback_hosts) {
+        (None, _) => false,
+        (Some(host), Some(hosts)) => hosts.iter().any(|allowed| is_allowed_host(host, allowed)),
+        (Some(host), None) => !is_internal_host(host),
+    }
+}
+
+fn is_allowed_host(host: &str, allowed: &str) -> bool {
+    match allowed.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.')),
+        None => host.eq_ignore_ascii_case(allowed),
+    }
+}
+
+fn is_internal_host(host: &str) -> bool {
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        Ok(IpAddr::V6(ip)) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local and link-local
+                || (ip.segments()[0] & 0xfe00) == 0xfc00
+                || (ip.segments()[0] & 0xffc0) == 0xfe80
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_internal_host(&ip.to_string()))
+        }
+        Err(_) => false,
+    }
+}
+
+/// The hex HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes a key of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// The pending notifications delivered by this process.
+static DELIVERING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn delivering() -> &'static Mutex<HashSet<String>> {
+    DELIVERING.get_or_init(Default::default)
+}
+
+fn notification_lease(id: &str) -> String {
+    format!("notification:{}", id)
+}
+
+/// Resume the pending notifications every minute, those of a crashed replica included, until the
+/// process drains.
+pub fn start(db: Database) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(RESUME_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown::draining() => break,
+            }
+            if let Err(e) = resume(&db).await {
+                tracing::error!("[notify] resume error: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Deliver in the background the pending notifications which no replica is delivering.
+pub async fn resume(db: &Database) -> anyhow::Result<()> {
+    let attempts = config().attempts.unwrap_or(DEFAULT_ATTEMPTS);
+    for pending in db.get_pending_notifications(RESUME_BATCH).await? {
+        if delivering().lock().unwrap().contains(&pending.id)
+            || !lease::acquire(db, &notification_lease(&pending.id)).await?
+        {
+            continue;
+        }
+        tracing::info!(
+            "[notify] resume {} to {} after {} attempts",
+            pending.proof_id,
+            pending.url,
+            pending.attempt
+        );
+        spawn_delivery(db, pending, attempts);
+    }
+    Ok(())
+}
+
+// The deliveries are finished before the process exits.
+fn spawn_delivery(db: &Database, pending: PendingNotification, attempts: u32) {
+    if !delivering().lock().unwrap().insert(pending.id.clone()) {
+        return;
+    }
+    let db = db.clone();
+    let guard = shutdown::track();
+    tokio::spawn(async move {
+        let _guard = guard;
+        deliver(&db, &pending, attempts).await;
+        delivering().lock().unwrap().remove(&pending.id);
+    });
+}
+
+/// Send `notification` to `callback_url` and to the urls of the config in the background.
+pub async fn notify(db: &Database, callback_url: &str, notification: &Notification) {
+    let mut urls = config().urls.clone().unwrap_or_default();
+    if !callback_url.is_empty() {
+        urls.push(callback_url.to_string());
+    }
+    if urls.is_empty() {
+        return;
+    }
+    let body = match serde_json::to_string(notification) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("[notify] {} encode: {:?}", notification.proof_id, e);
+            return;
+        }
+    };
+    let signature = config()
+        .secret
+        .as_ref()
+        .map(|secret| sign(secret, body.as_bytes()))
+        .unwrap_or_default();
+    let attempts = config().attempts.unwrap_or(DEFAULT_ATTEMPTS);
+    for url in urls {
+        let pending = PendingNotification {
+            id: uuid::Uuid::new_v4().to_string(),
+            proof_id: notification.proof_id.clone(),
+            url,
+            body: body.clone(),
+            signature: signature.clone(),
+            attempt: 0,
+            created_at: get_timestamp() as i64,
+        };
+        // not resumed after a crash if it can't be recorded, but still delivered
+        if let Err(e) = db.insert_pending_notification(&pending).await {
+            tracing::error!("[notify] save pending {} error: {:?}", pending.proof_id, e);
+        }
+        spawn_delivery(db, pending, attempts);
+    }
+}
+
+/// Try to deliver `pending` until it has been tried `attempts` times, with an exponential
+/// backoff, and return whether it was delivered. It is then no longer pending, unless another
+/// replica took its lease over meanwhile.
+pub async fn deliver(db: &Database, pending: &PendingNotification, attempts: u32) -> bool {
+    let lease_name = notification_lease(&pending.id);
+    let url = pending.url.as_str();
+    let signature = Some(pending.signature.as_str()).filter(|s| !s.is_empty());
+    let sink = sink(url);
+    let mut backoff = 1;
+    let mut delivered = false;
+    for attempt in pending.attempt + 1..=attempts.max(1) as i32 {
+        match lease::acquire(db, &lease_name).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!("[notify] {} to {} taken over", pending.proof_id, url);
+                return false;
+            }
+            Err(e) => tracing::warn!("[notify] renew {} failed: {:?}", lease_name, e),
+        }
+        let result = match &sink {
+            Some(sink) => sink.deliver(url, pending.body.as_bytes(), signature).await,
+            None => Err(anyhow::anyhow!("no sink for {}", url)),
+        };
+        let delivery = NotificationDelivery {
+            proof_id: pending.proof_id.clone(),
+            url: url.to_string(),
+            attempt,
+            delivered: result.is_ok(),
+            message: result
+                .as_ref()
+                .err()
+                .map(|e| e.to_string())
+                .unwrap_or_default(),
+            ts: get_timestamp() as i64,
+        };
+        if let Err(e) = db.insert_notification_delivery(&delivery).await {
+            tracing::error!("[notify] save delivery error: {:?}", e);
+        }
+        if delivery.delivered {
+            delivered = true;
+            break;
+        }
+        tracing::warn!(
+            "[notify] {} to {} attempt {} failed: {}",
+            pending.proof_id,
+            url,
+            attempt,
+            delivery.message
+        );
+        if sink.is_none() || attempt >= attempts as i32 {
+            break;
+        }
+        if let Err(e) = db
+            .update_pending_notification_attempt(&pending.id, attempt)
+            .await
+        {
+            tracing::error!("[notify] save attempt error: {:?}", e);
+        }
+        tokio::time::sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    if let Err(e) = db.delete_pending_notification(&pending.id).await {
+        tracing::error!("[notify] remove pending error: {:?}", e);
+    }
+    if let Err(e) = lease::release(db, &lease_name).await {
+        tracing::warn!("[notify] release {} failed: {:?}", lease_name, e);
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deliver() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        assert!(is_valid_callback("https://example.com/proofs"));
+        assert!(!is_valid_callback("file:///etc/passwd"));
+        assert!(!is_valid_callback(
+            "http://169.254.169.254/latest/meta-data"
+        ));
+        assert!(!is_valid_callback("http://[::1]:8080/"));
+        assert!(is_allowed_host("api.example.com", "*.example.com"));
+        assert!(!is_allowed_host("example.com", "*.example.com"));
+        assert!(!is_allowed_host("evilexample.com", "*.example.com"));
+        assert!(is_allowed_host("Example.com", "example.com"));
+
+        let dir = std::env::temp_dir().join(format!("notify-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(&format!("sqlite://{}/stage.db", dir.display())).unwrap();
+        db.migrate().await.unwrap();
+
+        lease::init(db.clone());
+        let pending = |id: &str, url: &str, body: &str| PendingNotification {
+            id: id.to_string(),
+            proof_id: "p1".to_string(),
+            url: url.to_string(),
+            body: body.to_string(),
+            ..Default::default()
+        };
+
+        let path = dir.join("notifications.jsonl");
+        let url = format!("file://{}", path.display());
+        assert!(deliver(&db, &pending("n1", &url, "{}"), 3).await);
+        assert!(deliver(&db, &pending("n2", &url, "[]"), 3).await);
+        assert_eq!(std::fs::read(&path).unwrap(), b"{}\n[]\n");
+
+        let socket = format!("unix://{}/missing.sock", dir.display());
+        assert!(!deliver(&db, &pending("n3", &socket, "{}"), 1).await);
+        assert!(!deliver(&db, &pending("n4", "ftp://example.com", "{}"), 3).await);
+
+        // a delivery interrupted by a crash is resumed
+        db.insert_pending_notification(&pending("n5", &url, "{}"))
+            .await
+            .unwrap();
+        resume(&db).await.unwrap();
+        while !db.get_pending_notifications(10).await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"{}\n[]\n{}\n");
+
+        let deliveries = db.get_notification_deliveries("p1").await.unwrap();
+        assert_eq!(
+            deliveries
+                .iter()
+                .map(|d| (d.attempt, d.delivered))
+                .collect::<Vec<_>>(),
+            [(1, true), (1, true), (1, false), (1, false), (1, true)]
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,20 +1,29 @@
 use crate::proto::prover_service::v1::{
-    prover_service_client::ProverServiceClient, AggregateRequest, GetTaskResultRequest,
-    GetTaskResultResponse, ProveRequest, ResultCode, SnarkProofRequest, SplitElfRequest,
+    prover_service_client::ProverServiceClient, AggregateRequest, FetchSegmentRequest,
+    FetchSegmentResponse, GetTaskResultRequest, GetTaskResultResponse, ProveRequest,
+    ReleaseSegmentsRequest, ResultCode, SnarkProofRequest, SplitElfRequest,
 };
+use crate::stage::safe_read;
+use common::file;
 use common::tls::Config as TlsConfig;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
 
 use crate::stage::tasks::{
     AggTask, ProveTask, SnarkTask, SplitTask, TASK_STATE_FAILED, TASK_STATE_PROCESSING,
     TASK_STATE_SUCCESS, TASK_STATE_UNPROCESSED, TASK_TIMEOUT,
 };
-use tonic::Request;
+use tonic::{Request, Streaming};
 
+use crate::lease::{self, NodeLease};
+use crate::proto::includes::v1::Step;
 use crate::prover_node::{NodeStatus, ProverNode};
+use crate::{metrics, telemetry};
 use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 use rand::SeedableRng;
+use std::future::Future;
 use std::time::Duration;
 use tonic::transport::Channel;
 
@@ -46,10 +55,20 @@ fn get_nodes(task_type: TaskType) -> Vec<ProverNode> {
 async fn get_idle_client(
     tls_config: Option<TlsConfig>,
     task_type: TaskType,
-) -> Option<(String, ProverServiceClient<Channel>, Arc<Mutex<NodeStatus>>)> {
+    program_id: Option<&str>,
+) -> Option<(
+    String,
+    ProverServiceClient<Channel>,
+    Arc<Mutex<NodeStatus>>,
+    NodeLease,
+)> {
     let mut nodes = get_nodes(task_type);
     let mut rng = StdRng::from_entropy();
     nodes.shuffle(&mut rng);
+    // Prefer the nodes which already hold the proving keys of the program.
+    if let Some(program_id) = program_id {
+        nodes.sort_by_key(|node| !node.is_warm(program_id));
+    }
 
     for mut node in nodes {
         {
@@ -59,9 +78,14 @@ async fn get_idle_client(
             }
             *status = NodeStatus::Busy;
         }
+        let Some(node_lease) = lease::reserve_node(&node.addr).await else {
+            // reserved by another stage replica
+            *node.status.lock().unwrap() = NodeStatus::Idle;
+            continue;
+        };
 
         if let Some(client) = node.is_active(tls_config.clone()).await {
-            return Some((node.addr.clone(), client, node.status.clone()));
+            return Some((node.addr.clone(), client, node.status.clone(), node_lease));
         } else {
             tracing::warn!(
                 "Node {} is unreachable, marked Busy to avoid reuse",
@@ -73,35 +97,243 @@ async fn get_idle_client(
     None
 }
 
+// The response of the rpc of a task, None if another stage replica took the node meanwhile.
+async fn on_node<T>(node_lease: &mut NodeLease, rpc: impl Future<Output = T>) -> Option<T> {
+    tokio::select! {
+        response = rpc => Some(response),
+        _ = node_lease.lost() => None,
+    }
+}
+
+// The task is handed back for rescheduling, the node is left to the replica which took it.
+async fn hand_back(step: Step, addrs: &str, node_status: &Mutex<NodeStatus>) {
+    tracing::warn!("[{:?}] lost the lease of {}, task rescheduled", step, addrs);
+    *node_status.lock().unwrap() = NodeStatus::Idle;
+    metrics::count_failure(step, "lease");
+    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+}
+
+fn mark_warm(addr: &str, program_id: &str) {
+    crate::prover_node::instance()
+        .lock()
+        .unwrap()
+        .mark_warm(addr, program_id);
+}
+
 pub fn result_code_to_state(code: i32) -> u32 {
     match ResultCode::from_i32(code) {
         Some(ResultCode::Unspecified) => TASK_STATE_PROCESSING,
         Some(ResultCode::Ok) => TASK_STATE_SUCCESS,
         Some(ResultCode::InternalError) => TASK_STATE_FAILED,
         Some(ResultCode::Busy) => TASK_STATE_UNPROCESSED,
+        // rescheduled, a fetched input is fetched again
+        Some(ResultCode::IntegrityError) => TASK_STATE_UNPROCESSED,
         _ => TASK_STATE_FAILED,
     }
 }
 
+// Count a failed task by the class of its result code, or as "rpc" without a response.
+fn count_failure(step: Step, code: Option<i32>) {
+    let class = match code.map(ResultCode::from_i32) {
+        None => "rpc",
+        Some(Some(ResultCode::Ok | ResultCode::Unspecified)) => return,
+        Some(Some(ResultCode::InternalError)) => "internal",
+        Some(Some(ResultCode::Busy)) => "busy",
+        Some(Some(ResultCode::IntegrityError)) => "integrity",
+        Some(Some(ResultCode::InvalidParameter)) => "invalid_parameter",
+        Some(None) => "unknown",
+    };
+    metrics::count_failure(step, class);
+}
+
+pub(crate) fn split_request(split_task: &SplitTask) -> SplitElfRequest {
+    let mut request = SplitElfRequest {
+        proof_id: split_task.proof_id.clone(),
+        computed_request_id: split_task.task_id.clone(),
+        elf_path: split_task.elf_path.clone(),
+        base_dir: split_task.base_dir.clone(),
+        seg_path: split_task.seg_path.clone(),
+        public_input_path: split_task.public_input_path.clone(),
+        private_input_path: split_task.private_input_path.clone(),
+        output_path: split_task.output_path.clone(),
+        args: split_task.args.clone(),
+        block_no: split_task.block_no,
+        seg_size: split_task.seg_size,
+        receipt_inputs_path: split_task.recepit_inputs_path.clone(),
+        program_id: split_task.program_id.clone(),
+        ..Default::default()
+    };
+    if split_task.segment_transfer {
+        request.elf_data = safe_read(&split_task.elf_path);
+        request.public_input = safe_read(&split_task.public_input_path);
+        request.private_input = safe_read(&split_task.private_input_path);
+        request.receipt_inputs = safe_read(&split_task.recepit_inputs_path);
+    }
+    request
+}
+
+pub(crate) fn prove_request(prove_task: &ProveTask) -> ProveRequest {
+    ProveRequest {
+        proof_id: prove_task.program.proof_id.clone(),
+        computed_request_id: prove_task.task_id.clone(),
+        program_id: prove_task.program_id.clone(),
+        segment: prove_task.segment.clone(),
+        block_no: prove_task.program.block_no,
+        seg_size: prove_task.program.seg_size,
+        receipts_input: prove_task.program.receipts.clone(),
+        index: prove_task.file_no as u32,
+        segment_addr: prove_task.segment_addr.clone(),
+    }
+}
+
+pub(crate) fn aggregate_request(agg_task: &AggTask) -> AggregateRequest {
+    AggregateRequest {
+        proof_id: agg_task.proof_id.clone(),
+        computed_request_id: agg_task.task_id.clone(),
+        block_no: agg_task.block_no,
+        seg_size: agg_task.seg_size,
+        vk: agg_task.vk.clone(),
+        inputs: agg_task.inputs.clone(),
+        is_final: agg_task.is_final,
+        is_first_shard: agg_task.is_first_shard,
+        is_leaf_layer: agg_task.is_leaf_layer,
+        is_deferred: agg_task.is_deferred,
+    }
+}
+
+pub(crate) fn snark_request(snark_task: &SnarkTask) -> SnarkProofRequest {
+    SnarkProofRequest {
+        version: snark_task.version,
+        proof_id: snark_task.proof_id.clone(),
+        computed_request_id: snark_task.task_id.clone(),
+        agg_receipt: snark_task.agg_receipt.clone(),
+    }
+}
+
+/// Copy `path` from the node which wrote it to the same path on the local storage. The digest of
+/// the received bytes is recorded, not the one of the node, which may have encrypted its copy.
+pub async fn fetch_file(
+    client: &mut ProverServiceClient<Channel>,
+    proof_id: &str,
+    path: &str,
+) -> anyhow::Result<()> {
+    if file::is_encrypted(path).await? {
+        // received in the clear, encrypted and digested again under the local data key
+        let mut stream = remote_file(client, proof_id, path).await?;
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.message().await? {
+            buf.extend_from_slice(&chunk.data);
+        }
+        return file::new_async(path).write(&buf).await;
+    }
+    copy_remote_file(client, proof_id, path).await
+}
+
+/// The digest recorded for `path` on the node which wrote it, None if it has none.
+pub async fn remote_digest(
+    client: &mut ProverServiceClient<Channel>,
+    proof_id: &str,
+    path: &str,
+) -> anyhow::Result<Option<String>> {
+    let mut buf = Vec::new();
+    let result = async {
+        let mut stream = remote_file(client, proof_id, &file::digest_path(path)).await?;
+        while let Some(chunk) = stream.message().await? {
+            buf.extend_from_slice(&chunk.data);
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    match result {
+        Err(e) if is_not_found(&e) => return Ok(None),
+        result => result?,
+    }
+    let digest = String::from_utf8(buf)?.trim().to_string();
+    Ok((!digest.is_empty()).then_some(digest))
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<tonic::Status>()
+        .is_some_and(|s| s.code() == tonic::Code::NotFound)
+}
+
+/// Whether the node serving a file could not be reached, the fetch can then be retried later.
+pub fn is_unavailable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<tonic::Status>()
+        .is_some_and(|s| s.code() == tonic::Code::Unavailable)
+}
+
+/// Connect to the node at `addr`, an unavailable status if it is unreachable.
+pub async fn connect(
+    addr: &str,
+    tls_config: Option<TlsConfig>,
+) -> anyhow::Result<ProverServiceClient<Channel>> {
+    ProverNode::new(&addr.to_string())
+        .is_active(tls_config)
+        .await
+        .ok_or_else(|| tonic::Status::unavailable(format!("node {} is unreachable", addr)).into())
+}
+
+/// Remove the segments of `proof_id` from the node which split it, once they are proved.
+pub async fn release_segments(
+    addr: &str,
+    proof_id: &str,
+    seg_path: &str,
+    tls_config: Option<TlsConfig>,
+) -> anyhow::Result<()> {
+    let mut client = connect(addr, tls_config).await?;
+    let request = ReleaseSegmentsRequest {
+        proof_id: proof_id.to_owned(),
+        seg_path: seg_path.to_owned(),
+    };
+    let mut grpc_request = Request::new(request);
+    grpc_request.set_timeout(Duration::from_secs(30));
+    let response = client.release_segments(grpc_request).await?.into_inner();
+    match response.result {
+        Some(result) if result.code != ResultCode::Ok as i32 => {
+            anyhow::bail!("release segments of {}: {}", proof_id, result.message)
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn remote_file(
+    client: &mut ProverServiceClient<Channel>,
+    proof_id: &str,
+    path: &str,
+) -> anyhow::Result<Streaming<FetchSegmentResponse>> {
+    let request = FetchSegmentRequest {
+        proof_id: proof_id.to_owned(),
+        path: path.to_owned(),
+    };
+    let mut grpc_request = Request::new(request);
+    grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
+    Ok(client.fetch_segment(grpc_request).await?.into_inner())
+}
+
+async fn copy_remote_file(
+    client: &mut ProverServiceClient<Channel>,
+    proof_id: &str,
+    path: &str,
+) -> anyhow::Result<()> {
+    let mut stream = remote_file(client, proof_id, path).await?;
+    if let Some(parent) = Path::new(path).parent().and_then(|p| p.to_str()) {
+        file::new_async(parent).create_dir_all().await?;
+    }
+    let mut writer = file::new_async(path).writer().await?;
+    while let Some(chunk) = stream.message().await? {
+        writer.write_all(&chunk.data).await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "split", skip_all, fields(proof_id = %split_task.proof_id, task_id = %split_task.task_id))]
 pub async fn split(mut split_task: SplitTask, tls_config: Option<TlsConfig>) -> Option<SplitTask> {
     split_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config, TaskType::Split).await;
-    if let Some((addrs, mut client, node_status)) = client {
-        let request = SplitElfRequest {
-            proof_id: split_task.proof_id.clone(),
-            computed_request_id: split_task.task_id.clone(),
-            elf_path: split_task.elf_path.clone(),
-            base_dir: split_task.base_dir.clone(),
-            seg_path: split_task.seg_path.clone(),
-            public_input_path: split_task.public_input_path.clone(),
-            private_input_path: split_task.private_input_path.clone(),
-            output_path: split_task.output_path.clone(),
-            args: split_task.args.clone(),
-            block_no: split_task.block_no,
-            seg_size: split_task.seg_size,
-            receipt_inputs_path: split_task.recepit_inputs_path.clone(),
-            program_id: split_task.program_id.clone(),
-        };
+    let client = get_idle_client(tls_config, TaskType::Split, Some(&split_task.program_id)).await;
+    if let Some((addrs, mut client, node_status, mut node_lease)) = client {
+        let request = split_request(&split_task);
         tracing::info!(
             "[split] rpc {} {}:{} start",
             addrs,
@@ -110,16 +342,49 @@ pub async fn split(mut split_task: SplitTask, tls_config: Option<TlsConfig>) ->
         );
         let mut grpc_request = Request::new(request);
         grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.split_elf(grpc_request).await;
-        let mut status = node_status.lock().unwrap();
-        *status = NodeStatus::Idle;
+        telemetry::inject(&mut grpc_request);
+        let Some(response) = on_node(&mut node_lease, client.split_elf(grpc_request)).await else {
+            hand_back(Step::Split, &addrs, &node_status).await;
+            return Some(split_task);
+        };
+        *node_status.lock().unwrap() = NodeStatus::Idle;
+        if response.is_err() {
+            count_failure(Step::Split, None);
+        }
         if let Ok(response) = response {
             if let Some(response_result) = response.get_ref().result.as_ref() {
+                count_failure(Step::Split, Some(response_result.code));
                 split_task.state = result_code_to_state(response_result.code);
+                split_task
+                    .trace
+                    .message
+                    .clone_from(&response_result.message);
+                if split_task.state == TASK_STATE_SUCCESS {
+                    mark_warm(&addrs, &split_task.program_id);
+                }
                 // FIXME: node_info usage?
                 split_task.trace.node_info = addrs.clone();
                 split_task.total_steps = response.get_ref().total_steps;
                 split_task.total_segments = response.get_ref().total_segments;
+                split_task
+                    .execution_report
+                    .clone_from(&response.get_ref().execution_report);
+                if split_task.state == TASK_STATE_SUCCESS && split_task.segment_transfer {
+                    for path in response.get_ref().outputs.iter() {
+                        if let Err(e) = fetch_file(&mut client, &split_task.proof_id, path).await {
+                            tracing::error!(
+                                "[split] fetch {} from {} failed: {:?}",
+                                path,
+                                addrs,
+                                e
+                            );
+                            split_task.state = TASK_STATE_FAILED;
+                            split_task.trace.message = format!("fetch {}: {}", path, e);
+                            metrics::count_failure(Step::Split, "fetch");
+                            break;
+                        }
+                    }
+                }
                 tracing::info!(
                     "[split] rpc {} {}:{} code:{:?} message:{:?} end. Total cycles {}, segments {}",
                     addrs,
@@ -138,20 +403,12 @@ pub async fn split(mut split_task: SplitTask, tls_config: Option<TlsConfig>) ->
     Some(split_task)
 }
 
+#[tracing::instrument(name = "prove", skip_all, fields(proof_id = %prove_task.program.proof_id, task_id = %prove_task.task_id))]
 pub async fn prove(mut prove_task: ProveTask, tls_config: Option<TlsConfig>) -> Option<ProveTask> {
     prove_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config, TaskType::Prove).await;
-    if let Some((addrs, mut client, node_status)) = client {
-        let request = ProveRequest {
-            proof_id: prove_task.program.proof_id.clone(),
-            computed_request_id: prove_task.task_id.clone(),
-            program_id: prove_task.program_id.clone(),
-            segment: prove_task.segment.clone(),
-            block_no: prove_task.program.block_no,
-            seg_size: prove_task.program.seg_size,
-            receipts_input: prove_task.program.receipts.clone(),
-            index: prove_task.file_no as u32,
-        };
+    let client = get_idle_client(tls_config, TaskType::Prove, Some(&prove_task.program_id)).await;
+    if let Some((addrs, mut client, node_status, mut node_lease)) = client {
+        let request = prove_request(&prove_task);
         tracing::info!(
             "[prove] rpc {} {}:{}:{} start",
             addrs,
@@ -161,13 +418,28 @@ pub async fn prove(mut prove_task: ProveTask, tls_config: Option<TlsConfig>) ->
         );
         let mut grpc_request = Request::new(request);
         grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.prove(grpc_request).await;
+        telemetry::inject(&mut grpc_request);
+        let Some(response) = on_node(&mut node_lease, client.prove(grpc_request)).await else {
+            hand_back(Step::Prove, &addrs, &node_status).await;
+            return Some(prove_task);
+        };
+        if response.is_err() {
+            count_failure(Step::Prove, None);
+        }
         if let Ok(response) = response {
             //  If the server does not respond, keep the previous busy status to prevent reuse.
             let mut status = node_status.lock().unwrap();
             *status = NodeStatus::Idle;
             if let Some(response_result) = response.get_ref().result.as_ref() {
+                count_failure(Step::Prove, Some(response_result.code));
                 prove_task.state = result_code_to_state(response_result.code);
+                prove_task
+                    .trace
+                    .message
+                    .clone_from(&response_result.message);
+                if prove_task.state == TASK_STATE_SUCCESS {
+                    mark_warm(&addrs, &prove_task.program_id);
+                }
                 prove_task.trace.node_info = addrs.clone();
                 tracing::info!(
                     "[prove] rpc {} {}:{}:{} code:{:?} message:{:?} end",
@@ -187,22 +459,12 @@ pub async fn prove(mut prove_task: ProveTask, tls_config: Option<TlsConfig>) ->
     Some(prove_task)
 }
 
+#[tracing::instrument(name = "aggregate", skip_all, fields(proof_id = %agg_task.proof_id, task_id = %agg_task.task_id))]
 pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) -> Option<AggTask> {
     agg_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config, TaskType::Agg).await;
-    if let Some((addrs, mut client, node_status)) = client {
-        let request = AggregateRequest {
-            proof_id: agg_task.proof_id.clone(),
-            computed_request_id: agg_task.task_id.clone(),
-            block_no: agg_task.block_no,
-            seg_size: agg_task.seg_size,
-            vk: agg_task.vk.clone(),
-            inputs: agg_task.inputs.clone(),
-            is_final: agg_task.is_final,
-            is_first_shard: agg_task.is_first_shard,
-            is_leaf_layer: agg_task.is_leaf_layer,
-            is_deferred: agg_task.is_deferred,
-        };
+    let client = get_idle_client(tls_config, TaskType::Agg, None).await;
+    if let Some((addrs, mut client, node_status, mut node_lease)) = client {
+        let request = aggregate_request(&agg_task);
         tracing::info!(
             "[aggregate] rpc {} {}:{}:{} {} inputs start",
             addrs,
@@ -213,12 +475,21 @@ pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) ->
         );
         let mut grpc_request = Request::new(request);
         grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.aggregate(grpc_request).await;
+        telemetry::inject(&mut grpc_request);
+        let Some(response) = on_node(&mut node_lease, client.aggregate(grpc_request)).await else {
+            hand_back(Step::Agg, &addrs, &node_status).await;
+            return Some(agg_task);
+        };
         let mut status = node_status.lock().unwrap();
         *status = NodeStatus::Idle;
+        if response.is_err() {
+            count_failure(Step::Agg, None);
+        }
         if let Ok(response) = response {
             if let Some(response_result) = response.get_ref().result.as_ref() {
+                count_failure(Step::Agg, Some(response_result.code));
                 agg_task.state = result_code_to_state(response_result.code);
+                agg_task.trace.message.clone_from(&response_result.message);
                 agg_task.trace.node_info = addrs.clone();
                 tracing::info!(
                     "[aggregate] rpc {} {}:{}:{} code:{:?} message:{:?} end",
@@ -237,18 +508,14 @@ pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) ->
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
     Some(agg_task)
 }
+#[tracing::instrument(name = "snark_proof", skip_all, fields(proof_id = %snark_task.proof_id, task_id = %snark_task.task_id))]
 pub async fn snark_proof(
     mut snark_task: SnarkTask,
     tls_config: Option<TlsConfig>,
 ) -> Option<SnarkTask> {
-    let client = get_idle_client(tls_config, TaskType::Snark).await;
-    if let Some((addrs, mut client, node_status)) = client {
-        let request = SnarkProofRequest {
-            version: snark_task.version,
-            proof_id: snark_task.proof_id.clone(),
-            computed_request_id: snark_task.task_id.clone(),
-            agg_receipt: snark_task.agg_receipt.clone(),
-        };
+    let client = get_idle_client(tls_config, TaskType::Snark, None).await;
+    if let Some((addrs, mut client, node_status, mut node_lease)) = client {
+        let request = snark_request(&snark_task);
         tracing::info!(
             "[snark_proof] rpc {} {}:{} start",
             addrs,
@@ -257,11 +524,24 @@ pub async fn snark_proof(
         );
         let mut grpc_request = Request::new(request);
         grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.snark_proof(grpc_request).await;
-        let mut status = node_status.lock().unwrap();
-        *status = NodeStatus::Idle;
+        telemetry::inject(&mut grpc_request);
+        let Some(response) = on_node(&mut node_lease, client.snark_proof(grpc_request)).await
+        else {
+            snark_task.state = TASK_STATE_UNPROCESSED;
+            hand_back(Step::Snark, &addrs, &node_status).await;
+            return Some(snark_task);
+        };
+        *node_status.lock().unwrap() = NodeStatus::Idle;
+        if response.is_err() {
+            count_failure(Step::Snark, None);
+        }
         if let Ok(response) = response {
             if let Some(response_result) = response.get_ref().result.as_ref() {
+                count_failure(Step::Snark, Some(response_result.code));
+                snark_task
+                    .trace
+                    .message
+                    .clone_from(&response_result.message);
                 if ResultCode::from_i32(response_result.code) == Some(ResultCode::Ok) {
                     tracing::info!(
                         "[snark_proof] rpc {} {}:{}  code:{:?} message:{:?}",
@@ -276,6 +556,12 @@ pub async fn snark_proof(
                     snark_task.output = response.get_ref().snark_proof_with_public_inputs.clone();
                     return Some(snark_task);
                 }
+                // The node is busy or draining, or the input is corrupted, reschedule it.
+                if result_code_to_state(response_result.code) == TASK_STATE_UNPROCESSED {
+                    snark_task.state = TASK_STATE_UNPROCESSED;
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    return Some(snark_task);
+                }
             }
         }
         snark_task.state = TASK_STATE_FAILED;
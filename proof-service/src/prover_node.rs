@@ -2,6 +2,7 @@ use crate::proto::prover_service::v1::prover_service_client::ProverServiceClient
 use crate::stage::tasks::TASK_TIMEOUT;
 use common::tls::Config as TlsConfig;
 use once_cell::sync::OnceCell;
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tonic::transport::Channel;
@@ -20,6 +21,8 @@ pub struct ProverNode {
     pub addr: String,
     pub client: Arc<Mutex<Option<tonic::transport::channel::Channel>>>,
     pub status: Arc<Mutex<NodeStatus>>,
+    // programs whose proving keys are likely cached on the node
+    pub warm_programs: Arc<Mutex<HashSet<String>>>,
 }
 
 impl ProverNode {
@@ -28,9 +31,14 @@ impl ProverNode {
             addr: addr.to_string(),
             client: Arc::new(Mutex::new(None)),
             status: Arc::new(Mutex::new(NodeStatus::Idle)),
+            warm_programs: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
+    pub fn is_warm(&self, program_id: &str) -> bool {
+        self.warm_programs.lock().unwrap().contains(program_id)
+    }
+
     pub fn get_client(&self) -> Option<tonic::transport::channel::Channel> {
         self.client.lock().unwrap().clone()
     }
@@ -127,6 +135,18 @@ impl ProverNodes {
         self.prover_nodes.clone()
     }
 
+    pub fn mark_warm(&self, addr: &str, program_id: &str) {
+        if program_id.is_empty() {
+            return;
+        }
+        for node in self.prover_nodes.iter().filter(|node| node.addr == addr) {
+            node.warm_programs
+                .lock()
+                .unwrap()
+                .insert(program_id.to_string());
+        }
+    }
+
     pub fn add_snark_node(&mut self, node: ProverNode) {
         self.snark_nodes.push(node);
     }
@@ -1,15 +1,24 @@
+use std::collections::HashMap;
+use std::path::{Component, Path};
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
+use tracing::Instrument;
 
-use crate::proto::includes::v1::ProverVersion;
+use crate::proto::includes::v1::{ExecutionReport, ProverVersion};
 use crate::proto::prover_service::v1::{
     get_status_response, prover_service_server::ProverService, AggregateRequest, AggregateResponse,
-    GetStatusRequest, GetStatusResponse, GetTaskResultRequest, GetTaskResultResponse, ProveRequest,
-    ProveResponse, Result, ResultCode, SnarkProofRequest, SnarkProofResponse, SplitElfRequest,
-    SplitElfResponse,
+    DrainRequest, DrainResponse, FetchSegmentRequest, FetchSegmentResponse, GetStatusRequest,
+    GetStatusResponse, GetTaskResultRequest, GetTaskResultResponse, ProveRequest, ProveResponse,
+    ReleaseSegmentsRequest, ReleaseSegmentsResponse, Result, ResultCode, SnarkProofRequest,
+    SnarkProofResponse, SplitElfRequest, SplitElfResponse, WarmProgramRequest, WarmProgramResponse,
 };
-use crate::{config, metrics};
+use crate::stage::tasks::TASK_TIMEOUT;
+use crate::{config, metrics, prover_client, shutdown, telemetry};
+use common::file;
+use common::tls::Config as TlsConfig;
 #[cfg(feature = "prover")]
 use prover::{
     contexts::{AggContext, ProveContext, SnarkContext},
@@ -30,8 +39,11 @@ async fn run_back_task<
 ) -> std::result::Result<T, String> {
     let rt = tokio::runtime::Handle::current();
     let (tx, rx) = tokio::sync::oneshot::channel();
+    // the spans of the prover are children of the rpc span
+    let span = tracing::Span::current();
     let _ = rt
         .spawn_blocking(move || {
+            let _enter = span.enter();
             let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(callable));
             let _ = tx.send(result);
         })
@@ -51,16 +63,22 @@ async fn run_back_task<
     })
 }
 
+const FETCH_CHUNK_SIZE: usize = 1 << 20;
+
 #[derive(Default)]
 pub struct ProverServiceSVC {
     pub config: config::RuntimeConfig,
+    // client tls config of the FetchSegment calls to the other nodes
+    peer_tls_config: tokio::sync::OnceCell<Option<TlsConfig>>,
+    // the proofs whose segments are served to the other nodes until ReleaseSegments
+    served: Mutex<HashMap<String, tokio::sync::oneshot::Sender<()>>>,
     #[cfg(feature = "prover")]
     pipeline: Arc<Mutex<Pipeline>>,
     #[cfg(feature = "prover_v2")]
     pipeline: Arc<Mutex<Pipeline>>,
 }
 impl ProverServiceSVC {
-    pub fn new(config: config::RuntimeConfig) -> Self {
+    pub fn new(config: config::RuntimeConfig) -> anyhow::Result<Self> {
         let version = if cfg!(feature = "prover") {
             ProverVersion::Zkm
         } else if cfg!(feature = "prover_v2") {
@@ -68,14 +86,229 @@ impl ProverServiceSVC {
         } else {
             panic!("Not supported prover version");
         };
+        #[cfg(feature = "prover_v2")]
+        prover_v2::init_key_cache(
+            config
+                .key_cache_size
+                .unwrap_or(prover_v2::DEFAULT_CACHE_SIZE),
+            config.key_cache_dir.as_deref(),
+        )?;
         let pipeline = Arc::new(Mutex::new(Pipeline::new(
             &config.base_dir,
             &config.get_proving_key_path(version.into()),
         )));
-        Self { config, pipeline }
+        if config.snark_warm_up.unwrap_or(false) {
+            let pipeline = pipeline.clone();
+            tokio::task::spawn_blocking(move || {
+                let start = Instant::now();
+                match pipeline.lock().unwrap().warm_up_snark() {
+                    Ok(done) => tracing::info!(
+                        "snark warm up done: {} elapsed: {:?}",
+                        done,
+                        start.elapsed()
+                    ),
+                    Err(e) => tracing::error!("snark warm up failed: {}", e),
+                }
+            });
+        }
+        Ok(Self {
+            config,
+            pipeline,
+            peer_tls_config: Default::default(),
+            served: Default::default(),
+        })
+    }
+
+    // Keep a draining node up while the other nodes may fetch the segments of `proof_id`, until
+    // the stage releases them or for a task timeout if it never does.
+    fn hold_segments(&self, proof_id: &str) {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let guard = shutdown::track();
+        // a split retried here replaces the earlier hold
+        self.served.lock().unwrap().insert(proof_id.to_string(), tx);
+        tokio::spawn(async move {
+            let _guard = guard;
+            let _ = tokio::time::timeout(Duration::from_secs(TASK_TIMEOUT), rx).await;
+        });
+    }
+
+    async fn peer_tls_config(&self) -> Option<TlsConfig> {
+        self.peer_tls_config
+            .get_or_init(|| async {
+                let ca_cert_path = self.config.ca_cert_path.as_ref()?;
+                TlsConfig::new(
+                    ca_cert_path,
+                    self.config.cert_path.as_deref().unwrap_or_default(),
+                    self.config.key_path.as_deref().unwrap_or_default(),
+                )
+                .await
+                .map_err(|e| tracing::error!("load tls config failed: {:?}", e))
+                .ok()
+            })
+            .await
+            .clone()
+    }
+
+    // Copy the segment from the split node, unless the local copy has the digest recorded by
+    // the split node. A copy corrupted since is caught when it is read, and fetched again.
+    async fn fetch_segment_from(
+        &self,
+        addr: &str,
+        proof_id: &str,
+        path: &str,
+    ) -> anyhow::Result<()> {
+        if addr == self.config.addr {
+            return Ok(());
+        }
+        let mut client = prover_client::connect(addr, self.peer_tls_config().await).await?;
+        if let Some(local) = file::recorded_digest(path).await? {
+            if file::new_async(path).exists().await?
+                && prover_client::remote_digest(&mut client, proof_id, path).await? == Some(local)
+            {
+                return Ok(());
+            }
+        }
+        prover_client::fetch_file(&mut client, proof_id, path).await
     }
 }
 
+// Only the outputs of the split are served, under the directory of the proof of the request, so
+// that a peer can not read the inputs, the data keys, the artifacts nor the other proofs.
+fn is_served(base_dir: &str, proof_id: &str, path: &str) -> bool {
+    if let Some(digested) = file::digested_path(path) {
+        // the digest of a served file
+        return file::digested_path(digested).is_none() && is_served(base_dir, proof_id, digested);
+    }
+    if base_dir.is_empty() || proof_id.is_empty() || proof_id.contains('/') {
+        return false;
+    }
+    let proof_dir = Path::new(base_dir).join("proof").join(proof_id);
+    let Ok(relative) = Path::new(path).strip_prefix(&proof_dir) else {
+        return false;
+    };
+    let mut names = vec![];
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => match name.to_str() {
+                Some(name) if !name.starts_with('.') && !file::is_hidden(name) => names.push(name),
+                _ => return false,
+            },
+            _ => return false,
+        }
+    }
+    matches!(
+        names.as_slice(),
+        ["vk.bin"] | ["execution_report.json"] | ["segment" | "wrap" | "output_stream", _]
+    )
+}
+
+// The directory of the segments of a proof, `{base_dir}/proof/{proof_id}/segment`.
+fn is_segment_dir(base_dir: &str, proof_id: &str, path: &str) -> bool {
+    if base_dir.is_empty() || proof_id.is_empty() || proof_id.contains('/') {
+        return false;
+    }
+    Path::new(path)
+        == Path::new(base_dir)
+            .join("proof")
+            .join(proof_id)
+            .join("segment")
+}
+
+// The inputs sent inline by a stage without shared storage.
+async fn write_inline_inputs(request: &SplitElfRequest) -> anyhow::Result<()> {
+    if request.elf_data.is_empty() {
+        return Ok(());
+    }
+    file::new_async(&request.seg_path).create_dir_all().await?;
+    file::new_async(&format!("{}/wrap", request.base_dir))
+        .create_dir_all()
+        .await?;
+    // the nodes encrypt the private inputs and the output under their own data keys
+    let mut private_dirs = vec![];
+    for path in [
+        &request.private_input_path,
+        &request.receipt_inputs_path,
+        &request.output_path,
+    ] {
+        if let Some(parent) = Path::new(path).parent().and_then(|p| p.to_str()) {
+            file::new_async(parent).create_dir_all().await?;
+            private_dirs.push(parent);
+        }
+    }
+    file::enable_encryption(&private_dirs).await?;
+    for (path, data) in [
+        (&request.elf_path, &request.elf_data),
+        (&request.public_input_path, &request.public_input),
+        (&request.private_input_path, &request.private_input),
+        (&request.receipt_inputs_path, &request.receipt_inputs),
+    ] {
+        if path.is_empty() || data.is_empty() {
+            continue;
+        }
+        if let Some(parent) = Path::new(path).parent().and_then(|p| p.to_str()) {
+            file::new_async(parent).create_dir_all().await?;
+        }
+        file::new_async(path).write(data).await?;
+    }
+    Ok(())
+}
+
+// Read by the split, the node only keeps its outputs for the stage and the prove nodes.
+async fn remove_inline_inputs(request: &SplitElfRequest) {
+    for path in [
+        &request.public_input_path,
+        &request.private_input_path,
+        &request.receipt_inputs_path,
+    ] {
+        if path.is_empty() {
+            continue;
+        }
+        if let Err(e) = file::new_async(path).remove_all().await {
+            tracing::warn!("remove {} failed: {:?}", path, e);
+        }
+    }
+}
+
+// The files written by the split which the stage needs, besides the segments.
+fn execution_report_path(base_dir: &str) -> String {
+    format!("{}/execution_report.json", base_dir)
+}
+
+// Written by the split next to the program vk, the provers without a report leave it empty.
+async fn read_execution_report(base_dir: &str) -> Option<ExecutionReport> {
+    let data = file::new_async(&execution_report_path(base_dir))
+        .read()
+        .await
+        .ok()?;
+    serde_json::from_slice(&data)
+        .map_err(|e| tracing::warn!("invalid execution report under {}: {:?}", base_dir, e))
+        .ok()
+}
+
+async fn split_outputs(request: &SplitElfRequest) -> Vec<String> {
+    let mut outputs = vec![
+        format!("{}/vk.bin", request.base_dir),
+        format!("{}/wrap/public_values.bin", request.base_dir),
+        execution_report_path(&request.base_dir),
+        request.output_path.clone(),
+    ];
+    if let Ok(files) = file::new_async(&request.seg_path).read_dir().await {
+        outputs.extend(
+            files
+                .into_iter()
+                .filter(|name| name.starts_with("deferred_proof_"))
+                .map(|name| format!("{}/{}", request.seg_path, name)),
+        );
+    }
+    let mut existing = Vec::with_capacity(outputs.len());
+    for path in outputs {
+        if !path.is_empty() && file::new_async(&path).exists().await.unwrap_or(false) {
+            existing.push(path);
+        }
+    }
+    existing
+}
+
 macro_rules! on_done {
     ($result:ident, $resp:ident) => {
         match $result {
@@ -93,8 +326,13 @@ macro_rules! on_done {
                 }
             }
             Err(e) => {
+                let code = if e.contains(file::INTEGRITY_ERROR) {
+                    ResultCode::IntegrityError
+                } else {
+                    ResultCode::InternalError
+                };
                 $resp.result = Some(Result {
-                    code: (ResultCode::InternalError.into()),
+                    code: code.into(),
                     message: (e.to_string()),
                 });
             }
@@ -102,6 +340,41 @@ macro_rules! on_done {
     };
 }
 
+// A span of the task in the trace of the stage which sent it.
+macro_rules! rpc_span {
+    ($name:literal, $request:ident) => {{
+        let span = tracing::info_span!(
+            $name,
+            proof_id = %$request.get_ref().proof_id,
+            task_id = %$request.get_ref().computed_request_id,
+        );
+        telemetry::set_parent(&span, &$request);
+        span
+    }};
+}
+
+// A draining node hands the task back as BUSY, so the stage reschedules it on another node.
+macro_rules! reject_if_draining {
+    ($request:ident, $resp:ident) => {
+        if shutdown::is_draining() {
+            tracing::warn!(
+                "{}:{} handed back, node is draining",
+                $request.get_ref().proof_id,
+                $request.get_ref().computed_request_id,
+            );
+            return Ok(Response::new($resp {
+                proof_id: $request.get_ref().proof_id.clone(),
+                computed_request_id: $request.get_ref().computed_request_id.clone(),
+                result: Some(Result {
+                    code: ResultCode::Busy.into(),
+                    message: "HALT".to_string(),
+                }),
+                ..Default::default()
+            }));
+        }
+    };
+}
+
 #[tonic::async_trait]
 impl ProverService for ProverServiceSVC {
     async fn get_status(
@@ -113,7 +386,9 @@ impl ProverService for ProverServiceSVC {
             let mut response = GetStatusResponse::default();
             let success = self.pipeline.lock().unwrap().get_status();
             tracing::info!("node {:?}: lock pipeline {:?}", self.config.addr, success);
-            if success {
+            if shutdown::is_draining() {
+                response.status = get_status_response::Status::Halt.into();
+            } else if success {
                 response.status = get_status_response::Status::Idle.into();
             } else {
                 response.status = get_status_response::Status::Computing.into();
@@ -140,12 +415,31 @@ impl ProverService for ProverServiceSVC {
         request: Request<SplitElfRequest>,
     ) -> tonic::Result<Response<SplitElfResponse>, Status> {
         metrics::record_metrics("prover::split_elf", || async {
+            let _guard = shutdown::track();
+            reject_if_draining!(request, SplitElfResponse);
             tracing::info!(
                 "[split_elf] {}:{} start",
                 request.get_ref().proof_id,
                 request.get_ref().computed_request_id,
             );
             let start = Instant::now();
+            if let Err(e) = write_inline_inputs(request.get_ref()).await {
+                tracing::error!(
+                    "[split_elf] {}:{} write inputs failed: {:?}",
+                    request.get_ref().proof_id,
+                    request.get_ref().computed_request_id,
+                    e
+                );
+                return Ok(Response::new(SplitElfResponse {
+                    proof_id: request.get_ref().proof_id.clone(),
+                    computed_request_id: request.get_ref().computed_request_id.clone(),
+                    result: Some(Result {
+                        code: ResultCode::InternalError.into(),
+                        message: e.to_string(),
+                    }),
+                    ..Default::default()
+                }));
+            }
             let split_context = SplitContext::new(
                 &request.get_ref().base_dir,
                 &request.get_ref().program_id,
@@ -184,6 +478,17 @@ impl ProverService for ProverServiceSVC {
                 Err(e) => Err(e),
             };
             on_done!(result, response);
+            if response.result.as_ref().map(|r| r.code) == Some(ResultCode::Ok.into()) {
+                response.execution_report =
+                    read_execution_report(&request.get_ref().base_dir).await;
+            }
+            if !request.get_ref().elf_data.is_empty() {
+                response.outputs = split_outputs(request.get_ref()).await;
+                remove_inline_inputs(request.get_ref()).await;
+                if response.result.as_ref().map(|r| r.code) == Some(ResultCode::Ok.into()) {
+                    self.hold_segments(&request.get_ref().proof_id);
+                }
+            }
             let end = Instant::now();
             let elapsed = end.duration_since(start);
             tracing::info!(
@@ -197,6 +502,7 @@ impl ProverService for ProverServiceSVC {
             );
             Ok(Response::new(response))
         })
+        .instrument(rpc_span!("split_elf", request))
         .await
     }
 
@@ -205,6 +511,8 @@ impl ProverService for ProverServiceSVC {
         request: Request<ProveRequest>,
     ) -> tonic::Result<Response<ProveResponse>, Status> {
         metrics::record_metrics("prover::prove", || async {
+            let _guard = shutdown::track();
+            reject_if_draining!(request, ProveResponse);
             tracing::info!(
                 "[prove] {}:{} start",
                 request.get_ref().proof_id,
@@ -212,6 +520,39 @@ impl ProverService for ProverServiceSVC {
                 //request.get_ref().seg_path,
             );
             let start = Instant::now();
+            if !request.get_ref().segment_addr.is_empty() {
+                if let Err(e) = self
+                    .fetch_segment_from(
+                        &request.get_ref().segment_addr,
+                        &request.get_ref().proof_id,
+                        &request.get_ref().segment,
+                    )
+                    .await
+                {
+                    tracing::error!(
+                        "[prove] {}:{} fetch segment from {} failed: {:?}",
+                        request.get_ref().proof_id,
+                        request.get_ref().computed_request_id,
+                        request.get_ref().segment_addr,
+                        e
+                    );
+                    // rescheduled while the split node restarts
+                    let code = if prover_client::is_unavailable(&e) {
+                        ResultCode::Busy
+                    } else {
+                        ResultCode::InternalError
+                    };
+                    return Ok(Response::new(ProveResponse {
+                        proof_id: request.get_ref().proof_id.clone(),
+                        computed_request_id: request.get_ref().computed_request_id.clone(),
+                        result: Some(Result {
+                            code: code.into(),
+                            message: e.to_string(),
+                        }),
+                        ..Default::default()
+                    }));
+                }
+            }
             #[cfg(feature = "prover")]
             let prove_context = ProveContext::new(
                 request.get_ref().block_no,
@@ -239,6 +580,16 @@ impl ProverService for ProverServiceSVC {
                 guard.prove_root(&prove_context)
             };
             let result = run_back_task(prove_func).await;
+            let segment_addr = &request.get_ref().segment_addr;
+            if !segment_addr.is_empty() && *segment_addr != self.config.addr {
+                // the copy of the segment is fetched again if the task is retried here
+                if let Err(e) = file::new_async(&request.get_ref().segment)
+                    .remove_all()
+                    .await
+                {
+                    tracing::warn!("remove {} failed: {:?}", request.get_ref().segment, e);
+                }
+            }
             let mut response = ProveResponse {
                 proof_id: request.get_ref().proof_id.clone(),
                 computed_request_id: request.get_ref().computed_request_id.clone(),
@@ -260,6 +611,7 @@ impl ProverService for ProverServiceSVC {
             );
             Ok(Response::new(response))
         })
+        .instrument(rpc_span!("prove", request))
         .await
     }
 
@@ -268,6 +620,8 @@ impl ProverService for ProverServiceSVC {
         request: Request<AggregateRequest>,
     ) -> tonic::Result<Response<AggregateResponse>, Status> {
         metrics::record_metrics("prover::aggregate", || async {
+            let _guard = shutdown::track();
+            reject_if_draining!(request, AggregateResponse);
             tracing::info!(
                 "[aggregate] {}:{} {} inputs start",
                 request.get_ref().proof_id,
@@ -332,6 +686,7 @@ impl ProverService for ProverServiceSVC {
             );
             Ok(Response::new(response))
         })
+        .instrument(rpc_span!("aggregate", request))
         .await
     }
 
@@ -340,6 +695,8 @@ impl ProverService for ProverServiceSVC {
         request: Request<SnarkProofRequest>,
     ) -> tonic::Result<Response<SnarkProofResponse>, Status> {
         metrics::record_metrics("prover::snark_proof", || async {
+            let _guard = shutdown::track();
+            reject_if_draining!(request, SnarkProofResponse);
             tracing::info!(
                 "[snark_proof] {}:{} start",
                 request.get_ref().proof_id,
@@ -384,6 +741,192 @@ impl ProverService for ProverServiceSVC {
             );
             Ok(Response::new(response))
         })
+        .instrument(rpc_span!("snark_proof", request))
+        .await
+    }
+
+    async fn warm_program(
+        &self,
+        request: Request<WarmProgramRequest>,
+    ) -> tonic::Result<Response<WarmProgramResponse>, Status> {
+        metrics::record_metrics("prover::warm_program", || async {
+            let _guard = shutdown::track();
+            tracing::info!("[warm_program] {} start", request.get_ref().program_id);
+            let start = Instant::now();
+            let program_id = request.get_ref().program_id.clone();
+            let elf_path = request.get_ref().elf_path.clone();
+            let pipeline = self.pipeline.clone();
+            let warm_func = move || {
+                let guard = pipeline.lock().unwrap_or_else(|e| {
+                    tracing::error!("Mutex poisoned, recovering");
+                    e.into_inner()
+                });
+                guard
+                    .warm_program(&program_id, &elf_path)
+                    .map(|done| (done, Vec::<u8>::new()))
+            };
+            let result = run_back_task(warm_func).await;
+            let mut response = WarmProgramResponse {
+                program_id: request.get_ref().program_id.clone(),
+                ..Default::default()
+            };
+            on_done!(result, response);
+            tracing::info!(
+                "[warm_program] {} code:{} elapsed:{} end",
+                request.get_ref().program_id,
+                response.result.as_ref().unwrap().code,
+                start.elapsed().as_secs()
+            );
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    type FetchSegmentStream = ReceiverStream<tonic::Result<FetchSegmentResponse, Status>>;
+
+    async fn fetch_segment(
+        &self,
+        request: Request<FetchSegmentRequest>,
+    ) -> tonic::Result<Response<Self::FetchSegmentStream>, Status> {
+        metrics::record_metrics("prover::fetch_segment", || async {
+            // still served while draining, the segments are fetched by the tasks of the other nodes
+            let guard = shutdown::track();
+            let path = request.get_ref().path.clone();
+            tracing::info!(
+                "[fetch_segment] {} {} start",
+                request.get_ref().proof_id,
+                path
+            );
+            if !is_served(&self.config.base_dir, &request.get_ref().proof_id, &path) {
+                return Err(Status::permission_denied(format!(
+                    "{} is not an output of proof {}",
+                    path,
+                    request.get_ref().proof_id
+                )));
+            }
+            let encrypted = file::is_encrypted(&path)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            // an encrypted file is sent in the clear over TLS, the receiver encrypts it again
+            let mut reader: file::BoxedReader = if encrypted {
+                let buf = file::new_async(&path)
+                    .read()
+                    .await
+                    .map_err(|e| Status::not_found(e.to_string()))?;
+                Box::new(std::io::Cursor::new(buf))
+            } else {
+                file::new_async(&path)
+                    .reader()
+                    .await
+                    .map_err(|e| Status::not_found(e.to_string()))?
+            };
+            let (tx, rx) = tokio::sync::mpsc::channel(4);
+            tokio::spawn(async move {
+                let _guard = guard;
+                let mut buf = vec![0u8; FETCH_CHUNK_SIZE];
+                loop {
+                    let chunk = match reader.read(&mut buf).await {
+                        Ok(0) => break,
+                        Ok(n) => Ok(FetchSegmentResponse {
+                            data: buf[..n].to_vec(),
+                        }),
+                        Err(e) => Err(Status::internal(e.to_string())),
+                    };
+                    let failed = chunk.is_err();
+                    if tx.send(chunk).await.is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            Ok(Response::new(ReceiverStream::new(rx)))
+        })
+        .await
+    }
+
+    async fn release_segments(
+        &self,
+        request: Request<ReleaseSegmentsRequest>,
+    ) -> tonic::Result<Response<ReleaseSegmentsResponse>, Status> {
+        metrics::record_metrics("prover::release_segments", || async {
+            let proof_id = &request.get_ref().proof_id;
+            let seg_path = &request.get_ref().seg_path;
+            tracing::info!("[release_segments] {} {}", proof_id, seg_path);
+            if !is_segment_dir(&self.config.base_dir, proof_id, seg_path) {
+                return Err(Status::permission_denied(format!(
+                    "{} is not the segment directory of proof {}",
+                    seg_path, proof_id
+                )));
+            }
+            self.served.lock().unwrap().remove(proof_id);
+            let result = match file::new_async(seg_path).remove_all().await {
+                Ok(_) => Result {
+                    code: ResultCode::Ok.into(),
+                    ..Default::default()
+                },
+                Err(e) => Result {
+                    code: ResultCode::InternalError.into(),
+                    message: e.to_string(),
+                },
+            };
+            Ok(Response::new(ReleaseSegmentsResponse {
+                result: Some(result),
+            }))
+        })
+        .await
+    }
+
+    async fn drain(
+        &self,
+        _request: Request<DrainRequest>,
+    ) -> tonic::Result<Response<DrainResponse>, Status> {
+        metrics::record_metrics("prover::drain", || async {
+            if shutdown::drain() {
+                tracing::info!("node {:?}: drain requested", self.config.addr);
+            }
+            let response = DrainResponse {
+                status: get_status_response::Status::Halt.into(),
+                // this request itself is not tracked
+                running_tasks: shutdown::inflight() as u64,
+            };
+            Ok(Response::new(response))
+        })
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_served() {
+        let served = |path: &str| is_served("/data", "p1", path);
+        assert!(served("/data/proof/p1/segment/0"));
+        assert!(served("/data/proof/p1/segment/0.sha256"));
+        assert!(served("/data/proof/p1/vk.bin"));
+        assert!(served("/data/proof/p1/wrap/public_values.bin"));
+        assert!(served("/data/proof/p1/execution_report.json"));
+        assert!(served("/data/proof/p1/output_stream/output_stream"));
+
+        assert!(!served("/data/proof/p2/segment/0"));
+        assert!(!served("/data/proof/p1/input_stream/private_input"));
+        assert!(!served("/data/proof/p1/output_stream/.data_key"));
+        assert!(!served("/data/proof/p1/segment/0.sha256.sha256"));
+        assert!(!served(
+            "/data/proof/p1/segment/../input_stream/private_input"
+        ));
+        assert!(!served("/data/proof/p1/segment"));
+        assert!(!served("/data/artifacts/0123"));
+        assert!(!is_served(
+            "/data",
+            "p1/../p2",
+            "/data/proof/p1/../p2/vk.bin"
+        ));
+        assert!(!is_served("", "p1", "/proof/p1/vk.bin"));
+
+        assert!(is_segment_dir("/data", "p1", "/data/proof/p1/segment/"));
+        assert!(!is_segment_dir("/data", "p1", "/data/proof/p1"));
+        assert!(!is_segment_dir("/data", "p1", "/data/proof/p2/segment"));
+        assert!(!is_segment_dir("/data", "p1", "/data/proof/p1/segment/.."));
+    }
+}
@@ -0,0 +1,67 @@
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+lazy_static! {
+    static ref DRAIN: watch::Sender<bool> = watch::channel(false).0;
+}
+
+static INFLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Put the process into drain mode: no new work is accepted, in-flight work is finished or
+/// handed back. Returns false if the process was already draining.
+pub fn drain() -> bool {
+    !DRAIN.send_replace(true)
+}
+
+pub fn is_draining() -> bool {
+    *DRAIN.borrow()
+}
+
+/// Resolves once drain mode is switched on.
+pub async fn draining() {
+    let mut rx = DRAIN.subscribe();
+    let _ = rx.wait_for(|draining| *draining).await;
+}
+
+/// Resolves once drain mode is on and every tracked task has finished.
+pub async fn drained() {
+    draining().await;
+    loop {
+        let inflight = INFLIGHT.load(Ordering::SeqCst);
+        if inflight == 0 {
+            break;
+        }
+        tracing::info!("Draining, waiting for {} task(s) to finish", inflight);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+pub fn inflight() -> usize {
+    INFLIGHT.load(Ordering::SeqCst)
+}
+
+/// Keeps the process alive in drain mode until dropped.
+pub struct TaskGuard(());
+
+pub fn track() -> TaskGuard {
+    INFLIGHT.fetch_add(1, Ordering::SeqCst);
+    TaskGuard(())
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        INFLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Wait for SIGTERM or SIGINT.
+pub async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
@@ -0,0 +1,187 @@
+//! Estimation of the segments and the time of a proof, from the cycles of its program and the
+//! durations of the past tasks in the `stage_event` table.
+use crate::database::Database;
+use crate::proto::includes::v1::Step;
+use crate::stage::tasks::TASK_STATE_SUCCESS;
+
+/// The past tasks of a step the durations are averaged over.
+const SAMPLES: i32 = 100;
+
+/// The tasks of a proof, as `Stage::gen_agg_tasks` lays them out for prover_v2.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Layout {
+    pub segments: u64,
+    /// The number of aggregation tasks of each layer, the last one has a single task.
+    pub agg_layers: Vec<u64>,
+}
+
+impl Layout {
+    pub fn new(total_cycles: u64, shard_size: u64, first_layer_batch_size: u64) -> Self {
+        let segments = total_cycles.div_ceil(shard_size.max(1)).max(1);
+        let mut agg_layers = vec![segments.div_ceil(first_layer_batch_size.max(1))];
+        while let Some(&tasks) = agg_layers.last() {
+            if tasks <= 1 {
+                break;
+            }
+            agg_layers.push(tasks.div_ceil(2));
+        }
+        Layout {
+            segments,
+            agg_layers,
+        }
+    }
+}
+
+/// The layout of a proof of `total_cycles` split into segments of `seg_size`.
+#[cfg(feature = "prover_v2")]
+pub fn layout(total_cycles: u64, seg_size: u32) -> Layout {
+    Layout::new(
+        total_cycles,
+        prover_v2::shard_size(seg_size),
+        prover_v2::FIRST_LAYER_BATCH_SIZE as u64,
+    )
+}
+
+// Only prover_v2 executes the programs in the stage, see `Execute`.
+#[cfg(not(feature = "prover_v2"))]
+pub fn layout(_total_cycles: u64, _seg_size: u32) -> Layout {
+    Layout::default()
+}
+
+/// The average seconds of a successful task of each step.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TaskTimings {
+    pub split_per_cycle: f64,
+    pub prove: f64,
+    pub agg: f64,
+    pub snark: f64,
+    /// The fewest past tasks of a step the averages are from.
+    pub samples: usize,
+}
+
+impl TaskTimings {
+    /// Average the last tasks of the steps up to `target_step`.
+    pub async fn load(db: &Database, target_step: Step) -> anyhow::Result<Self> {
+        let mut timings = TaskTimings {
+            samples: usize::MAX,
+            ..Default::default()
+        };
+        for step in [Step::Split, Step::Prove, Step::Agg, Step::Snark] {
+            if step > target_step {
+                break;
+            }
+            let durations = db
+                .get_task_durations(step.into(), TASK_STATE_SUCCESS as i32, SAMPLES)
+                .await?;
+            timings.samples = timings.samples.min(durations.len());
+            if durations.is_empty() {
+                continue;
+            }
+            let average =
+                durations.iter().map(|d| d.duration as f64).sum::<f64>() / durations.len() as f64;
+            match step {
+                Step::Split => {
+                    // the split runs the whole program, so its time is by cycle
+                    let (seconds, cycles) = durations
+                        .iter()
+                        .filter_map(|d| Some((d.duration, d.total_cycles.filter(|c| *c > 0)?)))
+                        .fold(
+                            (0f64, 0f64),
+                            |(seconds, cycles), (duration, total_cycles)| {
+                                (seconds + duration as f64, cycles + total_cycles as f64)
+                            },
+                        );
+                    if cycles > 0.0 {
+                        timings.split_per_cycle = seconds / cycles;
+                    }
+                }
+                Step::Prove => timings.prove = average,
+                Step::Agg => timings.agg = average,
+                _ => timings.snark = average,
+            }
+        }
+        Ok(timings)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Estimate {
+    pub node_seconds: f64,
+    pub wall_clock_seconds: f64,
+}
+
+impl Estimate {
+    /// The prove tasks run while the split writes the segments, and the aggregation tasks of a
+    /// layer once the split, all the prove tasks and the previous layer are done, each on one of
+    /// the `nodes`.
+    pub fn new(
+        total_cycles: u64,
+        layout: &Layout,
+        timings: &TaskTimings,
+        nodes: u64,
+        target_step: Step,
+    ) -> Self {
+        let nodes = nodes.max(1);
+        let split = total_cycles as f64 * timings.split_per_cycle;
+        let mut estimate = Estimate {
+            node_seconds: split,
+            wall_clock_seconds: split,
+        };
+        if target_step >= Step::Agg {
+            estimate.node_seconds += layout.segments as f64 * timings.prove;
+            let prove = layout.segments.div_ceil(nodes) as f64 * timings.prove;
+            estimate.wall_clock_seconds = split.max(prove);
+            for tasks in &layout.agg_layers {
+                estimate.node_seconds += *tasks as f64 * timings.agg;
+                estimate.wall_clock_seconds += tasks.div_ceil(nodes) as f64 * timings.agg;
+            }
+        }
+        if target_step >= Step::Snark {
+            estimate.node_seconds += timings.snark;
+            estimate.wall_clock_seconds += timings.snark;
+        }
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let layout = Layout::new(10, 4, 1);
+        assert_eq!(
+            layout,
+            Layout {
+                segments: 3,
+                agg_layers: vec![3, 2, 1]
+            }
+        );
+        assert_eq!(Layout::new(0, 4, 1).agg_layers, [1]);
+
+        let timings = TaskTimings {
+            split_per_cycle: 0.5,
+            prove: 10.0,
+            agg: 2.0,
+            snark: 100.0,
+            samples: 1,
+        };
+        assert_eq!(
+            Estimate::new(10, &layout, &timings, 2, Step::Snark),
+            Estimate {
+                node_seconds: 5.0 + 30.0 + 12.0 + 100.0,
+                wall_clock_seconds: 20.0 + 8.0 + 100.0,
+            }
+        );
+        // the split outlasts the prove tasks
+        assert_eq!(
+            Estimate::new(100, &layout, &timings, 2, Step::Agg).wall_clock_seconds,
+            50.0 + 8.0
+        );
+        assert_eq!(
+            Estimate::new(10, &layout, &timings, 2, Step::Split).node_seconds,
+            5.0
+        );
+    }
+}
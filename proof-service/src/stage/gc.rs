@@ -0,0 +1,381 @@
+//! Garbage collection of the proof directories, by the `[retention]` policy of the stage config.
+//! Each removed path is recorded in the `gc_log` table, and `stage_task.gc_level` tracks how far
+//! a proof has been collected. The content-addressed inputs under `{base_dir}/artifacts` are
+//! removed once no proof which is not entirely collected refers to them.
+use crate::config::RetentionConfig;
+use crate::database::{Database, StageTask};
+use crate::lease;
+use crate::proto::includes::v1::Step;
+use crate::prover_client;
+use crate::shutdown;
+use crate::stage::tasks::TASK_STATE_SUCCESS;
+use crate::stage::{stage::get_timestamp, GenerateTask};
+use crate::TlsConfig;
+use common::file;
+use std::collections::HashSet;
+use tokio::time;
+
+const GC_LEVEL_SEGMENTS: i32 = 1;
+const GC_LEVEL_INTERMEDIATES: i32 = 2;
+const GC_LEVEL_ALL: i32 = 3;
+
+const DEFAULT_INTERVAL: u64 = 3600;
+const BATCH_SIZE: i32 = 100;
+const SECONDS_PER_DAY: u64 = 86400;
+
+// Held by the replica collecting, so that the replicas do not remove the same paths at once.
+const GC_LEASE: &str = "gc";
+
+struct Collector {
+    retention: RetentionConfig,
+    base_dir: String,
+    // of the ReleaseSegments calls to the split nodes
+    tls_config: Option<TlsConfig>,
+    // the artifacts unreferenced at the last collection, a request may be writing the others
+    unreferenced: HashSet<String>,
+}
+
+pub fn start(
+    retention: RetentionConfig,
+    base_dir: &str,
+    tls_config: Option<TlsConfig>,
+    db: Database,
+) {
+    let mut collector = Collector {
+        retention,
+        base_dir: base_dir.to_string(),
+        tls_config,
+        unreferenced: HashSet::new(),
+    };
+    tokio::spawn(async move {
+        let interval = collector.retention.interval.unwrap_or(DEFAULT_INTERVAL);
+        loop {
+            if shutdown::is_draining() {
+                tracing::info!("[gc] draining, stop collecting");
+                return;
+            }
+            match lease::acquire(&db, GC_LEASE).await {
+                Ok(true) => {
+                    if let Err(e) = collector.collect(&db).await {
+                        tracing::error!("[gc] {:?}", e);
+                    }
+                    let _ = lease::release(&db, GC_LEASE).await;
+                }
+                Ok(false) => tracing::debug!("[gc] collected by another replica"),
+                Err(e) => tracing::error!("[gc] acquire lease: {:?}", e),
+            }
+            time::sleep(time::Duration::from_secs(interval)).await;
+        }
+    });
+}
+
+// The batches are collected until none is left, each collected task leaves the next batch.
+async fn next_batch(
+    db: &Database,
+    tasks: impl std::future::Future<Output = anyhow::Result<Vec<StageTask>>>,
+) -> anyhow::Result<Vec<StageTask>> {
+    if shutdown::is_draining() {
+        return Ok(vec![]);
+    }
+    // renewed for each batch, a lost lease stops the collection
+    if !lease::acquire(db, GC_LEASE).await? {
+        anyhow::bail!("lost the {} lease", GC_LEASE);
+    }
+    tasks.await
+}
+
+impl Collector {
+    async fn collect(&mut self, db: &Database) -> anyhow::Result<()> {
+        let retention = &self.retention;
+        let now = get_timestamp();
+        if retention.delete_segments.unwrap_or(false) {
+            loop {
+                let tasks = next_batch(
+                    db,
+                    db.get_aggregated_stage_tasks(GC_LEVEL_SEGMENTS, BATCH_SIZE),
+                )
+                .await?;
+                if tasks.is_empty() {
+                    break;
+                }
+                for task in tasks {
+                    let generate_task = generate_task(&task)?;
+                    // the segments are the result of a split-only proof
+                    if generate_task.target_step != Step::Split {
+                        remove(db, &task.id, &generate_task.seg_path).await?;
+                        if generate_task.segment_transfer {
+                            self.release_segments(db, &generate_task).await;
+                        }
+                    }
+                    db.update_stage_task_gc_level(&task.id, GC_LEVEL_SEGMENTS)
+                        .await?;
+                }
+            }
+        }
+        if let Some(days) = retention.intermediates_days {
+            let created_before = now.saturating_sub(days * SECONDS_PER_DAY);
+            loop {
+                let tasks = next_batch(
+                    db,
+                    db.get_finished_stage_tasks(GC_LEVEL_INTERMEDIATES, created_before, BATCH_SIZE),
+                )
+                .await?;
+                if tasks.is_empty() {
+                    break;
+                }
+                for task in tasks {
+                    let generate_task = generate_task(&task)?;
+                    remove_except(
+                        db,
+                        &task.id,
+                        &generate_task.base_dir,
+                        &final_outputs(&generate_task),
+                    )
+                    .await?;
+                    db.update_stage_task_gc_level(&task.id, GC_LEVEL_INTERMEDIATES)
+                        .await?;
+                }
+            }
+        }
+        if let Some(days) = retention.proofs_days {
+            let created_before = now.saturating_sub(days * SECONDS_PER_DAY);
+            loop {
+                let tasks = next_batch(
+                    db,
+                    db.get_finished_stage_tasks(GC_LEVEL_ALL, created_before, BATCH_SIZE),
+                )
+                .await?;
+                if tasks.is_empty() {
+                    break;
+                }
+                for task in tasks {
+                    remove(db, &task.id, &generate_task(&task)?.base_dir).await?;
+                    db.update_stage_task_gc_level(&task.id, GC_LEVEL_ALL)
+                        .await?;
+                }
+            }
+        }
+        self.collect_artifacts(db).await
+    }
+
+    // The segments of a proof stay on its split node without a shared storage, the stage
+    // removes them at the end of the proof, and again here in case it could not.
+    async fn release_segments(&self, db: &Database, generate_task: &GenerateTask) {
+        let events = match db.get_stage_events(&generate_task.proof_id).await {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::warn!("[gc] {} events: {:?}", generate_task.proof_id, e);
+                return;
+            }
+        };
+        let split_nodes = events.iter().filter(|event| {
+            event.step == i32::from(Step::Split)
+                && event.state == TASK_STATE_SUCCESS as i32
+                && !event.node.is_empty()
+        });
+        for event in split_nodes {
+            if let Err(e) = prover_client::release_segments(
+                &event.node,
+                &generate_task.proof_id,
+                &generate_task.seg_path,
+                self.tls_config.clone(),
+            )
+            .await
+            {
+                tracing::warn!(
+                    "[gc] release the segments of {} on {}: {:?}",
+                    generate_task.proof_id,
+                    event.node,
+                    e
+                );
+            }
+        }
+    }
+
+    // An artifact is removed once it is unreferenced at two collections in a row.
+    async fn collect_artifacts(&mut self, db: &Database) -> anyhow::Result<()> {
+        let dir = format!("{}/artifacts", self.base_dir);
+        if !file::new_async(&dir).exists().await? {
+            return Ok(());
+        }
+        let mut unreferenced = HashSet::new();
+        for (i, name) in file::new_async(&dir).read_dir().await?.iter().enumerate() {
+            let path = format!("{}/{}", dir, name);
+            if i % BATCH_SIZE as usize == 0 {
+                if shutdown::is_draining() {
+                    return Ok(());
+                }
+                if !lease::acquire(db, GC_LEASE).await? {
+                    anyhow::bail!("lost the {} lease", GC_LEASE);
+                }
+            }
+            if db.is_referenced_by_stage_task(&path, GC_LEVEL_ALL).await? {
+                continue;
+            }
+            if self.unreferenced.contains(&path) {
+                remove(db, "", &path).await?;
+            } else {
+                unreferenced.insert(path);
+            }
+        }
+        self.unreferenced = unreferenced;
+        Ok(())
+    }
+}
+
+/// The paths `GetStatus` and `VerifyProof` serve, kept until the whole proof directory expires.
+fn final_outputs(generate_task: &GenerateTask) -> Vec<String> {
+    let mut outputs = vec![
+        format!("{}/wrap", generate_task.base_dir),
+        format!("{}/vk.bin", generate_task.base_dir),
+        generate_task.snark_path.clone(),
+        generate_task.output_stream_path.clone(),
+    ];
+    if generate_task.composite_proof {
+        outputs.push(format!("{}/receipt/0", generate_task.prove_path));
+    }
+    outputs.retain(|path| !path.is_empty());
+    outputs
+}
+
+// Remove what is under `dir` but the `kept` paths and their parent directories.
+async fn remove_except(
+    db: &Database,
+    proof_id: &str,
+    dir: &str,
+    kept: &[String],
+) -> anyhow::Result<()> {
+    if !file::new_async(dir).exists().await? {
+        return Ok(());
+    }
+    for name in file::new_async(dir).read_dir().await? {
+        let path = format!("{}/{}", dir, name);
+        let prefix = format!("{}/", path);
+        if kept.contains(&path) {
+            continue;
+        }
+        if kept.iter().any(|kept| kept.starts_with(&prefix)) {
+            Box::pin(remove_except(db, proof_id, &path, kept)).await?;
+        } else {
+            remove(db, proof_id, &path).await?;
+        }
+    }
+    Ok(())
+}
+
+fn generate_task(task: &StageTask) -> anyhow::Result<GenerateTask> {
+    let context = task
+        .context
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("stage task {} has no context", task.id))?;
+    Ok(serde_json::from_str(context)?)
+}
+
+async fn remove(db: &Database, proof_id: &str, path: &str) -> anyhow::Result<()> {
+    if !file::new_async(path).exists().await? {
+        return Ok(());
+    }
+    let bytes = file::new_async(path).remove_all().await?;
+    tracing::info!("[gc] {} removed {} ({} bytes)", proof_id, path, bytes);
+    db.insert_gc_log(proof_id, path, bytes).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_remove_intermediates() {
+        let dir = std::env::temp_dir().join(format!("gc-{}", uuid::Uuid::new_v4()));
+        let base_dir = format!("{}/proof/p1", dir.display());
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(&format!("sqlite://{}/stage.db", dir.display())).unwrap();
+        db.migrate().await.unwrap();
+
+        let generate_task = GenerateTask {
+            base_dir: base_dir.clone(),
+            prove_path: format!("{}/prove", base_dir),
+            snark_path: format!("{}/snark/proof_with_public_inputs.json", base_dir),
+            output_stream_path: format!("{}/output_stream/output_stream", base_dir),
+            composite_proof: true,
+            ..Default::default()
+        };
+        let files = [
+            "vk.bin",
+            "wrap/public_values.bin",
+            "snark/proof_with_public_inputs.json",
+            "output_stream/output_stream",
+            "prove/receipt/0",
+            "prove/receipt/1",
+            "segment/0",
+            "input_stream/private_input",
+        ];
+        for name in files {
+            let path = format!("{}/{}", base_dir, name);
+            file::new_async(path.rsplit_once('/').unwrap().0)
+                .create_dir_all()
+                .await
+                .unwrap();
+            file::new_async(&path).write(name.as_bytes()).await.unwrap();
+        }
+        remove_except(&db, "p1", &base_dir, &final_outputs(&generate_task))
+            .await
+            .unwrap();
+        for name in files {
+            let exists = file::new_async(&format!("{}/{}", base_dir, name))
+                .exists()
+                .await
+                .unwrap();
+            let removed = ["prove/receipt/1", "segment/0", "input_stream/private_input"];
+            assert_eq!(exists, !removed.contains(&name), "{}", name);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_collect_artifacts() {
+        let dir = std::env::temp_dir().join(format!("gc-{}", uuid::Uuid::new_v4()));
+        let base_dir = dir.display().to_string();
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::new(&format!("sqlite://{}/stage.db", base_dir)).unwrap();
+        db.migrate().await.unwrap();
+        lease::init(db.clone());
+
+        let artifacts = format!("{}/artifacts", base_dir);
+        let used = file::put_content_addressed(&artifacts, b"elf")
+            .await
+            .unwrap();
+        let unused = file::put_content_addressed(&artifacts, b"old")
+            .await
+            .unwrap();
+        let context = serde_json::to_string(&GenerateTask {
+            elf_path: used.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        db.insert_stage_task("p1", "", 1, &context).await.unwrap();
+
+        let mut collector = Collector {
+            retention: RetentionConfig::default(),
+            base_dir,
+            tls_config: None,
+            unreferenced: HashSet::new(),
+        };
+        let exists = |path: String| async move { file::new_async(&path).exists().await.unwrap() };
+        // an artifact may be written before the proof referring to it is inserted
+        collector.collect_artifacts(&db).await.unwrap();
+        assert!(exists(unused.clone()).await);
+        collector.collect_artifacts(&db).await.unwrap();
+        assert!(!exists(unused).await);
+        assert!(exists(used.clone()).await);
+
+        db.update_stage_task_gc_level("p1", GC_LEVEL_ALL)
+            .await
+            .unwrap();
+        collector.collect_artifacts(&db).await.unwrap();
+        collector.collect_artifacts(&db).await.unwrap();
+        assert!(!exists(used).await);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
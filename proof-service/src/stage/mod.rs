@@ -1,8 +1,11 @@
+pub mod estimate;
+mod gc;
 #[allow(clippy::module_inception)]
 pub mod stage;
 pub mod stage_service;
 mod stage_worker;
 pub mod tasks;
+pub mod verify;
 pub use tasks::generate_task::GenerateTask;
 
 use crate::proto::includes::v1::BlockFileItem;
@@ -25,7 +28,7 @@ pub fn read_block_data(block_no: u64, block_path: &str) -> Vec<BlockFileItem> {
 
 pub fn safe_read(path: &str) -> Vec<u8> {
     tracing::debug!("read {}", path);
-    std::fs::read(path).unwrap_or_else(|_e| {
+    file::new(path).read().unwrap_or_else(|_e| {
         // tracing::warn!("read: {}, {:?}", path, e);
         vec![]
     })
@@ -95,7 +95,10 @@ impl Stage {
                         return;
                     } else {
                         self.gen_prove_task_post();
-                        crate::metrics::SEGMENTS_GAUGE.set(self.prove_tasks.len() as f64);
+                        crate::metrics::PROOF_SEGMENTS_HISTOGRAM
+                            .observe(self.prove_tasks.len() as f64);
+                        crate::metrics::PROOF_CYCLES_HISTOGRAM
+                            .observe(self.split_task.total_steps as f64);
                         tracing::info!(
                             "proof_id {} done. Generate {} prove_tasks",
                             self.generate_task.proof_id,
@@ -191,6 +194,7 @@ impl Stage {
             .clone_from(&self.generate_task.output_stream_path);
         self.split_task.block_no = self.generate_task.block_no;
         self.split_task.seg_size = self.generate_task.seg_size;
+        self.split_task.segment_transfer = self.generate_task.segment_transfer;
 
         self.split_task.task_id = uuid::Uuid::new_v4().to_string();
         self.split_task.state = TASK_STATE_UNPROCESSED;
@@ -205,6 +209,8 @@ impl Stage {
         let dst = &mut self.split_task;
         dst.total_steps = split_task.total_steps;
         dst.total_segments = split_task.total_segments;
+        // the prove nodes fetch the segments from it
+        dst.trace.node_info.clone_from(&split_task.trace.node_info);
         on_task!(split_task, dst, self);
     }
 
@@ -219,6 +225,8 @@ impl Stage {
             file_no,
             is_deferred: false,
             segment: format!("{}/{file_no}", self.generate_task.seg_path),
+            // assigned once the split is done, see `gen_prove_task_post`
+            segment_addr: String::new(),
             program: self.generate_task.gen_program(),
             // will be assigned after the root proving
             output: vec![],
@@ -226,7 +234,11 @@ impl Stage {
     }
 
     fn gen_prove_task(&mut self) {
-        if self.generate_task.target_step == Step::Split || self.is_tasks_gen_done {
+        // Without shared storage the segments are only known once the split is done.
+        if self.generate_task.target_step == Step::Split
+            || self.is_tasks_gen_done
+            || self.generate_task.segment_transfer
+        {
             return;
         }
         // Pre-allocate 64 tasks
@@ -236,16 +248,16 @@ impl Stage {
                 .map(|i| self.task_with_no(i))
                 .collect();
         }
-        let file_numbers: usize = match std::fs::read_to_string(format!(
-            "{}/segments.txt",
-            self.generate_task.seg_path
-        )) {
-            Ok(content) => match content.trim().parse() {
-                Ok(n) => n,
+        let file_numbers: usize =
+            match common::file::new(&format!("{}/segments.txt", self.generate_task.seg_path))
+                .read_to_string()
+            {
+                Ok(content) => match content.trim().parse() {
+                    Ok(n) => n,
+                    Err(_) => return,
+                },
                 Err(_) => return,
-            },
-            Err(_) => return,
-        };
+            };
 
         // generate prove tasks
         for file_no in self.prove_tasks.len()..file_numbers {
@@ -269,6 +281,12 @@ impl Stage {
                     .collect::<Vec<_>>();
                 self.prove_tasks.extend_from_slice(&missing_tasks);
             }
+            if self.generate_task.segment_transfer {
+                for task in self.prove_tasks.iter_mut() {
+                    task.segment_addr
+                        .clone_from(&self.split_task.trace.node_info);
+                }
+            }
         }
 
         #[cfg(feature = "prover_v2")]
@@ -316,7 +334,14 @@ impl Stage {
     pub fn get_prove_task(&mut self) -> Option<ProveTask> {
         for prove_task in self.prove_tasks.iter_mut() {
             if prove_task.state == TASK_STATE_UNPROCESSED || prove_task.state == TASK_STATE_FAILED {
-                if !std::path::Path::new(&prove_task.segment).exists() {
+                if self.generate_task.segment_transfer {
+                    if !self.is_tasks_gen_done {
+                        continue;
+                    }
+                } else if !common::file::new(&prove_task.segment)
+                    .exists()
+                    .unwrap_or(false)
+                {
                     continue;
                 }
                 prove_task.state = TASK_STATE_PROCESSING;
@@ -356,6 +381,18 @@ impl Stage {
             .count()
     }
 
+    /// The tasks of `step` waiting for a prover node.
+    pub fn count_pending_tasks(&self, step: Step) -> usize {
+        let pending = |state: u32| state == TASK_STATE_UNPROCESSED || state == TASK_STATE_FAILED;
+        match step {
+            Step::Split => pending(self.split_task.state) as usize,
+            Step::Prove => self.prove_tasks.iter().filter(|t| pending(t.state)).count(),
+            Step::Agg => self.agg_tasks.iter().filter(|t| pending(t.state)).count(),
+            Step::Snark => pending(self.snark_task.state) as usize,
+            _ => 0,
+        }
+    }
+
     pub fn count_processing_prove_tasks(&self) -> usize {
         self.prove_tasks
             .iter()
@@ -524,9 +561,9 @@ impl Stage {
             // write final agg task output
             if agg_task.is_final && self.generate_task.target_step == Step::Agg {
                 // Here we also use snark_path to store agg proof ;
-                let mut f = std::fs::File::create(&self.generate_task.snark_path)
-                    .unwrap_or_else(|_| panic!("can not open {}", &self.generate_task.snark_path));
-                f.write_all(&agg_task.output).unwrap();
+                common::file::new(&self.generate_task.snark_path)
+                    .write_all(&agg_task.output)
+                    .unwrap_or_else(|_| panic!("can not write {}", &self.generate_task.snark_path));
             }
         }
     }
@@ -572,9 +609,9 @@ impl Stage {
         let dst = &mut self.snark_task;
         // write snark proof to disk
         // TODO: handle the result gracefully
-        let mut f = std::fs::File::create(&self.generate_task.snark_path)
-            .unwrap_or_else(|_| panic!("can not open {}", &self.generate_task.snark_path));
-        f.write_all(&snark_task.output).unwrap();
+        common::file::new(&self.generate_task.snark_path)
+            .write_all(&snark_task.output)
+            .unwrap_or_else(|_| panic!("can not write {}", &self.generate_task.snark_path));
         on_task!(snark_task, dst, self);
     }
 }
@@ -1,34 +1,52 @@
 use crate::proto::stage_service::v1::{
     stage_service_server::StageService,
-    GenerateProofRequest, GenerateProofResponse, GetStatusRequest, GetStatusResponse,
+    EstimateProofRequest, EstimateProofResponse, ExecuteRequest, ExecuteResponse,
+    GenerateProofRequest, GenerateProofResponse, GetExecutionReportRequest,
+    GetExecutionReportResponse, GetProofTraceRequest, GetProofTraceResponse, GetStatusRequest,
+    GetStatusResponse, ProofEvent,
     Status::{Computing, InvalidParameter},
+    VerifyProofRequest, VerifyProofResponse,
 };
+#[cfg(feature = "prover_v2")]
+use crate::proto::{includes::v1::ExecutionReport, stage_service::v1::Status::Success};
 use anyhow::Error;
 use common::tls::Config as TlsConfig;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-use crate::stage::{stage_worker, tasks, GenerateTask};
+use crate::stage::{estimate, gc, stage_worker, tasks, verify, GenerateTask};
 
 use tonic::{Request, Response, Status};
 
+use crate::attestation;
 use crate::config;
-use common::file;
+use crate::lease;
+use common::{envelope, file};
 
 #[cfg(feature = "prover")]
 use prover::provers;
+#[cfg(feature = "prover_v2")]
+use prover_v2::{
+    contexts::SplitContext,
+    executor::{ExecuteOutput, Executor},
+};
 
 use ethers::types::Signature;
 use sha2::{Digest, Sha256};
-use std::io::Write;
 use std::str::FromStr;
 
 use crate::database;
 use crate::metrics;
+use crate::notify;
+use crate::prover_node;
+use crate::shutdown;
 
 use crate::proto::includes::v1::{ProverVersion, Step};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 
+const DEFAULT_EXECUTE_CONCURRENCY: usize = 2;
+
 lazy_static! {
     static ref GLOBAL_TASKMAP: Mutex<HashMap<String, i32>> = Mutex::new(HashMap::new());
 }
@@ -36,6 +54,14 @@ lazy_static! {
 pub struct StageServiceSVC {
     db: database::Database,
     config: config::RuntimeConfig,
+    // the programs run by `Execute` and `EstimateProof`
+    executions: Arc<Semaphore>,
+}
+
+/// Held by a running program until the emulator returns, even if the request is dropped.
+struct ExecuteSlot {
+    _permit: OwnedSemaphorePermit,
+    _guard: shutdown::TaskGuard,
 }
 
 impl StageServiceSVC {
@@ -53,11 +79,31 @@ impl StageServiceSVC {
             None
         };
         let database_url = config.database_url.as_str();
-        let db = database::Database::new(database_url);
-        sqlx::migrate!("./migrations").run(&db.db_pool).await?;
+        let db = database::Database::new(database_url)?;
+        db.migrate().await?;
+        lease::init(db.clone());
+        if let Some(key_path) = &config.attestation_key_path {
+            attestation::init(key_path)?;
+        }
+        if let Some(notify) = config.notify.clone() {
+            notify::init(notify);
+        }
+        notify::start(db.clone());
         let _ =
             stage_worker::start(config.prover_addrs.len(), tls_config.clone(), db.clone()).await;
-        Ok(StageServiceSVC { db, config })
+        if let Some(retention) = config.retention.clone() {
+            gc::start(retention, &config.base_dir, tls_config.clone(), db.clone());
+        }
+        let concurrency = config
+            .execute
+            .as_ref()
+            .and_then(|execute| execute.concurrency)
+            .unwrap_or(DEFAULT_EXECUTE_CONCURRENCY);
+        Ok(StageServiceSVC {
+            db,
+            config,
+            executions: Arc::new(Semaphore::new(concurrency)),
+        })
     }
 
     pub fn verify_signature(&self, request: &GenerateProofRequest) -> Result<String, Error> {
@@ -69,12 +115,114 @@ impl StageServiceSVC {
                 format!("{}&{}", request.proof_id, request.seg_size)
             }
         };
-        let signature = Signature::from_str(&request.signature)?;
-        let recovered = signature.recover(sign_data)?;
-        Ok(format!("{:?}", recovered))
+        recover_address(&sign_data, &request.signature)
+    }
+
+    // Programs are run or set up by the stage up to `[execute] concurrency` at once.
+    #[allow(clippy::result_large_err)]
+    fn execute_slot(&self) -> Result<ExecuteSlot, Status> {
+        if shutdown::is_draining() {
+            return Err(Status::unavailable("the stage is draining"));
+        }
+        let permit = self
+            .executions
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Status::resource_exhausted("too many programs are running"))?;
+        Ok(ExecuteSlot {
+            _permit: permit,
+            _guard: shutdown::track(),
+        })
+    }
+
+    /// Run the program of `request`, refused once `[execute] concurrency` programs are running.
+    async fn execute_program(&self, request: &ExecuteRequest) -> Result<ExecuteResponse, Status> {
+        let slot = self.execute_slot()?;
+        let max_cycles = self.config.execute.as_ref().and_then(|e| e.max_cycles);
+        execute_program(&self.config.base_dir, max_cycles, request, slot)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    /// The reason to refuse to run the program of `request`, None if its signer is whitelisted.
+    async fn check_execute_signature(
+        &self,
+        request: &ExecuteRequest,
+    ) -> Result<Option<&'static str>, Status> {
+        self.check_signature(
+            "execute",
+            &request.proof_id,
+            &execute_sign_data(request),
+            &request.signature,
+        )
+        .await
+    }
+
+    /// The reason the request of `method` is refused, if its signature over `sign_data` is not of
+    /// a user.
+    async fn check_signature(
+        &self,
+        method: &str,
+        proof_id: &str,
+        sign_data: &str,
+        signature: &str,
+    ) -> Result<Option<&'static str>, Status> {
+        let address = match recover_address(sign_data, signature) {
+            Ok(address) => address,
+            Err(e) => {
+                tracing::warn!("[{}] {} invalid signature {:?}", method, proof_id, e);
+                return Ok(Some("invalid signature"));
+            }
+        };
+        let users = self
+            .db
+            .get_user(&address)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if users.is_empty() {
+            tracing::warn!("[{}] {} permission denied", method, proof_id);
+            return Ok(Some("permission denied"));
+        }
+        Ok(None)
     }
 }
 
+/// The data signed for an `ExecuteRequest`, the program and its inputs are covered by their
+/// sha256: `{proof_id}&{elf}&{private_input_stream}&{receipt_input}...`.
+pub fn execute_sign_data(request: &ExecuteRequest) -> String {
+    let mut parts = vec![
+        request.proof_id.clone(),
+        file::digest(&request.elf_data),
+        file::digest(&request.private_input_stream),
+    ];
+    parts.extend(
+        request
+            .receipt_inputs
+            .iter()
+            .map(|input| file::digest(input)),
+    );
+    parts.join("&")
+}
+
+/// The data signed for a `VerifyProofRequest`: `{proof_id}&{proof}&{vk}&{elf}&{public_values}`,
+/// by their sha256.
+pub fn verify_sign_data(request: &VerifyProofRequest) -> String {
+    [
+        request.proof_id.clone(),
+        file::digest(&request.proof),
+        file::digest(&request.vk),
+        file::digest(&request.elf_data),
+        file::digest(&request.public_values),
+    ]
+    .join("&")
+}
+
+fn recover_address(sign_data: &str, signature: &str) -> Result<String, Error> {
+    let signature = Signature::from_str(signature)?;
+    let recovered = signature.recover(sign_data)?;
+    Ok(format!("{:?}", recovered))
+}
+
 #[tonic::async_trait]
 impl StageService for StageServiceSVC {
     async fn get_status(
@@ -90,6 +238,14 @@ impl StageService for StageServiceSVC {
             if let Ok(task) = task {
                 response.status = task.status;
                 response.step = task.step;
+                if task.status == crate::proto::stage_service::v1::Status::Success as i32 {
+                    response.attestation = self
+                        .db
+                        .get_attestation(&request.get_ref().proof_id)
+                        .await
+                        .map_err(|e| Status::internal(e.to_string()))?
+                        .and_then(|attestation| serde_json::from_str(&attestation).ok());
+                }
                 let execute_info: Vec<tasks::SplitTask> = self
                     .db
                     .get_prove_task_infos(&request.get_ref().proof_id, tasks::TASK_ITYPE_SPLIT)
@@ -107,12 +263,17 @@ impl StageService for StageServiceSVC {
                                 == crate::proto::stage_service::v1::Status::Success as i32
                                 && !context.output_stream_path.is_empty()
                             {
-                                let output_data =
-                                    file::new(&context.output_stream_path).read().unwrap();
+                                let output_data = file::new_async(&context.output_stream_path)
+                                    .read()
+                                    .await
+                                    .map_err(|e| Status::internal(e.to_string()))?;
                                 response.output_stream.clone_from(&output_data);
                                 if context.composite_proof {
                                     let receipts_path = format!("{}/receipt/0", context.prove_path);
-                                    let receipts_data = file::new(&receipts_path).read().unwrap();
+                                    let receipts_data = file::new_async(&receipts_path)
+                                        .read()
+                                        .await
+                                        .map_err(|e| Status::internal(e.to_string()))?;
                                     response.receipt = receipts_data;
                                 }
                             }
@@ -130,7 +291,10 @@ impl StageService for StageServiceSVC {
                 if target_step != Step::Split && !composite_proof {
                     if let Some(result) = task.result {
                         response.proof_with_public_inputs = if target_step == Step::Agg {
-                            file::new(&proof_path).read().unwrap()
+                            file::new_async(&proof_path)
+                                .read()
+                                .await
+                                .map_err(|e| Status::internal(e.to_string()))?
                         } else {
                             result.into_bytes()
                         };
@@ -170,6 +334,187 @@ impl StageService for StageServiceSVC {
         .await
     }
 
+    async fn get_proof_trace(
+        &self,
+        request: Request<GetProofTraceRequest>,
+    ) -> tonic::Result<Response<GetProofTraceResponse>, Status> {
+        metrics::record_metrics("stage::get_proof_trace", || async {
+            let proof_id = &request.get_ref().proof_id;
+            let events = self
+                .db
+                .get_stage_events(proof_id)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            let response = GetProofTraceResponse {
+                proof_id: proof_id.clone(),
+                events: events
+                    .into_iter()
+                    .map(|event| ProofEvent {
+                        step: event.step,
+                        task_id: event.task_id,
+                        node: event.node,
+                        state: event.state as u32,
+                        duration: event.duration as u64,
+                        message: event.message,
+                        timestamp: event.ts as u64,
+                        retries: event.retries as u32,
+                    })
+                    .collect(),
+            };
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn get_execution_report(
+        &self,
+        request: Request<GetExecutionReportRequest>,
+    ) -> tonic::Result<Response<GetExecutionReportResponse>, Status> {
+        metrics::record_metrics("stage::get_execution_report", || async {
+            let proof_id = &request.get_ref().proof_id;
+            let report = self
+                .db
+                .get_execution_report(proof_id)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or_else(|| Status::not_found(format!("no execution report of {}", proof_id)))?;
+            let report =
+                serde_json::from_str(&report).map_err(|e| Status::internal(e.to_string()))?;
+            Ok(Response::new(GetExecutionReportResponse {
+                proof_id: proof_id.clone(),
+                report: Some(report),
+            }))
+        })
+        .await
+    }
+
+    async fn execute(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> tonic::Result<Response<ExecuteResponse>, Status> {
+        metrics::record_metrics("stage::execute", || async {
+            let request = request.get_ref();
+            if let Some(error_message) = self.check_execute_signature(request).await? {
+                return Ok(Response::new(ExecuteResponse {
+                    proof_id: request.proof_id.clone(),
+                    status: InvalidParameter.into(),
+                    error_message: error_message.to_string(),
+                    ..Default::default()
+                }));
+            }
+            let response = self.execute_program(request).await?;
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn estimate_proof(
+        &self,
+        request: Request<EstimateProofRequest>,
+    ) -> tonic::Result<Response<EstimateProofResponse>, Status> {
+        metrics::record_metrics("stage::estimate_proof", || async {
+            let request = request.get_ref();
+            let execute = request.execute.clone().unwrap_or_default();
+            let mut response = EstimateProofResponse {
+                proof_id: execute.proof_id.clone(),
+                ..Default::default()
+            };
+            let target_step = request.target_step.unwrap_or(Step::Snark.into());
+            let error_message = if ![Step::Split, Step::Agg, Step::Snark]
+                .iter()
+                .any(|step| *step as i32 == target_step)
+            {
+                Some("invalid TargetStep, only Support Split, Agg and Snark")
+            } else {
+                self.check_execute_signature(&execute).await?
+            };
+            if let Some(error_message) = error_message {
+                response.status = InvalidParameter.into();
+                response.error_message = error_message.to_string();
+                return Ok(Response::new(response));
+            }
+            let target_step = Step::from_i32(target_step).unwrap();
+
+            let execution = self.execute_program(&execute).await?;
+            response.status = execution.status;
+            response.error_message = execution.error_message;
+            response.exit_code = execution.exit_code;
+            response.total_cycles = execution.total_cycles;
+            if response.status != crate::proto::stage_service::v1::Status::Success as i32
+                || response.exit_code != 0
+            {
+                return Ok(Response::new(response));
+            }
+
+            let layout = estimate::layout(response.total_cycles, request.seg_size);
+            let timings = estimate::TaskTimings::load(&self.db, target_step)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            let nodes = prover_node::instance().lock().unwrap().get_nodes().len() as u64;
+            let estimate = estimate::Estimate::new(
+                response.total_cycles,
+                &layout,
+                &timings,
+                nodes,
+                target_step,
+            );
+            response.segments = layout.segments;
+            response.aggregation_depth = layout.agg_layers.len() as u32;
+            response.node_seconds = estimate.node_seconds.ceil() as u64;
+            response.wall_clock_seconds = estimate.wall_clock_seconds.ceil() as u64;
+            response.nodes = nodes as u32;
+            response.samples = timings.samples as u32;
+            tracing::info!(
+                "[estimate_proof] {} segments: {} node seconds: {} wall clock seconds: {}",
+                response.proof_id,
+                response.segments,
+                response.node_seconds,
+                response.wall_clock_seconds
+            );
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn verify_proof(
+        &self,
+        request: Request<VerifyProofRequest>,
+    ) -> tonic::Result<Response<VerifyProofResponse>, Status> {
+        metrics::record_metrics("stage::verify_proof", || async {
+            let request = request.get_ref();
+            if let Some(error_message) = self
+                .check_signature(
+                    "verify_proof",
+                    &request.proof_id,
+                    &verify_sign_data(request),
+                    &request.signature,
+                )
+                .await?
+            {
+                return Ok(Response::new(VerifyProofResponse {
+                    proof_id: request.proof_id.clone(),
+                    status: InvalidParameter.into(),
+                    error_message: error_message.to_string(),
+                    ..Default::default()
+                }));
+            }
+            // the Groth16 proofs are verified with the artifacts of the proving key path of prover_v2
+            let proving_key_path = self
+                .config
+                .proving_key_paths
+                .get(ProverVersion::Zkm2 as usize)
+                .cloned()
+                .unwrap_or_default();
+            // the vk may be set up from the elf, as costly as running the program
+            let slot = self.execute_slot()?;
+            let response = verify::verify_proof(&proving_key_path, request, slot)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
     async fn generate_proof(
         &self,
         request: Request<GenerateProofRequest>,
@@ -266,145 +611,30 @@ impl StageService for StageServiceSVC {
             }
             let target_step = Step::from_i32(target_step).unwrap();
 
-            let base_dir = self.config.base_dir.clone();
-            let dir_path = format!("{}/proof/{}", base_dir, request.get_ref().proof_id);
-            file::new(&dir_path)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-
-            let elf_path = format!("{}/elf", dir_path);
-            file::new(&elf_path)
-                .write(&request.get_ref().elf_data)
-                .map_err(|e| Status::internal(e.to_string()))?;
-
-            let block_no = request.get_ref().block_no.unwrap_or(0u64);
-            let block_dir = format!("{}/0_{}", dir_path, block_no);
-            file::new(&block_dir)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-
-            for file_block_item in &request.get_ref().block_data {
-                let block_path = format!("{}/{}", block_dir, file_block_item.file_name);
-                file::new(&block_path)
-                    .write(&file_block_item.file_content)
-                    .map_err(|e| Status::internal(e.to_string()))?;
+            if let Some(callback_url) = &request.get_ref().callback_url {
+                if !notify::is_valid_callback(callback_url) {
+                    let response = GenerateProofResponse {
+                        proof_id: request.get_ref().proof_id.clone(),
+                        status: InvalidParameter.into(),
+                        error_message: "invalid callback_url, only support http and https"
+                            .to_string(),
+                        ..Default::default()
+                    };
+                    tracing::warn!(
+                        "[generate_proof] {} invalid callback_url {}",
+                        request.get_ref().proof_id,
+                        callback_url,
+                    );
+                    return Ok(Response::new(response));
+                }
             }
 
-            let input_stream_dir = format!("{}/input_stream", dir_path);
-            file::new(&input_stream_dir)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-            let public_input_stream_path = if request.get_ref().public_input_stream.is_empty() {
-                "".to_string()
-            } else {
-                let public_input_stream_path = format!("{}/{}", input_stream_dir, "public_input");
-                file::new(&public_input_stream_path)
-                    .write(&request.get_ref().public_input_stream)
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                public_input_stream_path
-            };
-
-            let private_input_stream_path = if request.get_ref().private_input_stream.is_empty() {
-                "".to_string()
-            } else {
-                let private_input_stream_path = format!("{}/{}", input_stream_dir, "private_input");
-                file::new(&private_input_stream_path)
-                    .write(&request.get_ref().private_input_stream)
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                private_input_stream_path
-            };
-
-            let receipt_inputs_path = if request.get_ref().receipt_inputs.is_empty() {
-                "".to_string()
-            } else {
-                let receipt_inputs_path = format!("{}/{}", input_stream_dir, "receipt_inputs");
-                let mut buf = Vec::new();
-                bincode::serialize_into(&mut buf, &request.get_ref().receipt_inputs)
-                    .expect("serialization failed");
-                file::new(&receipt_inputs_path)
-                    .write(&buf)
-                    .map_err(|e| Status::internal(e.to_string()))?;
-                receipt_inputs_path
-            };
-
-            let receipts_path = if request.get_ref().receipts.is_empty() {
-                "".to_string()
-            } else {
-                let receipts_path = format!("{}/{}", input_stream_dir, "receipts");
-                let mut buf = Vec::new();
-                bincode::serialize_into(&mut buf, &request.get_ref().receipts)
-                    .expect("serialization failed");
-                file::new(&receipts_path)
-                    .write(&buf)
+            let mut generate_task =
+                prepare_generate_task(&self.config.base_dir, request.get_ref(), target_step)
+                    .await
                     .map_err(|e| Status::internal(e.to_string()))?;
-                receipts_path
-            };
-
-            let output_stream_dir = format!("{}/output_stream", dir_path);
-            file::new(&output_stream_dir)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-
-            let output_stream_path = if cfg!(feature = "prover") {
-                format!("{}/{}", output_stream_dir, "output_stream")
-            } else {
-                String::new()
-            };
-
-            let seg_path = format!("{}/segment", dir_path);
-            file::new(&seg_path)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-
-            let prove_path = format!("{}/prove", dir_path);
-
-            let prove_receipt_path = format!("{}/receipt", prove_path);
-            file::new(&prove_receipt_path)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-
-            let agg_path = format!("{}/aggregate", dir_path);
-            let wrap_dir = format!("{}/wrap", dir_path);
-            file::new(&wrap_dir)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-            let snark_dir = format!("{}/snark", dir_path);
-            file::new(&snark_dir)
-                .create_dir_all()
-                .map_err(|e| Status::internal(e.to_string()))?;
-            let snark_path = format!("{}/proof_with_public_inputs.json", snark_dir);
-
-            let prover_version = if cfg!(feature = "prover") {
-                ProverVersion::Zkm
-            } else if cfg!(feature = "prover_v2") {
-                ProverVersion::Zkm2
-            } else {
-                return Err(Status::internal("ProverVersion error"));
-            };
-            // compute program id
-            let mut hasher = Sha256::new();
-            hasher.update(&request.get_ref().elf_data);
-            let elf_hash = hasher.finalize();
-            let generate_task = GenerateTask::new(
-                prover_version,
-                hex::encode(elf_hash),
-                &request.get_ref().proof_id,
-                &dir_path,
-                &elf_path,
-                &seg_path,
-                &prove_path,
-                &agg_path,
-                &snark_path,
-                &public_input_stream_path,
-                &private_input_stream_path,
-                &output_stream_path,
-                Some(block_no),
-                request.get_ref().seg_size,
-                target_step,
-                request.get_ref().composite_proof,
-                &receipt_inputs_path,
-                &receipts_path,
-            );
+            generate_task.segment_transfer = self.config.segment_transfer.unwrap_or(false);
+            generate_task.callback_url = request.get_ref().callback_url.clone().unwrap_or_default();
 
             let _ = self
                 .db
@@ -467,3 +697,319 @@ impl StageService for StageServiceSVC {
         .await
     }
 }
+
+/// Run the program of the request in this process, for at most `max_cycles`. The inputs are
+/// written under `{base_dir}/execute` for the emulator, and removed once it has run.
+#[cfg(feature = "prover_v2")]
+async fn execute_program(
+    base_dir: &str,
+    max_cycles: Option<u64>,
+    request: &ExecuteRequest,
+    slot: ExecuteSlot,
+) -> anyhow::Result<ExecuteResponse> {
+    let dir = format!("{}/execute/{}", base_dir, uuid::Uuid::new_v4());
+    let output = run_program(&dir, max_cycles, request, slot).await;
+    if let Err(e) = file::new_async(&dir).remove_all().await {
+        tracing::warn!("[execute] remove {} failed: {:?}", dir, e);
+    }
+    let mut response = ExecuteResponse {
+        proof_id: request.proof_id.clone(),
+        ..Default::default()
+    };
+    match output? {
+        Ok(output) => {
+            tracing::info!(
+                "[execute] {} exit code: {} total cycles: {}",
+                request.proof_id,
+                output.exit_code,
+                output.report.total_cycles
+            );
+            response.status = Success.into();
+            response.exit_code = output.exit_code;
+            response.total_cycles = output.report.total_cycles;
+            response.public_values = output.public_values;
+            response.report = Some(ExecutionReport {
+                total_cycles: output.report.total_cycles,
+                total_syscalls: output.report.total_syscalls,
+                touched_memory_addresses: output.report.touched_memory_addresses,
+                opcode_counts: output.report.opcode_counts.into_iter().collect(),
+                syscall_counts: output.report.syscall_counts.into_iter().collect(),
+            });
+        }
+        // the program could not run, e.g. the elf is invalid or it hit an unsupported syscall
+        Err(e) => {
+            tracing::warn!("[execute] {} failed: {}", request.proof_id, e);
+            response.status = InvalidParameter.into();
+            response.error_message = e;
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(feature = "prover_v2")]
+async fn run_program(
+    dir: &str,
+    max_cycles: Option<u64>,
+    request: &ExecuteRequest,
+    slot: ExecuteSlot,
+) -> anyhow::Result<Result<ExecuteOutput, String>> {
+    file::new_async(dir).create_dir_all().await?;
+    file::enable_encryption(&[dir]).await?;
+    let elf_path = format!("{}/elf", dir);
+    file::new_async(&elf_path).write(&request.elf_data).await?;
+    let private_input_path = format!("{}/private_input", dir);
+    let private_input = if request.private_input_stream.is_empty() {
+        bincode::serialize(&Vec::<Vec<u8>>::new())?
+    } else {
+        request.private_input_stream.clone()
+    };
+    file::new_async(&private_input_path)
+        .write(&private_input)
+        .await?;
+    let receipt_inputs_path = if request.receipt_inputs.is_empty() {
+        "".to_string()
+    } else {
+        let receipt_inputs_path = format!("{}/receipt_inputs", dir);
+        file::new_async(&receipt_inputs_path)
+            .write(&bincode::serialize(&request.receipt_inputs)?)
+            .await?;
+        receipt_inputs_path
+    };
+    let ctx = SplitContext {
+        elf_path,
+        private_input_path,
+        receipt_inputs_path,
+        max_cycles,
+        ..Default::default()
+    };
+    let output = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        Executor::default().execute(&ctx).map_err(|e| e.to_string())
+    })
+    .await?;
+    Ok(output)
+}
+
+#[cfg(not(feature = "prover_v2"))]
+async fn execute_program(
+    _base_dir: &str,
+    _max_cycles: Option<u64>,
+    request: &ExecuteRequest,
+    _slot: ExecuteSlot,
+) -> anyhow::Result<ExecuteResponse> {
+    Ok(ExecuteResponse {
+        proof_id: request.proof_id.clone(),
+        status: crate::proto::stage_service::v1::Status::InternalError.into(),
+        error_message: "execute is only supported by prover_v2".to_string(),
+        ..Default::default()
+    })
+}
+
+/// Lay out `{base_dir}/proof/{proof_id}` for the request, and build the context of its stage task.
+pub async fn prepare_generate_task(
+    base_dir: &str,
+    request: &GenerateProofRequest,
+    target_step: Step,
+) -> anyhow::Result<GenerateTask> {
+    let dir_path = format!("{}/proof/{}", base_dir, request.proof_id);
+    file::new_async(&dir_path).create_dir_all().await?;
+
+    // The inputs shared by many proofs are stored once, by digest.
+    let artifacts_dir = format!("{}/artifacts", base_dir);
+    let elf_path = file::put_content_addressed(&artifacts_dir, &request.elf_data).await?;
+
+    let block_no = request.block_no.unwrap_or(0u64);
+    let block_dir = format!("{}/0_{}", dir_path, block_no);
+    file::new_async(&block_dir).create_dir_all().await?;
+
+    for file_block_item in &request.block_data {
+        let block_path = format!("{}/{}", block_dir, file_block_item.file_name);
+        file::new_async(&block_path)
+            .write(&file_block_item.file_content)
+            .await?;
+    }
+
+    let input_stream_dir = format!("{}/input_stream", dir_path);
+    file::new_async(&input_stream_dir).create_dir_all().await?;
+    let output_stream_dir = format!("{}/output_stream", dir_path);
+    file::new_async(&output_stream_dir).create_dir_all().await?;
+    // the inputs and outputs of the proof are encrypted at rest if a master key is configured
+    file::enable_encryption(&[&input_stream_dir, &output_stream_dir]).await?;
+
+    let public_input_stream_path = if request.public_input_stream.is_empty() {
+        "".to_string()
+    } else {
+        let public_input_stream_path = format!("{}/{}", input_stream_dir, "public_input");
+        file::new_async(&public_input_stream_path)
+            .write(&request.public_input_stream)
+            .await?;
+        public_input_stream_path
+    };
+
+    let private_input_stream_path = if request.private_input_stream.is_empty() {
+        "".to_string()
+    } else {
+        let private_input_stream_path = format!("{}/{}", input_stream_dir, "private_input");
+        file::new_async(&private_input_stream_path)
+            .write(&request.private_input_stream)
+            .await?;
+        private_input_stream_path
+    };
+
+    let receipt_inputs_path = if request.receipt_inputs.is_empty() {
+        "".to_string()
+    } else {
+        let mut buf = Vec::new();
+        bincode::serialize_into(&mut buf, &request.receipt_inputs).expect("serialization failed");
+        if envelope::is_enabled() {
+            // encrypted under the key of this proof, so not shared with the other proofs
+            let receipt_inputs_path = format!("{}/{}", input_stream_dir, "receipt_inputs");
+            file::new_async(&receipt_inputs_path).write(&buf).await?;
+            receipt_inputs_path
+        } else {
+            file::put_content_addressed(&artifacts_dir, &buf).await?
+        }
+    };
+
+    let receipts_path = if request.receipts.is_empty() {
+        "".to_string()
+    } else {
+        let receipts_path = format!("{}/{}", input_stream_dir, "receipts");
+        let mut buf = Vec::new();
+        bincode::serialize_into(&mut buf, &request.receipts).expect("serialization failed");
+        file::new_async(&receipts_path).write(&buf).await?;
+        receipts_path
+    };
+
+    let output_stream_path = if cfg!(feature = "prover") {
+        format!("{}/{}", output_stream_dir, "output_stream")
+    } else {
+        String::new()
+    };
+
+    let seg_path = format!("{}/segment", dir_path);
+    file::new_async(&seg_path).create_dir_all().await?;
+
+    let prove_path = format!("{}/prove", dir_path);
+
+    let prove_receipt_path = format!("{}/receipt", prove_path);
+    file::new_async(&prove_receipt_path)
+        .create_dir_all()
+        .await?;
+
+    let agg_path = format!("{}/aggregate", dir_path);
+    let wrap_dir = format!("{}/wrap", dir_path);
+    file::new_async(&wrap_dir).create_dir_all().await?;
+    let snark_dir = format!("{}/snark", dir_path);
+    file::new_async(&snark_dir).create_dir_all().await?;
+    let snark_path = format!("{}/proof_with_public_inputs.json", snark_dir);
+
+    let prover_version = if cfg!(feature = "prover") {
+        ProverVersion::Zkm
+    } else if cfg!(feature = "prover_v2") {
+        ProverVersion::Zkm2
+    } else {
+        anyhow::bail!("ProverVersion error");
+    };
+    // compute program id
+    let mut hasher = Sha256::new();
+    hasher.update(&request.elf_data);
+    let elf_hash = hasher.finalize();
+    let generate_task = GenerateTask::new(
+        prover_version,
+        hex::encode(elf_hash),
+        &request.proof_id,
+        &dir_path,
+        &elf_path,
+        &seg_path,
+        &prove_path,
+        &agg_path,
+        &snark_path,
+        &public_input_stream_path,
+        &private_input_stream_path,
+        &output_stream_path,
+        Some(block_no),
+        request.seg_size,
+        target_step,
+        request.composite_proof,
+        &receipt_inputs_path,
+        &receipts_path,
+    );
+    Ok(generate_task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prepare_generate_task() {
+        let base_dir = "mem://test_prepare_generate_task";
+        let request = GenerateProofRequest {
+            proof_id: "p1".to_string(),
+            elf_data: vec![1, 2, 3],
+            private_input_stream: vec![4, 5],
+            seg_size: 1024,
+            ..Default::default()
+        };
+        let task = prepare_generate_task(base_dir, &request, Step::Snark)
+            .await
+            .unwrap();
+
+        let proof_dir = format!("{}/proof/p1", base_dir);
+        assert_eq!(task.base_dir, proof_dir);
+        assert_eq!(task.seg_path, format!("{}/segment", proof_dir));
+        assert!(task.public_input_path.is_empty());
+        assert_eq!(
+            file::new_async(&task.private_input_path)
+                .read()
+                .await
+                .unwrap(),
+            vec![4, 5]
+        );
+        assert_eq!(
+            file::new_async(&task.elf_path).read().await.unwrap(),
+            vec![1, 2, 3]
+        );
+        let mut dirs = file::new_async(&proof_dir).read_dir().await.unwrap();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            [
+                "0_0",
+                "input_stream",
+                "output_stream",
+                "prove",
+                "segment",
+                "snark",
+                "wrap"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_sign_data() {
+        let request = ExecuteRequest {
+            proof_id: "p1".to_string(),
+            elf_data: vec![1, 2, 3],
+            private_input_stream: vec![4, 5],
+            receipt_inputs: vec![vec![6]],
+            ..Default::default()
+        };
+        let sign_data = execute_sign_data(&request);
+        assert_eq!(
+            sign_data,
+            format!(
+                "p1&{}&{}&{}",
+                file::digest(&[1, 2, 3]),
+                file::digest(&[4, 5]),
+                file::digest(&[6])
+            )
+        );
+        let other = ExecuteRequest {
+            elf_data: vec![7],
+            ..request
+        };
+        assert_ne!(execute_sign_data(&other), sign_data);
+    }
+}
@@ -1,10 +1,18 @@
+use crate::attestation;
 use crate::database;
 use crate::database::StageTask;
+use crate::lease::{self, LEASE_TTL};
+use crate::metrics;
+use crate::notify::{self, Notification};
 use crate::prover_client;
+use crate::shutdown;
 use crate::stage::{
     stage::get_timestamp,
     stage::Stage,
-    tasks::{Task, TASK_ITYPE_FINAL, TASK_ITYPE_SPLIT, TASK_STATE_FAILED, TASK_STATE_SUCCESS},
+    tasks::{
+        save_task, SplitTask, Task, Trace, TASK_ITYPE_FINAL, TASK_ITYPE_SPLIT, TASK_STATE_FAILED,
+        TASK_STATE_SUCCESS,
+    },
     GenerateTask,
 };
 use crate::TlsConfig;
@@ -13,35 +21,148 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::time;
+use tracing::Instrument;
 
-use crate::proto::includes::v1::Step;
-use crate::proto::stage_service;
+use crate::proto::includes::v1::{ProverVersion, Step};
+use crate::proto::stage_service::{self, v1::Attestation};
 
-macro_rules! save_task {
-    ($task:ident, $db_pool:ident, $type:expr) => {
+// Record a step change, or a task result into the trace of the proof.
+async fn record_event(
+    db: &database::Database,
+    proof_id: &str,
+    step: Step,
+    task_id: &str,
+    trace: &Trace,
+    state: u32,
+    retries: u32,
+) {
+    let now = get_timestamp();
+    // a rescheduled task has not finished
+    let finish_ts = if trace.finish_ts >= trace.start_ts {
+        trace.finish_ts
+    } else {
+        now
+    };
+    let event = database::StageEvent {
+        proof_id: proof_id.to_string(),
+        step: step.into(),
+        task_id: task_id.to_string(),
+        node: trace.node_info.clone(),
+        state: state as i32,
+        duration: finish_ts.saturating_sub(trace.start_ts) as i64,
+        message: trace.message.clone(),
+        ts: now as i64,
+        retries: retries as i32,
+    };
+    if let Err(e) = db.insert_stage_event(&event).await {
+        tracing::error!("save event error: {:?}", e)
+    }
+}
+
+// The report is also in the proof directory, written by the split node or fetched from it.
+async fn save_execution_report(db: &database::Database, split_task: &SplitTask) {
+    let Some(report) = &split_task.execution_report else {
+        return;
+    };
+    if split_task.state != TASK_STATE_SUCCESS {
+        return;
+    }
+    let result = match serde_json::to_string(report) {
+        Ok(json) => {
+            db.save_execution_report(&split_task.proof_id, report.total_cycles, &json)
+                .await
+        }
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        tracing::error!("save execution report error: {:?}", e)
+    }
+}
+
+// Sign the summary of a successful proof, the proof is the one `GetStatus` returns.
+async fn attest(
+    generate_context: &GenerateTask,
+    finished_at: u64,
+) -> anyhow::Result<Option<Attestation>> {
+    let suffix = match generate_context.version {
+        ProverVersion::Zkm => "json",
+        _ => "bin",
+    };
+    let public_values_path = format!(
+        "{}/wrap/public_values.{}",
+        generate_context.base_dir, suffix
+    );
+    let public_values = file::new_async(&public_values_path).read().await?;
+    let proof_path = if generate_context.composite_proof {
+        format!("{}/receipt/0", generate_context.prove_path)
+    } else if generate_context.target_step == Step::Split {
+        String::new()
+    } else {
+        generate_context.snark_path.clone()
+    };
+    let proof = if proof_path.is_empty() {
+        vec![]
+    } else {
+        file::new_async(&proof_path).read().await?
+    };
+    attestation::attest(
+        &generate_context.proof_id,
+        &generate_context.program_id,
+        &public_values,
+        &proof,
+        finished_at,
+    )
+    .await
+}
+
+// The attestation is saved before the task is marked as succeeded, so a successful task always
+// has it once the signing is configured. A proof whose outputs can't be read is not signed.
+async fn save_attestation(
+    db: &database::Database,
+    generate_context: &GenerateTask,
+    finished_at: u64,
+) -> Option<Attestation> {
+    let result = match attest(generate_context, finished_at).await {
+        Ok(Some(attestation)) => match serde_json::to_string(&attestation) {
+            Ok(json) => db
+                .save_attestation(&generate_context.proof_id, &json)
+                .await
+                .map(|_| Some(attestation)),
+            Err(e) => Err(e.into()),
+        },
+        result => result,
+    };
+    result
+        .map_err(|e| {
+            tracing::error!(
+                "save attestation of {} error: {:?}",
+                generate_context.proof_id,
+                e
+            )
+        })
+        .ok()
+        .flatten()
+}
+
+// Only the ended tasks are recorded, with the times they were rescheduled, e.g. while no node
+// was idle.
+macro_rules! record_task {
+    ($task:ident, $db:ident, $retries:ident, $proof_id:expr, $version:expr, $step:expr) => {
+        metrics::observe_task($step, $version, &$task.trace, $task.state);
         if $task.state == TASK_STATE_FAILED || $task.state == TASK_STATE_SUCCESS {
-            tracing::info!(
-                "begin to save task: {:?}:{:?} type {:?} status {}",
-                $task.proof_id,
-                $task.task_id,
-                $type,
-                $task.state
-            );
-            // TODO: should remove the content from database, store it by FS.
-            let content = serde_json::to_string(&$task).unwrap();
-            let prove_task = database::ProveTask {
-                id: $task.task_id,
-                itype: $type,
-                proof_id: $task.proof_id,
-                status: $task.state as i32,
-                node_info: $task.trace.node_info.clone(),
-                content: Some(content),
-                time_cost: ($task.trace.duration()) as i64,
-                ..Default::default()
-            };
-            if let Err(e) = $db_pool.insert_prove_task(&prove_task).await {
-                tracing::error!("save task error: {:?}", e)
-            }
+            let retries = $retries.remove(&$task.task_id).unwrap_or_default();
+            record_event(
+                &$db,
+                &$proof_id,
+                $step,
+                &$task.task_id,
+                &$task.trace,
+                $task.state,
+                retries,
+            )
+            .await
+        } else {
+            *$retries.entry($task.task_id.clone()).or_default() += 1;
         }
     };
 }
@@ -57,38 +178,78 @@ async fn run_stage_task(
         match task_decoded {
             Ok(generate_context) => {
                 let mut check_at = get_timestamp();
+                let started_at = check_at;
+                let proof_id = task.id.clone();
+                let version = generate_context.version.as_str_name();
+                let mut pending = metrics::PendingTasks::default();
+                let mut retries: HashMap<String, u32> = HashMap::new();
+                let mut step_trace = Trace {
+                    start_ts: check_at,
+                    ..Default::default()
+                };
                 let mut stage = Stage::new(generate_context.clone());
                 let (tx, mut rx) = tokio::sync::mpsc::channel(128);
                 stage.dispatch();
                 let mut interval = time::interval(time::Duration::from_millis(200));
+                // cloned by each rpc in flight
+                let outstanding = Arc::new(());
                 loop {
+                    // On drain no task is sent anymore, and the task is released once the
+                    // responses of the nodes are in, so that their work is not lost.
+                    let draining = shutdown::is_draining();
+                    if draining && Arc::strong_count(&outstanding) == 1 {
+                        // Release the task instead of failing it, so that another stage replica
+                        // can pick it up right away.
+                        let _ = db
+                            .update_stage_task_check_at(
+                                &task.id,
+                                task.check_at as u64,
+                                0,
+                                stage.step.into(),
+                            )
+                            .await;
+                        let _ = lease::release(&db, &lease::stage_task_lease(&task.id)).await;
+                        tracing::info!("[stage] {} released on drain", task.id);
+                        return;
+                    }
                     let current_step = stage.step;
                     match stage.step {
+                        _ if draining => {}
                         Step::Prove => {
                             let split_task = stage.get_split_task();
                             if let Some(split_task) = split_task {
                                 let tx = tx.clone();
                                 let tls_config = tls_config.clone();
-                                tokio::spawn(async move {
-                                    let response =
-                                        prover_client::split(split_task, tls_config).await;
-                                    if let Some(split_task) = response {
-                                        let _ = tx.send(Task::Split(split_task)).await;
+                                let inflight = (shutdown::track(), outstanding.clone());
+                                tokio::spawn(
+                                    async move {
+                                        let _inflight = inflight;
+                                        let response =
+                                            prover_client::split(split_task, tls_config).await;
+                                        if let Some(split_task) = response {
+                                            let _ = tx.send(Task::Split(split_task)).await;
+                                        }
                                     }
-                                });
+                                    .in_current_span(),
+                                );
                             }
                             // This is a temporary workaround.
                             if stage.count_processing_prove_tasks() < node_num {
                                 if let Some(prove_task) = stage.get_prove_task() {
                                     let tx = tx.clone();
                                     let tls_config = tls_config.clone();
-                                    tokio::spawn(async move {
-                                        let response =
-                                            prover_client::prove(prove_task, tls_config).await;
-                                        if let Some(prove_task) = response {
-                                            let _ = tx.send(Task::Prove(prove_task)).await;
+                                    let inflight = (shutdown::track(), outstanding.clone());
+                                    tokio::spawn(
+                                        async move {
+                                            let _inflight = inflight;
+                                            let response =
+                                                prover_client::prove(prove_task, tls_config).await;
+                                            if let Some(prove_task) = response {
+                                                let _ = tx.send(Task::Prove(prove_task)).await;
+                                            }
                                         }
-                                    });
+                                        .in_current_span(),
+                                    );
                                 }
                             }
 
@@ -100,13 +261,19 @@ async fn run_stage_task(
                                 if let Some(agg_task) = agg_task {
                                     let tx = tx.clone();
                                     let tls_config = tls_config.clone();
-                                    tokio::spawn(async move {
-                                        let response =
-                                            prover_client::aggregate(agg_task, tls_config).await;
-                                        if let Some(agg_task) = response {
-                                            let _ = tx.send(Task::Agg(agg_task)).await;
+                                    let inflight = (shutdown::track(), outstanding.clone());
+                                    tokio::spawn(
+                                        async move {
+                                            let _inflight = inflight;
+                                            let response =
+                                                prover_client::aggregate(agg_task, tls_config)
+                                                    .await;
+                                            if let Some(agg_task) = response {
+                                                let _ = tx.send(Task::Agg(agg_task)).await;
+                                            }
                                         }
-                                    });
+                                        .in_current_span(),
+                                    );
                                 }
                             }
                         }
@@ -115,35 +282,49 @@ async fn run_stage_task(
                             if let Some(snark_task) = snark_task {
                                 let tx = tx.clone();
                                 let tls_config = tls_config.clone();
-                                tokio::spawn(async move {
-                                    let response =
-                                        prover_client::snark_proof(snark_task, tls_config).await;
-                                    if let Some(snark_task) = response {
-                                        let _ = tx.send(Task::Snark(snark_task)).await;
+                                let inflight = (shutdown::track(), outstanding.clone());
+                                tokio::spawn(
+                                    async move {
+                                        let _inflight = inflight;
+                                        let response =
+                                            prover_client::snark_proof(snark_task, tls_config)
+                                                .await;
+                                        if let Some(snark_task) = response {
+                                            let _ = tx.send(Task::Snark(snark_task)).await;
+                                        }
                                     }
-                                });
+                                    .in_current_span(),
+                                );
                             }
                         }
                         _ => {}
                     }
+                    for step in [Step::Split, Step::Prove, Step::Agg, Step::Snark] {
+                        pending.set(step, stage.count_pending_tasks(step));
+                    }
                     tokio::select! {
                         task = rx.recv() => {
                             if let Some(task) = task {
                                 match task {
                                     Task::Split(mut data) => {
                                         stage.on_split_task(&mut data);
+                                        record_task!(data, db, retries, proof_id, version, Step::Split);
+                                        save_execution_report(&db, &data).await;
                                         save_task!(data, db, TASK_ITYPE_SPLIT);
                                     },
                                     Task::Prove(mut data) => {
                                         stage.on_prove_task(&mut data);
+                                        record_task!(data, db, retries, proof_id, version, Step::Prove);
                                         // save_task!(data, db, TASK_ITYPE_PROVE);
                                     },
                                     Task::Agg(mut data) => {
                                         stage.on_agg_task(&mut data);
+                                        record_task!(data, db, retries, proof_id, version, Step::Agg);
                                         // save_task!(data, db, TASK_ITYPE_AGG);
                                     },
                                     Task::Snark(mut data) => {
                                         stage.on_snark_task(&mut data);
+                                        record_task!(data, db, retries, proof_id, version, Step::Snark);
                                         save_task!(data, db, TASK_ITYPE_FINAL);
                                     },
                                 };
@@ -157,8 +338,31 @@ async fn run_stage_task(
                     }
                     stage.dispatch();
                     let ts_now = get_timestamp();
+                    if current_step != stage.step {
+                        step_trace.finish_ts = ts_now;
+                        record_event(
+                            &db,
+                            &task.id,
+                            current_step,
+                            "",
+                            &step_trace,
+                            TASK_STATE_SUCCESS,
+                            0,
+                        )
+                        .await;
+                        step_trace.start_ts = ts_now;
+                    }
                     if check_at + 10 < ts_now || current_step != stage.step {
                         check_at = ts_now;
+                        match lease::acquire(&db, &lease::stage_task_lease(&task.id)).await {
+                            Ok(false) => {
+                                // it expired while stalled, and another replica took it over
+                                tracing::warn!("[stage] {} lease lost, stop", task.id);
+                                return;
+                            }
+                            Err(e) => tracing::warn!("[stage] {} renew lease: {:?}", task.id, e),
+                            Ok(true) => {}
+                        }
                         let rows_affected = db
                             .update_stage_task_check_at(
                                 &task.id,
@@ -175,6 +379,18 @@ async fn run_stage_task(
                     }
                 }
                 if stage.is_error() {
+                    step_trace.finish_ts = get_timestamp();
+                    step_trace.message.clone_from(&stage.errmsg);
+                    record_event(
+                        &db,
+                        &task.id,
+                        stage.step,
+                        "",
+                        &step_trace,
+                        TASK_STATE_FAILED,
+                        0,
+                    )
+                    .await;
                     let get_status = || match stage.step {
                         Step::Split => stage_service::v1::Status::SplitError,
                         Step::Prove => stage_service::v1::Status::ProveError,
@@ -186,13 +402,27 @@ async fn run_stage_task(
                     db.update_stage_task(&task.id, status.into(), "")
                         .await
                         .unwrap();
+                    let notification = Notification {
+                        proof_id: task.id.clone(),
+                        status: status.into(),
+                        step: stage.step.into(),
+                        error_message: stage.errmsg.clone(),
+                        finished_at: get_timestamp(),
+                        ..Default::default()
+                    };
+                    notify::notify(&db, &generate_context.callback_url, &notification).await;
                 } else {
                     // If generate compressed proof, do not store in database, use file instead.
                     let result = if generate_context.target_step == Step::Snark {
-                        file::new(&generate_context.snark_path).read().unwrap()
+                        file::new_async(&generate_context.snark_path)
+                            .read()
+                            .await
+                            .unwrap()
                     } else {
                         vec![]
                     };
+                    let finished_at = get_timestamp();
+                    let attestation = save_attestation(&db, &generate_context, finished_at).await;
                     db.update_stage_task(
                         &task.id,
                         stage_service::v1::Status::Success.into(),
@@ -200,8 +430,35 @@ async fn run_stage_task(
                     )
                     .await
                     .unwrap();
+                    let notification = Notification {
+                        proof_id: task.id.clone(),
+                        status: stage_service::v1::Status::Success.into(),
+                        step: stage.step.into(),
+                        finished_at,
+                        attestation,
+                        ..Default::default()
+                    };
+                    notify::notify(&db, &generate_context.callback_url, &notification).await;
+                    let proof_trace = Trace {
+                        start_ts: started_at,
+                        finish_ts: get_timestamp(),
+                        ..Default::default()
+                    };
+                    record_event(
+                        &db,
+                        &task.id,
+                        Step::End,
+                        "",
+                        &proof_trace,
+                        TASK_STATE_SUCCESS,
+                        0,
+                    )
+                    .await;
                     tracing::info!("[stage] finished {:?} ", stage);
                 }
+                if generate_context.segment_transfer {
+                    release_segments(&stage, tls_config.clone()).await;
+                }
             }
             Err(_) => {
                 let _ = db
@@ -216,15 +473,44 @@ async fn run_stage_task(
     }
 }
 
+// The segments are left on the split node until the end of the proof, for the prove tasks
+// rescheduled meanwhile.
+async fn release_segments(stage: &Stage, tls_config: Option<TlsConfig>) {
+    let split_addr = &stage.split_task.trace.node_info;
+    if split_addr.is_empty() {
+        return;
+    }
+    if let Err(e) = prover_client::release_segments(
+        split_addr,
+        &stage.generate_task.proof_id,
+        &stage.generate_task.seg_path,
+        tls_config,
+    )
+    .await
+    {
+        tracing::warn!(
+            "[stage] release the segments of {} on {}: {:?}",
+            stage.generate_task.proof_id,
+            split_addr,
+            e
+        );
+    }
+}
+
 async fn load_stage_task(node_num: usize, tls_config: Option<TlsConfig>, db: database::Database) {
     let store = Arc::new(Mutex::new(HashMap::new()));
     loop {
+        if shutdown::is_draining() {
+            tracing::info!("[stage] draining, stop loading stage tasks");
+            return;
+        }
         let limit = 5;
         let status = stage_service::v1::Status::Computing.into();
         let check_at = get_timestamp();
-        // FIXME: why do we just fetch the task in last 1 min?
+        // The owner of a task heartbeats its check_at along with the lease, so the candidates are
+        // the tasks quiet for a lease period. The lease decides which replica takes one.
         let result = db
-            .get_incomplete_stage_tasks(status, (check_at - 60) as i64, limit)
+            .get_incomplete_stage_tasks(status, (check_at - LEASE_TTL) as i64, limit)
             .await;
         match result {
             Ok(tasks) => {
@@ -236,6 +522,15 @@ async fn load_stage_task(node_num: usize, tls_config: Option<TlsConfig>, db: dat
                             if store.lock().unwrap().contains_key(&task.id) {
                                 continue;
                             }
+                            let lease_name = lease::stage_task_lease(&task.id);
+                            match lease::acquire(&db, &lease_name).await {
+                                Ok(true) => {}
+                                Ok(false) => continue,
+                                Err(e) => {
+                                    tracing::error!("[stage] {} lease: {:?}", task.id, e);
+                                    continue;
+                                }
+                            }
                             let rows_affected = db
                                 .update_stage_task_check_at(
                                     &task.id,
@@ -244,21 +539,26 @@ async fn load_stage_task(node_num: usize, tls_config: Option<TlsConfig>, db: dat
                                     task.step,
                                 )
                                 .await;
-                            if let Ok(rows_affected) = rows_affected {
-                                if rows_affected == 1 {
-                                    task.check_at = check_at as i64;
-                                    store.lock().unwrap().insert(task.id.clone(), check_at);
-                                    let store_arc = store.clone();
-                                    let tls_config_copy = tls_config.clone();
-                                    let db_copy = db.clone();
-                                    tokio::spawn(async move {
-                                        let id = task.id.clone();
-                                        run_stage_task(node_num, task, tls_config_copy, db_copy)
-                                            .await;
-                                        store_arc.lock().unwrap().remove(&id);
-                                    });
-                                }
+                            if let Ok(1) = rows_affected {
+                                task.check_at = check_at as i64;
                             }
+                            store.lock().unwrap().insert(task.id.clone(), check_at);
+                            let store_arc = store.clone();
+                            let tls_config_copy = tls_config.clone();
+                            let db_copy = db.clone();
+                            let guard = shutdown::track();
+                            tokio::spawn(async move {
+                                let _guard = guard;
+                                let id = task.id.clone();
+                                // the root span of the trace of the proof
+                                let span = tracing::info_span!("stage_task", proof_id = %id);
+                                run_stage_task(node_num, task, tls_config_copy, db_copy.clone())
+                                    .instrument(span)
+                                    .await;
+                                // a no-op if the lease was lost to another replica
+                                let _ = lease::release(&db_copy, &lease_name).await;
+                                store_arc.lock().unwrap().remove(&id);
+                            });
                         }
                     }
                 }
@@ -22,6 +22,12 @@ pub struct GenerateTask {
     pub composite_proof: bool,
     pub receipt_inputs_path: String,
     pub receipts_path: String,
+    // the prover nodes do not share the base dir, see `RuntimeConfig::segment_transfer`
+    #[serde(default)]
+    pub segment_transfer: bool,
+    // notified of the end of the proof, see `notify`
+    #[serde(default)]
+    pub callback_url: String,
     #[serde(skip_serializing, skip_deserializing)]
     pub program: Option<Program>,
 }
@@ -112,6 +118,8 @@ impl GenerateTask {
             composite_proof,
             receipt_inputs_path: receipt_inputs_path.to_string(),
             receipts_path: receipts_path.to_string(),
+            segment_transfer: false,
+            callback_url: String::new(),
             program: None,
         }
     }
@@ -40,6 +40,9 @@ pub struct Trace {
     pub finish_ts: u64,
     // FIXME: remove?
     pub node_info: String,
+    // the result message of the node
+    #[serde(default)]
+    pub message: String,
 }
 
 impl Trace {
@@ -48,3 +51,37 @@ impl Trace {
         self.finish_ts - self.start_ts
     }
 }
+
+// Insert a finished task into the `prove_task` records of `$store`, the database of the stage or
+// the task store of the standalone mode.
+macro_rules! save_task {
+    ($task:ident, $store:ident, $type:expr) => {
+        save_task!($task, $store, $type, $task.trace.node_info.clone())
+    };
+    ($task:ident, $store:ident, $type:expr, $node_info:expr) => {
+        if $task.state == TASK_STATE_FAILED || $task.state == TASK_STATE_SUCCESS {
+            tracing::info!(
+                "begin to save task: {:?}:{:?} type {:?} status {}",
+                $task.proof_id,
+                $task.task_id,
+                $type,
+                $task.state
+            );
+            // TODO: should remove the content from database, store it by FS.
+            let prove_task = $crate::database::ProveTask {
+                id: $task.task_id.clone(),
+                itype: $type,
+                proof_id: $task.proof_id.clone(),
+                status: $task.state as i32,
+                node_info: $node_info,
+                content: serde_json::to_string(&$task).ok(),
+                time_cost: ($task.trace.duration()) as i64,
+                ..Default::default()
+            };
+            if let Err(e) = $store.insert_prove_task(&prove_task).await {
+                tracing::error!("save task error: {:?}", e)
+            }
+        }
+    };
+}
+pub(crate) use save_task;
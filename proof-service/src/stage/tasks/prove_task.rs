@@ -16,6 +16,9 @@ pub struct ProveTask {
     #[serde(skip_serializing, skip_deserializing)]
     // pub segment: Vec<u8>,
     pub segment: String,
+    // the split node serving the segment, empty if it is on shared storage
+    #[serde(skip_serializing, skip_deserializing)]
+    pub segment_addr: String,
     #[serde(skip_serializing, skip_deserializing)]
     pub program: Program,
 
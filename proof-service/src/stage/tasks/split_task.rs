@@ -1,3 +1,4 @@
+use crate::proto::includes::v1::ExecutionReport;
 use crate::stage::tasks::Trace;
 use serde::{Deserialize, Serialize};
 
@@ -17,6 +18,9 @@ pub struct SplitTask {
     pub block_no: Option<u64>,
     pub seg_size: u32,
     pub recepit_inputs_path: String,
+    // send the inputs inline and fetch the outputs back from the split node
+    #[serde(default)]
+    pub segment_transfer: bool,
 
     pub trace: Trace,
 
@@ -25,4 +29,6 @@ pub struct SplitTask {
 
     pub total_steps: u64,
     pub total_segments: u32,
+    #[serde(default)]
+    pub execution_report: Option<ExecutionReport>,
 }
@@ -0,0 +1,135 @@
+//! Verification of the outputs of the proofs in the stage, by the verifiers of prover_v2, so that a
+//! proof can be checked without an Ethereum node.
+use crate::proto::stage_service::v1::{
+    Status::InvalidParameter, VerifyProofRequest, VerifyProofResponse,
+};
+#[cfg(feature = "prover_v2")]
+use std::collections::HashMap;
+#[cfg(feature = "prover_v2")]
+use std::sync::{Mutex, OnceLock};
+
+// The vks set up from the elfs of the requests, by program id. The stage has no key cache.
+#[cfg(feature = "prover_v2")]
+static PROGRAM_VKS: OnceLock<Mutex<HashMap<String, Vec<u8>>>> = OnceLock::new();
+#[cfg(feature = "prover_v2")]
+const MAX_PROGRAM_VKS: usize = 64;
+
+#[cfg(feature = "prover_v2")]
+fn program_vk(elf: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use sha2::{Digest, Sha256};
+
+    let program_id = hex::encode(Sha256::digest(elf));
+    let vks = PROGRAM_VKS.get_or_init(Default::default);
+    if let Some(vk) = vks.lock().unwrap().get(&program_id) {
+        return Ok(vk.clone());
+    }
+    let vk = prover_v2::program_vk(&program_id, elf)?;
+    let mut vks = vks.lock().unwrap();
+    if vks.len() >= MAX_PROGRAM_VKS {
+        vks.clear();
+    }
+    vks.insert(program_id, vk.clone());
+    Ok(vk)
+}
+
+fn check(request: &VerifyProofRequest) -> Option<&'static str> {
+    if request.proof.is_empty() {
+        Some("the proof is empty")
+    } else if request.vk.is_empty() && request.elf_data.is_empty() {
+        Some("either the vk or the elf of the program is required")
+    } else {
+        None
+    }
+}
+
+fn invalid_parameter(request: &VerifyProofRequest) -> Option<VerifyProofResponse> {
+    check(request).map(|error_message| VerifyProofResponse {
+        status: InvalidParameter.into(),
+        error_message: error_message.to_string(),
+        proof_id: request.proof_id.clone(),
+        ..Default::default()
+    })
+}
+
+/// Verify the proof of `request`, the Groth16 proofs with the keys under `proving_key_path`. A
+/// proof is verified if the response is `Success`, and it is valid or the reason is in
+/// `error_message`. `slot` is held until the verifier returns, even if the request is dropped.
+#[cfg(feature = "prover_v2")]
+pub async fn verify_proof(
+    proving_key_path: &str,
+    request: &VerifyProofRequest,
+    slot: impl Send + 'static,
+) -> anyhow::Result<VerifyProofResponse> {
+    use crate::proto::stage_service::v1::Status::Success;
+    use prover_v2::verifier::Verifier;
+
+    if let Some(response) = invalid_parameter(request) {
+        return Ok(response);
+    }
+    let verifier = Verifier::new(proving_key_path);
+    let inputs = request.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        let vk = if inputs.vk.is_empty() {
+            program_vk(&inputs.elf_data)?
+        } else {
+            inputs.vk
+        };
+        verifier.verify(&inputs.proof, &vk, &inputs.public_values)
+    })
+    .await?;
+    let mut response = VerifyProofResponse {
+        status: Success.into(),
+        proof_id: request.proof_id.clone(),
+        valid: result.is_ok(),
+        ..Default::default()
+    };
+    if let Err(e) = result {
+        tracing::info!("[verify_proof] {} invalid: {}", request.proof_id, e);
+        response.error_message = e.to_string();
+    }
+    Ok(response)
+}
+
+#[cfg(not(feature = "prover_v2"))]
+pub async fn verify_proof(
+    _proving_key_path: &str,
+    request: &VerifyProofRequest,
+    _slot: impl Send + 'static,
+) -> anyhow::Result<VerifyProofResponse> {
+    if let Some(response) = invalid_parameter(request) {
+        return Ok(response);
+    }
+    Ok(VerifyProofResponse {
+        status: crate::proto::stage_service::v1::Status::InternalError.into(),
+        error_message: "verify is only supported by prover_v2".to_string(),
+        proof_id: request.proof_id.clone(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_verify_proof_parameters() {
+        let mut request = VerifyProofRequest {
+            proof_id: "p1".to_string(),
+            ..Default::default()
+        };
+        let response = verify_proof("", &request, ()).await.unwrap();
+        assert_eq!(response.status, InvalidParameter as i32);
+        assert_eq!(response.error_message, "the proof is empty");
+        assert!(!response.valid);
+
+        request.proof = b"{}".to_vec();
+        let response = verify_proof("", &request, ()).await.unwrap();
+        assert_eq!(response.status, InvalidParameter as i32);
+        assert_eq!(
+            response.error_message,
+            "either the vk or the elf of the program is required"
+        );
+        assert_eq!(response.proof_id, "p1");
+    }
+}
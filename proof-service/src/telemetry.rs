@@ -0,0 +1,125 @@
+//! Export of the tracing spans over OTLP, and propagation of the trace context in the gRPC
+//! metadata, so that a proof is a single trace across the stage and the prover nodes.
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The layer exporting the spans to `OTEL_EXPORTER_OTLP_ENDPOINT` over gRPC, e.g.
+/// `http://localhost:4317`, None if it is unset. The service is named by `OTEL_SERVICE_NAME`.
+pub fn otlp_layer<S>() -> Option<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "proof-service".to_string());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name,
+            )])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio);
+    match tracer {
+        Ok(tracer) => {
+            opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        Err(e) => {
+            // the logger is not set up yet
+            eprintln!("OTLP exporter setup failed: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Flush the spans not exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Send the context of the current span along with `request`.
+pub fn inject<T>(request: &mut tonic::Request<T>) {
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(request.metadata_mut()))
+    });
+}
+
+/// Make `span` a child of the span which sent `request`.
+pub fn set_parent<T>(span: &tracing::Span, request: &tonic::Request<T>) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(request.metadata()))
+    });
+    span.set_parent(context);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_propagate_context() {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        // the tracer only holds a weak reference to its provider
+        let provider = trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::with_default(subscriber, || {
+            let client_span = tracing::info_span!("client");
+            let mut request = tonic::Request::new(());
+            client_span.in_scope(|| inject(&mut request));
+            assert!(request.metadata().contains_key("traceparent"));
+
+            let server_span = tracing::info_span!("server");
+            set_parent(&server_span, &request);
+            let trace_id = |span: &tracing::Span| span.context().span().span_context().trace_id();
+            assert_eq!(trace_id(&client_span), trace_id(&server_span));
+        });
+    }
+}
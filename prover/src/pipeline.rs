@@ -90,6 +90,35 @@ impl Pipeline {
         }
     }
 
+    // The recursive circuits are shared by all the programs, so warming up builds them.
+    pub fn warm_program(&self, _program_id: &str, _elf_path: &str) -> Result<bool, String> {
+        let result = self.mutex.try_lock();
+        match result {
+            Ok(_guard) => {
+                let _ = crate::provers::instance();
+                Ok(true)
+            }
+            Err(e) => {
+                log::error!("warm_program busy: {:?}", e);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn warm_up_snark(&self) -> Result<bool, String> {
+        let result = self.mutex.try_lock();
+        match result {
+            Ok(_guard) => {
+                let _ = crate::provers::instance();
+                Ok(true)
+            }
+            Err(e) => {
+                log::error!("warm_up_snark busy: {:?}", e);
+                Ok(false)
+            }
+        }
+    }
+
     /// Return zkm-prover status
     pub fn get_status(&self) -> bool {
         let result = self.mutex.try_lock();
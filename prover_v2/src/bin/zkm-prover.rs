@@ -0,0 +1,236 @@
+use clap::{Parser, Subcommand};
+use common::file;
+use prover_v2::contexts::{AggContext, ProveContext, SnarkContext, SplitContext};
+use prover_v2::pipeline::Pipeline;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+
+/// Run a single stage of the proving pipeline from files on disk, in the formats used by the
+/// proof service.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Directory of the groth16 proving key
+    #[arg(long = "proving-key-paths", default_value_t = String::from("/tmp/zkm/proving.key"))]
+    proving_key_paths: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the program only, print the exit code and total cycles and write the public values
+    Execute {
+        #[arg(long)]
+        elf: String,
+        /// bincode `Vec<Vec<u8>>` of the private input stream
+        #[arg(long = "private-input")]
+        private_input: String,
+        #[arg(long = "receipt-inputs", default_value_t = String::new())]
+        receipt_inputs: String,
+        #[arg(long = "public-values")]
+        public_values: Option<String>,
+    },
+    /// Split the program into segments under `{base_dir}/segment`
+    Split {
+        #[arg(long)]
+        elf: String,
+        #[arg(long = "private-input")]
+        private_input: String,
+        #[arg(long = "receipt-inputs", default_value_t = String::new())]
+        receipt_inputs: String,
+        #[arg(long = "base-dir")]
+        base_dir: String,
+        #[arg(long = "seg-size", default_value_t = 262144)]
+        seg_size: u32,
+    },
+    /// Prove a zstd-bincode segment, writing the bincode shard proof
+    ProveSegment {
+        #[arg(long)]
+        segment: String,
+        /// the program of the segment, its digest keys the proving keys
+        #[arg(long)]
+        elf: String,
+        #[arg(long = "seg-size", default_value_t = 262144)]
+        seg_size: u32,
+        #[arg(long)]
+        output: String,
+    },
+    /// Aggregate shard proofs (leaf layer) or json `ZKMProof`s, writing the json `ZKMProof`
+    Aggregate {
+        /// bincode program vk, required by the leaf layer
+        #[arg(long, default_value_t = String::new())]
+        vk: String,
+        #[arg(long, num_args = 1.., required = true)]
+        inputs: Vec<String>,
+        #[arg(long = "leaf", default_value_t = false)]
+        is_leaf_layer: bool,
+        #[arg(long = "first-shard", default_value_t = false)]
+        is_first_shard: bool,
+        #[arg(long = "deferred", default_value_t = false)]
+        is_deferred: bool,
+        #[arg(long = "complete", default_value_t = false)]
+        is_complete: bool,
+        #[arg(long)]
+        output: String,
+    },
+    /// Wrap the final json `ZKMProof` into a Groth16 proof
+    Snark {
+        #[arg(long)]
+        input: String,
+        #[arg(long)]
+        output: String,
+    },
+    /// Verify a json `ZKMProof`, compressed or Groth16, or a composite receipt
+    Verify {
+        #[arg(long)]
+        proof: String,
+        /// bincode program vk, as written by split to `{base_dir}/vk.bin`
+        #[arg(long, default_value_t = String::new())]
+        vk: String,
+        /// the program, to set up the vk if it is not given
+        #[arg(long, default_value_t = String::new())]
+        elf: String,
+        #[arg(long = "public-values", default_value_t = String::new())]
+        public_values: String,
+    },
+}
+
+fn write(path: &str, buf: &[u8]) -> anyhow::Result<()> {
+    file::new(path).write_all(buf)?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    zkm_core_machine::utils::setup_logger();
+    let args = Args::parse();
+    let pipeline = Pipeline::new("", &args.proving_key_paths);
+
+    match args.command {
+        Command::Execute {
+            elf,
+            private_input,
+            receipt_inputs,
+            public_values,
+        } => {
+            let ctx = SplitContext {
+                elf_path: elf,
+                private_input_path: private_input,
+                receipt_inputs_path: receipt_inputs,
+                ..Default::default()
+            };
+            let output = pipeline.execute(&ctx).map_err(anyhow::Error::msg)?;
+            println!("exit code: {}", output.exit_code);
+            println!("total cycles: {}", output.report.total_cycles);
+            if let Some(path) = public_values {
+                write(&path, &output.public_values)?;
+            }
+        }
+        Command::Split {
+            elf,
+            private_input,
+            receipt_inputs,
+            base_dir,
+            seg_size,
+        } => {
+            let seg_path = format!("{base_dir}/segment");
+            file::new(&seg_path).create_dir_all()?;
+            file::new(&format!("{base_dir}/wrap")).create_dir_all()?;
+            // same program id as the stage service
+            let program_id = hex::encode(Sha256::digest(file::new(&elf).read()?));
+            let ctx = SplitContext::new(
+                &base_dir,
+                &program_id,
+                &elf,
+                None,
+                seg_size,
+                &seg_path,
+                "",
+                &private_input,
+                "",
+                "",
+                &receipt_inputs,
+            );
+            let (_, total_steps, total_segments) =
+                pipeline.split(&ctx).map_err(anyhow::Error::msg)?;
+            println!("program id: {program_id}");
+            println!("total cycles: {total_steps}, segments: {total_segments}");
+        }
+        Command::ProveSegment {
+            segment,
+            elf,
+            seg_size,
+            output,
+        } => {
+            let program_id = hex::encode(Sha256::digest(file::new(&elf).read()?));
+            let ctx = ProveContext {
+                program_id,
+                segment,
+                seg_size,
+                ..Default::default()
+            };
+            let (_, receipt) = pipeline.prove_root(&ctx).map_err(anyhow::Error::msg)?;
+            write(&output, &receipt)?;
+        }
+        Command::Aggregate {
+            vk,
+            inputs,
+            is_leaf_layer,
+            is_first_shard,
+            is_deferred,
+            is_complete,
+            output,
+        } => {
+            let vk = if vk.is_empty() {
+                vec![]
+            } else {
+                file::new(&vk).read()?
+            };
+            let proofs = inputs
+                .iter()
+                .map(|path| file::new(path).read())
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let ctx = AggContext {
+                vk,
+                proofs,
+                is_complete,
+                is_first_shard,
+                is_leaf_layer,
+                is_deferred,
+            };
+            let (_, receipt) = pipeline.prove_aggregate(&ctx).map_err(anyhow::Error::msg)?;
+            write(&output, &receipt)?;
+        }
+        Command::Snark { input, output } => {
+            let ctx = SnarkContext {
+                agg_receipt: file::new(&input).read()?,
+                ..Default::default()
+            };
+            let (_, proof) = pipeline.prove_snark(&ctx).map_err(anyhow::Error::msg)?;
+            write(&output, &proof)?;
+        }
+        Command::Verify {
+            proof,
+            vk,
+            elf,
+            public_values,
+        } => {
+            let vk = if vk.is_empty() {
+                let elf = file::new(&elf).read()?;
+                prover_v2::program_vk(&hex::encode(Sha256::digest(&elf)), &elf)?
+            } else {
+                file::new(&vk).read()?
+            };
+            let public_values = if public_values.is_empty() {
+                vec![]
+            } else {
+                file::new(&public_values).read()?
+            };
+            pipeline
+                .verify(&file::new(&proof).read()?, &vk, &public_values)
+                .map_err(anyhow::Error::msg)?;
+            println!("proof is valid");
+        }
+    }
+    Ok(())
+}
@@ -14,6 +14,9 @@ pub struct SplitContext {
     pub output_path: String,
     pub args: String,
     pub receipt_inputs_path: String,
+    // stop the execution after this many cycles
+    #[serde(default)]
+    pub max_cycles: Option<u64>,
 }
 
 impl SplitContext {
@@ -43,6 +46,7 @@ impl SplitContext {
             output_path: output_path.to_string(),
             args: args.to_string(),
             receipt_inputs_path: receipt_inputs_path.to_string(),
+            max_cycles: None,
         }
     }
 }
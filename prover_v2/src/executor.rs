@@ -1,5 +1,7 @@
 use common::file;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, Seek, Write};
 use std::sync::{
@@ -12,8 +14,8 @@ use std::time::Instant;
 use p3_maybe_rayon::prelude::*;
 use zkm_core_executor::{
     events::{format_table_line, sorted_table_lines},
-    ExecutionRecord, ExecutionReport, Executor as Runtime, Program, SubproofVerifier, ZKMContext,
-    ZKMReduceProof,
+    ExecutionError, ExecutionRecord, ExecutionReport, Executor as Runtime, Program,
+    SubproofVerifier, ZKMContext, ZKMReduceProof,
 };
 use zkm_core_machine::{
     io::ZKMStdin,
@@ -27,7 +29,46 @@ use zkm_stark::{
 };
 
 pub use crate::contexts::SplitContext;
-use crate::{get_prover, NetworkProve, FIRST_LAYER_BATCH_SIZE, KEY_CACHE};
+use crate::{get_prover, key_cache, NetworkProve, FIRST_LAYER_BATCH_SIZE};
+
+/// The `ExecutionReport` of a split by opcode and syscall name, written to
+/// `{base_dir}/execution_report.json`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CycleReport {
+    pub total_cycles: u64,
+    pub total_syscalls: u64,
+    pub touched_memory_addresses: u64,
+    pub opcode_counts: BTreeMap<String, u64>,
+    pub syscall_counts: BTreeMap<String, u64>,
+}
+
+fn nonzero_counts<'a>(lines: impl IntoIterator<Item = (String, &'a u64)>) -> BTreeMap<String, u64> {
+    lines
+        .into_iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(label, count)| (label, *count))
+        .collect()
+}
+
+impl From<&ExecutionReport> for CycleReport {
+    fn from(report: &ExecutionReport) -> Self {
+        CycleReport {
+            total_cycles: report.total_instruction_count(),
+            total_syscalls: report.total_syscall_count(),
+            touched_memory_addresses: report.touched_memory_addresses,
+            opcode_counts: nonzero_counts(sorted_table_lines(report.opcode_counts.as_ref()).1),
+            syscall_counts: nonzero_counts(sorted_table_lines(report.syscall_counts.as_ref()).1),
+        }
+    }
+}
+
+/// The result of running a program in the emulator only.
+#[derive(Debug, Default, Clone)]
+pub struct ExecuteOutput {
+    pub public_values: Vec<u8>,
+    pub exit_code: u32,
+    pub report: CycleReport,
+}
 
 #[derive(Default)]
 pub struct Executor {}
@@ -35,25 +76,7 @@ impl Executor {
     pub fn split(&self, ctx: &SplitContext) -> anyhow::Result<(u64, u32)> {
         let prover = get_prover();
         let mut network_prove = NetworkProve::new(ctx.seg_size);
-
-        let encoded_input = file::new(&ctx.private_input_path).read()?;
-        let inputs_data: Vec<Vec<u8>> = bincode::deserialize(&encoded_input)?;
-        inputs_data.into_iter().for_each(|input| {
-            network_prove.stdin.write_vec(input);
-        });
-
-        if !ctx.receipt_inputs_path.is_empty() {
-            let receipt_datas = std::fs::read(&ctx.receipt_inputs_path)?;
-            let receipts = bincode::deserialize::<Vec<Vec<u8>>>(&receipt_datas)?;
-            for receipt in receipts.iter() {
-                let receipt: (
-                    ZKMReduceProof<KoalaBearPoseidon2>,
-                    StarkVerifyingKey<KoalaBearPoseidon2>,
-                ) = bincode::deserialize(receipt).map_err(|e| anyhow::anyhow!(e))?;
-                network_prove.stdin.write_proof(receipt.0, receipt.1);
-            }
-            tracing::info!("Write {} receipts", receipts.len());
-        }
+        Self::load_stdin(ctx, &mut network_prove.stdin)?;
 
         let elf_path = ctx.elf_path.clone();
         tracing::info!("split {} load elf file", elf_path);
@@ -62,21 +85,14 @@ impl Executor {
         let program = prover
             .get_program(&elf)
             .map_err(|e| anyhow::Error::msg(e.to_string()))?;
-        let mut cache = KEY_CACHE.lock().unwrap();
-        let vk = if let Some((_, vk)) = cache.cache.get(&ctx.program_id) {
-            tracing::info!("load vk from cache");
-            vk
-        } else {
-            tracing::info!("No vk in cache, generate new keys");
-            let (pk, vk) = prover.core_prover.setup(&program);
-            cache.push(ctx.program_id.clone(), (pk, vk));
-            &cache.cache.get(&ctx.program_id).unwrap().1
-        };
+        let mut cache = key_cache();
+        let (_, vk) =
+            cache.get_or_insert_with(&ctx.program_id, || Ok(prover.core_prover.setup(&program)))?;
         let vk_bytes = bincode::serialize(&vk)?;
         file::new(&format!("{}/vk.bin", ctx.base_dir)).write_all(&vk_bytes)?;
 
         let context = network_prove.context_builder.build();
-        let (total_steps, total_segments, public_values_stream) = self.split_with_context(
+        let (total_steps, total_segments, public_values_stream, report) = self.split_with_context(
             &prover,
             ctx,
             program,
@@ -90,10 +106,69 @@ impl Executor {
         // file::new(&ctx.output_path).write(&public_values_stream)?;
         let public_values_path = format!("{}/wrap/public_values.bin", ctx.base_dir);
         file::new(&public_values_path).write_all(&public_values_stream)?;
+        let report_path = format!("{}/execution_report.json", ctx.base_dir);
+        file::new(&report_path).write_all(&serde_json::to_vec(&report)?)?;
 
         Ok((total_steps, total_segments))
     }
 
+    /// Run the program without generating traces or writing segments. A program halting with a
+    /// non-zero exit code has no public values nor report.
+    pub fn execute(&self, ctx: &SplitContext) -> anyhow::Result<ExecuteOutput> {
+        let prover = get_prover();
+        let mut network_prove = NetworkProve::new(ctx.seg_size);
+        Self::load_stdin(ctx, &mut network_prove.stdin)?;
+
+        let elf = file::new(&ctx.elf_path).read()?;
+        if let Some(max_cycles) = ctx.max_cycles {
+            network_prove.context_builder.max_cycles(max_cycles);
+        }
+        let context = network_prove.context_builder.build();
+        let (public_values, report) = match prover.execute(&elf, &network_prove.stdin, context) {
+            Ok(output) => output,
+            Err(ExecutionError::HaltWithNonZeroExitCode(exit_code)) => {
+                tracing::info!("execute {} exit code: {}", ctx.elf_path, exit_code);
+                return Ok(ExecuteOutput {
+                    exit_code,
+                    ..Default::default()
+                });
+            }
+            Err(e) => return Err(anyhow::Error::msg(e.to_string())),
+        };
+        tracing::info!(
+            "execute {} total cycles: {}",
+            ctx.elf_path,
+            report.total_instruction_count()
+        );
+        Ok(ExecuteOutput {
+            public_values: public_values.to_vec(),
+            exit_code: 0,
+            report: CycleReport::from(&report),
+        })
+    }
+
+    fn load_stdin(ctx: &SplitContext, stdin: &mut ZKMStdin) -> anyhow::Result<()> {
+        let encoded_input = file::new(&ctx.private_input_path).read()?;
+        let inputs_data: Vec<Vec<u8>> = bincode::deserialize(&encoded_input)?;
+        inputs_data.into_iter().for_each(|input| {
+            stdin.write_vec(input);
+        });
+
+        if !ctx.receipt_inputs_path.is_empty() {
+            let receipt_datas = file::new(&ctx.receipt_inputs_path).read()?;
+            let receipts = bincode::deserialize::<Vec<Vec<u8>>>(&receipt_datas)?;
+            for receipt in receipts.iter() {
+                let receipt: (
+                    ZKMReduceProof<KoalaBearPoseidon2>,
+                    StarkVerifyingKey<KoalaBearPoseidon2>,
+                ) = bincode::deserialize(receipt).map_err(|e| anyhow::anyhow!(e))?;
+                stdin.write_proof(receipt.0, receipt.1);
+            }
+            tracing::info!("Write {} receipts", receipts.len());
+        }
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn split_with_context<'a>(
         &self,
@@ -105,7 +180,7 @@ impl Executor {
         opts: ZKMCoreOpts,
         mut context: ZKMContext<'a>,
         shape_config: Option<&CoreShapeConfig<<CoreSC as StarkGenericConfig>::Val>>,
-    ) -> anyhow::Result<(u64, u32, Vec<u8>)> {
+    ) -> anyhow::Result<(u64, u32, Vec<u8>, CycleReport)> {
         context.subproof_verifier = Some(prover as &dyn SubproofVerifier);
         // Setup the runtime.
         let mut runtime = Runtime::with_context(program.clone(), opts, context);
@@ -389,17 +464,19 @@ impl Executor {
                 cycles as f64 / (split_time * 1000.0),
             );
 
-            Ok((cycles, total_segments as u32, public_values_stream))
+            Ok((
+                cycles,
+                total_segments as u32,
+                public_values_stream,
+                CycleReport::from(&*report_aggregate),
+            ))
         })
     }
 }
 
+// The prove nodes poll for the segments, the file writes are atomic so they never see a partial
+// one.
 fn write_file(path: String, buf: &[u8]) -> anyhow::Result<()> {
-    let tmp_path = format!("{path}.tmp");
-    let mut file = File::create(&tmp_path)?;
-    file.write_all(buf)?;
-    file.sync_all()?;
-    std::fs::rename(tmp_path, path)?;
-
+    file::new(&path).write_all(buf)?;
     Ok(())
 }
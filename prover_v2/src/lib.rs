@@ -1,6 +1,8 @@
+use common::file;
 use lru::LruCache;
 use once_cell::sync::OnceCell;
 use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::Duration;
 use zkm_core_executor::ZKMContextBuilder;
@@ -15,6 +17,7 @@ pub mod contexts;
 pub mod executor;
 pub mod root_prover;
 pub mod snark_prover;
+pub mod verifier;
 
 pub mod pipeline;
 
@@ -50,6 +53,11 @@ impl NetworkProve<'_> {
     }
 }
 
+/// The cycles of a shard, i.e. a segment of the split, for `seg_size`.
+pub fn shard_size(seg_size: u32) -> u64 {
+    NetworkProve::new(seg_size).opts.core_opts.shard_size as u64
+}
+
 static GLOBAL_PROVER: OnceCell<Mutex<ZKMProver>> = OnceCell::new();
 fn prover_instance() -> &'static Mutex<ZKMProver> {
     GLOBAL_PROVER.get_or_init(|| Mutex::new(ZKMProver::new()))
@@ -64,28 +72,187 @@ pub fn get_prover() -> impl std::ops::DerefMut<Target = ZKMProver> {
 static WRAP_KEYS: OnceCell<(StarkProvingKey<OuterSC>, StarkVerifyingKey<OuterSC>)> =
     OnceCell::new();
 
-const DEFAULT_CACHE_SIZE: usize = 3;
+pub const DEFAULT_CACHE_SIZE: usize = 3;
+
+pub type CoreKeys = (StarkProvingKey<CoreSC>, StarkVerifyingKey<CoreSC>);
 
 pub struct StarkKeyCache {
-    pub cache: LruCache<String, (StarkProvingKey<CoreSC>, StarkVerifyingKey<CoreSC>)>,
+    pub cache: LruCache<String, CoreKeys>,
+    // keys are persisted under `{dir}/{program_id}/`
+    dir: Option<String>,
 }
 
 impl StarkKeyCache {
-    pub fn new(size: usize) -> Self {
-        let cache = LruCache::<String, (StarkProvingKey<CoreSC>, StarkVerifyingKey<CoreSC>)>::new(
-            NonZeroUsize::new(size).unwrap(),
-        );
-        Self { cache }
+    pub fn new(size: usize) -> anyhow::Result<Self> {
+        Self::with_dir(size, None)
+    }
+
+    pub fn with_dir(size: usize, dir: Option<&str>) -> anyhow::Result<Self> {
+        let size = NonZeroUsize::new(size)
+            .ok_or_else(|| anyhow::anyhow!("key_cache_size must be greater than 0"))?;
+        Ok(Self {
+            cache: LruCache::<String, CoreKeys>::new(size),
+            dir: dir.map(|d| d.trim_end_matches('/').to_string()),
+        })
     }
     pub fn contains(&mut self, key: &String) -> bool {
         self.cache.get(key).is_some()
     }
-    pub fn push(&mut self, key: String, v: (StarkProvingKey<CoreSC>, StarkVerifyingKey<CoreSC>)) {
+    pub fn push(&mut self, key: String, v: CoreKeys) {
         self.cache.push(key.clone(), v);
     }
+
+    /// Get the keys of `program_id` from memory, then from disk, and run `setup` otherwise.
+    pub fn get_or_insert_with<F>(&mut self, program_id: &str, setup: F) -> anyhow::Result<&CoreKeys>
+    where
+        F: FnOnce() -> anyhow::Result<CoreKeys>,
+    {
+        check_program_id(program_id)?;
+        if self.cache.contains(program_id) {
+            KEY_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let keys = match self.load(program_id) {
+                Ok(Some(keys)) => {
+                    tracing::info!("load keys of {} from disk", program_id);
+                    KEY_CACHE_LOADS.fetch_add(1, Ordering::Relaxed);
+                    keys
+                }
+                result => {
+                    if let Err(e) = result {
+                        tracing::warn!("load keys of {} failed: {:?}", program_id, e);
+                    }
+                    tracing::info!("No keys of {} in cache, generate new keys", program_id);
+                    KEY_CACHE_SETUPS.fetch_add(1, Ordering::Relaxed);
+                    let keys = setup()?;
+                    if let Err(e) = self.save(program_id, &keys) {
+                        tracing::warn!("save keys of {} failed: {:?}", program_id, e);
+                    }
+                    keys
+                }
+            };
+            self.cache.push(program_id.to_string(), keys);
+        }
+        Ok(self.cache.get(program_id).unwrap())
+    }
+
+    /// The bincode vk of `program_id` from memory or from disk, without loading its pk.
+    pub fn vk(&self, program_id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        check_program_id(program_id)?;
+        if let Some((_, vk)) = self.cache.peek(program_id) {
+            return Ok(Some(bincode::serialize(vk)?));
+        }
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        let path = format!("{dir}/{program_id}/vk.bin");
+        Ok(file::new(&path).read_optional()?)
+    }
+
+    fn load(&self, program_id: &str) -> anyhow::Result<Option<CoreKeys>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        check_program_id(program_id)?;
+        let dir = format!("{dir}/{program_id}");
+        let files = file::new(&dir).read_dir().unwrap_or_default();
+        if !files.iter().any(|f| f == "pk.bin") || !files.iter().any(|f| f == "vk.bin") {
+            return Ok(None);
+        }
+        let pk = bincode::deserialize(&file::new(&format!("{dir}/pk.bin")).read()?)?;
+        let vk = bincode::deserialize(&file::new(&format!("{dir}/vk.bin")).read()?)?;
+        Ok(Some((pk, vk)))
+    }
+
+    fn save(&self, program_id: &str, keys: &CoreKeys) -> anyhow::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        check_program_id(program_id)?;
+        let dir = format!("{dir}/{program_id}");
+        file::new(&dir).create_dir_all()?;
+        // vk.bin is written last, its presence marks a complete entry
+        file::new(&format!("{dir}/pk.bin")).write_all(&bincode::serialize(&keys.0)?)?;
+        file::new(&format!("{dir}/vk.bin")).write_all(&bincode::serialize(&keys.1)?)?;
+        Ok(())
+    }
 }
 
-lazy_static::lazy_static! {
-    pub static ref KEY_CACHE: Mutex<StarkKeyCache> =
-        Mutex::new(StarkKeyCache::new(DEFAULT_CACHE_SIZE));
+/// A program id is the hex sha256 of its elf, it names the directory of its keys.
+fn check_program_id(program_id: &str) -> anyhow::Result<()> {
+    if program_id.len() != 64 || !program_id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!("invalid program id {:?}", program_id);
+    }
+    Ok(())
+}
+
+static KEY_CACHE: OnceCell<Mutex<StarkKeyCache>> = OnceCell::new();
+static KEY_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static KEY_CACHE_LOADS: AtomicU64 = AtomicU64::new(0);
+static KEY_CACHE_SETUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Lookups of the key cache since start, served from memory, loaded from disk or set up.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyCacheStats {
+    pub hits: u64,
+    pub loads: u64,
+    pub setups: u64,
+}
+
+/// Configure the proving key cache, must be called before the first proving task.
+pub fn init_key_cache(size: usize, dir: Option<&str>) -> anyhow::Result<()> {
+    let cache = StarkKeyCache::with_dir(size, dir)?;
+    if KEY_CACHE.set(Mutex::new(cache)).is_err() {
+        tracing::warn!("key cache is already initialized");
+    }
+    Ok(())
+}
+
+pub fn key_cache() -> std::sync::MutexGuard<'static, StarkKeyCache> {
+    KEY_CACHE
+        .get_or_init(|| {
+            Mutex::new(StarkKeyCache::new(DEFAULT_CACHE_SIZE).expect("DEFAULT_CACHE_SIZE is 0"))
+        })
+        .lock()
+        .expect("KEY_CACHE lock poisoned")
+}
+
+pub fn key_cache_stats() -> KeyCacheStats {
+    KeyCacheStats {
+        hits: KEY_CACHE_HITS.load(Ordering::Relaxed),
+        loads: KEY_CACHE_LOADS.load(Ordering::Relaxed),
+        setups: KEY_CACHE_SETUPS.load(Ordering::Relaxed),
+    }
+}
+
+/// Load or set up the keys of a program ahead of its first proving task, `program_id` must be
+/// the digest of the elf at `elf_path`.
+pub fn warm_program(program_id: &str, elf_path: &str) -> anyhow::Result<()> {
+    check_program_id(program_id)?;
+    let elf = file::new(elf_path).read()?;
+    if file::digest(&elf) != program_id {
+        anyhow::bail!("{} is not the program id of {}", program_id, elf_path);
+    }
+    let prover = get_prover();
+    let mut cache = key_cache();
+    cache.get_or_insert_with(program_id, || {
+        let program = prover
+            .get_program(&elf)
+            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        Ok(prover.core_prover.setup(&program))
+    })?;
+    Ok(())
+}
+
+/// The bincode vk of the program of `elf`, as split writes it to `{base_dir}/vk.bin`. It is set
+/// up without the key cache lock when the cache doesn't hold it, and the pk is dropped.
+pub fn program_vk(program_id: &str, elf: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if let Some(vk) = key_cache().vk(program_id)? {
+        return Ok(vk);
+    }
+    let prover = get_prover();
+    let program = prover
+        .get_program(elf)
+        .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+    let (_, vk) = prover.core_prover.setup(&program);
+    Ok(bincode::serialize(&vk)?)
 }
@@ -2,9 +2,10 @@ use std::sync::Mutex;
 
 use crate::agg_prover::AggProver;
 use crate::contexts::{AggContext, ProveContext, SnarkContext, SplitContext};
-use crate::executor::Executor;
+use crate::executor::{ExecuteOutput, Executor};
 use crate::root_prover::RootProver;
 use crate::snark_prover::SnarkProver;
+use crate::verifier::Verifier;
 
 #[derive(Default)]
 pub struct Pipeline {
@@ -13,6 +14,7 @@ pub struct Pipeline {
     root_prover: RootProver,
     agg_prover: AggProver,
     snark_prover: SnarkProver,
+    verifier: Verifier,
 }
 
 impl Pipeline {
@@ -23,6 +25,7 @@ impl Pipeline {
             root_prover: RootProver::default(),
             agg_prover: AggProver::default(),
             snark_prover: SnarkProver::new(keys_input_dir),
+            verifier: Verifier::new(keys_input_dir),
         }
     }
 
@@ -33,6 +36,13 @@ impl Pipeline {
             .map_err(|e| e.to_string())
     }
 
+    /// Run the program only, without generating traces or writing segments.
+    pub fn execute(&self, split_context: &SplitContext) -> Result<ExecuteOutput, String> {
+        self.executor
+            .execute(split_context)
+            .map_err(|e| e.to_string())
+    }
+
     pub fn prove_root(&self, prove_context: &ProveContext) -> Result<(bool, Vec<u8>), String> {
         match self.mutex.try_lock() {
             Ok(_) => self
@@ -80,6 +90,44 @@ impl Pipeline {
         }
     }
 
+    pub fn verify(&self, proof: &[u8], vk: &[u8], public_values: &[u8]) -> Result<bool, String> {
+        self.verifier
+            .verify(proof, vk, public_values)
+            .map(|_| true)
+            .map_err(|e| {
+                tracing::error!("verify error {:#?}", e);
+                e.to_string()
+            })
+    }
+
+    pub fn warm_program(&self, program_id: &str, elf_path: &str) -> Result<bool, String> {
+        match self.mutex.try_lock() {
+            Ok(_) => crate::warm_program(program_id, elf_path)
+                .map(|_| true)
+                .map_err(|e| {
+                    tracing::error!("warm_program error {:#?}", e);
+                    e.to_string()
+                }),
+            Err(e) => {
+                tracing::error!("warm_program busy: {:?}", e);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn warm_up_snark(&self) -> Result<bool, String> {
+        match self.mutex.try_lock() {
+            Ok(_) => self.snark_prover.warm_up().map(|_| true).map_err(|e| {
+                tracing::error!("warm_up_snark error {:#?}", e);
+                e.to_string()
+            }),
+            Err(e) => {
+                tracing::error!("warm_up_snark busy: {:?}", e);
+                Ok(false)
+            }
+        }
+    }
+
     /// Return zkm-prover status
     pub fn get_status(&self) -> bool {
         let result = self.mutex.try_lock();
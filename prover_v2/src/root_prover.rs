@@ -1,8 +1,9 @@
+use common::file;
 use zkm_core_executor::ExecutionRecord;
 use zkm_stark::{MachineProver, StarkGenericConfig};
 
 use crate::contexts::ProveContext;
-use crate::{get_prover, NetworkProve, KEY_CACHE};
+use crate::{get_prover, key_cache, NetworkProve};
 
 #[derive(Default)]
 pub struct RootProver {}
@@ -10,42 +11,12 @@ pub struct RootProver {}
 impl RootProver {
     pub fn prove(&self, ctx: &ProveContext) -> anyhow::Result<Vec<u8>> {
         let now = std::time::Instant::now();
-        let mut record: ExecutionRecord = {
-            let mut retries = 0;
-            const MAX_RETRIES: usize = 10;
-
-            loop {
-                let result = std::fs::read(&ctx.segment)
-                    .and_then(|segment| {
-                        zstd::stream::decode_all(&*segment)
-                            .map_err(|e| std::io::Error::other(format!("zstd decode failed: {e}")))
-                    })
-                    .and_then(|decoded| {
-                        bincode::deserialize::<ExecutionRecord>(&decoded).map_err(|e| {
-                            std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                format!("deserialize failed: {e}"),
-                            )
-                        })
-                    });
-
-                match result {
-                    Ok(r) => break r,
-                    Err(e) => {
-                        if retries >= MAX_RETRIES {
-                            return Err(anyhow::anyhow!(
-                                "Segment read/decode failed after {} retries: {}",
-                                MAX_RETRIES,
-                                e
-                            ));
-                        }
-                        tracing::warn!("Segment {:?} error: {}, retrying...", ctx.segment, e);
-                        retries += 1;
-                        std::thread::sleep(std::time::Duration::from_millis(300));
-                    }
-                }
-            }
-        };
+        // The segment is published atomically once complete, so a failed read is final.
+        let segment = file::new(&ctx.segment).read()?;
+        let decoded = zstd::stream::decode_all(&*segment)
+            .map_err(|e| anyhow::anyhow!("zstd decode {} failed: {e}", ctx.segment))?;
+        let mut record: ExecutionRecord = bincode::deserialize(&decoded)
+            .map_err(|e| anyhow::anyhow!("deserialize {} failed: {e}", ctx.segment))?;
         tracing::info!("read segment time: {:?}", now.elapsed());
 
         let network_prove = NetworkProve::new(ctx.seg_size);
@@ -53,14 +24,10 @@ impl RootProver {
 
         let prover = get_prover();
         let now = std::time::Instant::now();
-        let mut cache = KEY_CACHE.lock().unwrap();
-        let pk = if let Some((pk, _)) = cache.cache.get(&ctx.program_id) {
-            pk
-        } else {
-            let (pk, vk) = prover.core_prover.setup(&record.program);
-            cache.push(ctx.program_id.clone(), (pk, vk));
-            &cache.cache.get(&ctx.program_id).unwrap().0
-        };
+        let mut cache = key_cache();
+        let (pk, _) = cache.get_or_insert_with(&ctx.program_id, || {
+            Ok(prover.core_prover.setup(&record.program))
+        })?;
         tracing::info!("setup time: {:?}", now.elapsed());
         let now = std::time::Instant::now();
         prover.core_prover.machine().generate_dependencies(
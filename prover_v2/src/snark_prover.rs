@@ -1,5 +1,7 @@
 use crate::contexts::SnarkContext;
 use crate::{get_prover, NetworkProve, WRAP_KEYS};
+use common::file;
+use sha2::{Digest, Sha256};
 use std::path::PathBuf;
 use tracing::instrument;
 use zkm_core_executor::ZKMReduceProof;
@@ -9,7 +11,16 @@ use zkm_recursion_circuit::witness::Witnessable;
 use zkm_recursion_compiler::config::InnerConfig;
 use zkm_recursion_core::Runtime;
 use zkm_sdk::ZKMProof;
-use zkm_stark::{Challenge, MachineProver, StarkGenericConfig, Val, ZKMProverOpts};
+use zkm_stark::{
+    Challenge, MachineProver, StarkGenericConfig, StarkProvingKey, StarkVerifyingKey, Val,
+    ZKMProverOpts,
+};
+
+type WrapKeys = (StarkProvingKey<OuterSC>, StarkVerifyingKey<OuterSC>);
+
+const WRAP_PK: &str = "wrap_pk.bin";
+const WRAP_VK: &str = "wrap_vk.bin";
+const WRAP_KEYS_CHECKSUM: &str = "wrap_keys.sha256";
 
 // It seems we don't need `output_dir`.
 #[derive(Default)]
@@ -52,6 +63,76 @@ impl SnarkProver {
         Ok(ZKMProof::Groth16(proof))
     }
 
+    /// Build the wrap keys eagerly, so that the first snark proof doesn't pay for the setup.
+    pub fn warm_up(&self) -> anyhow::Result<()> {
+        let prover = get_prover();
+        self.wrap_keys(&prover)?;
+        Ok(())
+    }
+
+    // The wrap keys are kept in memory, and persisted under the proving key directory together
+    // with the checksum of the wrap program they were set up for.
+    fn wrap_keys(&self, prover: &ZKMProver) -> anyhow::Result<WrapKeys> {
+        if let Some(keys) = WRAP_KEYS.get() {
+            tracing::info!("using cached pk and vk");
+            return Ok(keys.clone());
+        }
+        let program = prover.wrap_program();
+        let checksum = hex::encode(Sha256::digest(bincode::serialize(&*program)?));
+        let keys = match self.load_wrap_keys(&checksum) {
+            Ok(Some(keys)) => {
+                tracing::info!("load wrap pk and vk from {}", self.proving_key_paths);
+                keys
+            }
+            result => {
+                if let Err(e) = result {
+                    tracing::warn!("load wrap keys failed: {:?}", e);
+                }
+                tracing::info!("setup wrap_prover");
+                let keys = tracing::info_span!("setup wrap")
+                    .in_scope(|| prover.wrap_prover.setup(&program));
+                if let Err(e) = self.save_wrap_keys(&checksum, &keys) {
+                    tracing::warn!("save wrap keys failed: {:?}", e);
+                }
+                keys
+            }
+        };
+        WRAP_KEYS.set(keys.clone()).ok();
+        Ok(keys)
+    }
+
+    fn load_wrap_keys(&self, checksum: &str) -> anyhow::Result<Option<WrapKeys>> {
+        let checksum_path = format!("{}/{WRAP_KEYS_CHECKSUM}", self.proving_key_paths);
+        let saved = file::new(&checksum_path)
+            .read_to_string()
+            .unwrap_or_default();
+        if saved.trim() != checksum {
+            if !saved.is_empty() {
+                tracing::warn!(
+                    "wrap keys checksum {} mismatches the wrap program {}",
+                    saved.trim(),
+                    checksum
+                );
+            }
+            return Ok(None);
+        }
+        let pk = file::new(&format!("{}/{WRAP_PK}", self.proving_key_paths)).read()?;
+        let vk = file::new(&format!("{}/{WRAP_VK}", self.proving_key_paths)).read()?;
+        Ok(Some((
+            bincode::deserialize(&pk)?,
+            bincode::deserialize(&vk)?,
+        )))
+    }
+
+    fn save_wrap_keys(&self, checksum: &str, keys: &WrapKeys) -> anyhow::Result<()> {
+        let dir = &self.proving_key_paths;
+        file::new(&format!("{dir}/{WRAP_PK}")).write_all(&bincode::serialize(&keys.0)?)?;
+        file::new(&format!("{dir}/{WRAP_VK}")).write_all(&bincode::serialize(&keys.1)?)?;
+        // written last, a complete checksum implies complete keys
+        file::new(&format!("{dir}/{WRAP_KEYS_CHECKSUM}")).write_all(checksum.as_bytes())?;
+        Ok(())
+    }
+
     #[instrument(name = "wrap_bn254", level = "info", skip_all)]
     fn wrap_bn254(
         &self,
@@ -91,16 +172,9 @@ impl SnarkProver {
 
         // cache wrap_pk and wrap_vk
         let time = std::time::Instant::now();
-        let (wrap_pk, wrap_vk) = if let Some((pk, vk)) = WRAP_KEYS.get() {
-            tracing::info!("using cached pk and vk");
-            (pk.clone(), vk.clone())
-        } else {
-            tracing::info!("setup wrap_prover");
-            let (pk, vk) =
-                tracing::info_span!("setup wrap").in_scope(|| prover.wrap_prover.setup(&program));
-            WRAP_KEYS.set((pk.clone(), vk.clone())).ok();
-            (pk, vk)
-        };
+        let (wrap_pk, wrap_vk) = self
+            .wrap_keys(prover)
+            .map_err(|e| ZKMRecursionProverError::RuntimeError(e.to_string()))?;
         let elapsed = time.elapsed();
         tracing::info!("setup wrap time: {:?}", elapsed);
 
@@ -0,0 +1,91 @@
+use std::borrow::Borrow;
+use std::path::Path;
+
+use p3_field::PrimeField32;
+use sha2::{Digest, Sha256};
+use zkm_core_executor::ZKMReduceProof;
+use zkm_prover::{InnerSC, ZKMVerifyingKey};
+use zkm_recursion_core::air::RecursionPublicValues;
+use zkm_sdk::{ZKMProof, ZKMPublicValues};
+use zkm_stark::StarkVerifyingKey;
+
+use crate::get_prover;
+
+#[derive(Default)]
+pub struct Verifier {
+    proving_key_paths: String,
+}
+
+impl Verifier {
+    pub fn new(proving_key_paths: &str) -> Self {
+        Self {
+            proving_key_paths: proving_key_paths.into(),
+        }
+    }
+
+    /// Verify a json `ZKMProof` against the bincode program vk and the public values stream. A
+    /// proof that is not json is taken as a composite receipt, i.e. a bincode
+    /// `(ZKMReduceProof, vk)` as passed in the receipt inputs. The compressed proofs and the
+    /// receipts are checked against `public_values` unless it is empty, the Groth16 proofs always.
+    pub fn verify(&self, proof: &[u8], vk: &[u8], public_values: &[u8]) -> anyhow::Result<()> {
+        let Ok(proof) = serde_json::from_slice::<ZKMProof>(proof) else {
+            return Self::verify_receipt(proof, vk, public_values);
+        };
+        let vk = ZKMVerifyingKey {
+            vk: bincode::deserialize(vk)?,
+        };
+        let prover = get_prover();
+        match proof {
+            ZKMProof::Compressed(proof) => {
+                check_public_values(&proof, public_values)?;
+                prover
+                    .verify_compressed(&proof, &vk)
+                    .map_err(|e| anyhow::anyhow!("verify compressed proof failed: {:?}", e))
+            }
+            ZKMProof::Groth16(proof) => prover
+                .verify_groth16_bn254(
+                    &proof,
+                    &vk,
+                    &ZKMPublicValues::from(public_values),
+                    Path::new(&self.proving_key_paths),
+                )
+                .map_err(|e| anyhow::anyhow!("verify groth16 proof failed: {:?}", e)),
+            _ => anyhow::bail!("unsupported proof type"),
+        }
+    }
+
+    fn verify_receipt(receipt: &[u8], vk: &[u8], public_values: &[u8]) -> anyhow::Result<()> {
+        let (proof, receipt_vk): (ZKMReduceProof<InnerSC>, StarkVerifyingKey<InnerSC>) =
+            bincode::deserialize(receipt)
+                .map_err(|e| anyhow::anyhow!("neither a ZKMProof nor a receipt: {}", e))?;
+        anyhow::ensure!(
+            bincode::serialize(&receipt_vk)? == vk,
+            "the receipt is of another program"
+        );
+        check_public_values(&proof, public_values)?;
+        get_prover()
+            .verify_compressed(&proof, &ZKMVerifyingKey { vk: receipt_vk })
+            .map_err(|e| anyhow::anyhow!("verify receipt failed: {:?}", e))
+    }
+}
+
+/// Check that `proof` commits to the sha256 of `public_values`, if any.
+fn check_public_values(
+    proof: &ZKMReduceProof<InnerSC>,
+    public_values: &[u8],
+) -> anyhow::Result<()> {
+    if public_values.is_empty() {
+        return Ok(());
+    }
+    let committed: &RecursionPublicValues<_> = proof.proof.public_values.as_slice().borrow();
+    let digest = committed
+        .committed_value_digest
+        .iter()
+        .flat_map(|word| word.iter().map(|x| x.as_canonical_u32() as u8))
+        .collect::<Vec<_>>();
+    anyhow::ensure!(
+        digest == Sha256::digest(public_values).as_slice(),
+        "the public values are not those committed by the proof"
+    );
+    Ok(())
+}